@@ -1,81 +1,192 @@
 extern crate rusqlite;
-extern crate blake2;
 extern crate hex;
 extern crate base64;
 extern crate libc;
 extern crate bincode;
 extern crate crossbeam_utils;
+extern crate time;
 
 use super::metadatadb::*;
 use super::rsync::*;
-use super::{NodeInfo, NodeId};
+use super::notify::*;
+use super::signing::*;
+use super::{NodeInfo, NodeId, ConflictInfo, ConflictKind, Checkpoint, CheckpointNode, PeerInfo, VerifyReport, FsckReport, QuarantineInfo, SyncerError};
+use super::{BlobHash, HashKind, HASHZERO};
+use super::versions::*;
+use super::packs::*;
+use super::localstore::*;
 use crate::settings::*;
 use crate::rwhashes::*;
 use crate::config::*;
 use crate::filesystem::*;
 use self::rusqlite::Connection;
-use self::blake2::Blake2b;
-use self::blake2::digest::{Input, VariableOutput};
-use self::libc::c_int;
 use std::cmp;
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::io::prelude::*;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::{usize, i64};
-use std::collections::HashMap;
+use std::mem;
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
 use std::sync::{Arc, Mutex, RwLock};
 use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, SeekFrom};
 use std::fs::File;
 
-pub type BlobHash = [u8;HASHSIZE];
+// Node logs are split into segments named <peerid>.<segment> so that old ones can be
+// archived or dropped once a checkpoint covers them. The plain <peerid> file is
+// segment 0 which is also what logs from before segments look like.
+fn parse_log_name(name: &str) -> Option<(&str, u64)> {
+  let (peerid, segment) = match name.find('.') {
+    None => (name, 0),
+    Some(pos) => match name[pos+1..].parse::<u64>() {
+      Ok(segment) if segment > 0 => (&name[..pos], segment),
+      _ => return None,
+    },
+  };
+  if peerid.len() != 16 || hex::decode(peerid).is_err() {
+    return None
+  }
+  Some((peerid, segment))
+}
 
-#[derive(Clone)]
-pub struct Blob {
-  data: Vec<u8>,
+fn log_name(peerid: &str, segment: u64) -> String {
+  if segment == 0 {
+    peerid.to_string()
+  } else {
+    format!("{}.{}", peerid, segment)
+  }
 }
 
-impl Blob {
-  pub fn zero(size: usize) -> Self {
-    Self::new_with_data(vec![0 as u8; size])
+// Retirement markers are "<signer> <segment> <offset> <signature>" with the position
+// where the retired peer's log ended. Older ones are just "<signer> <signature>".
+type Retirement<'a> = (&'a str, Option<(u64, u64)>, &'a str);
+
+fn parse_retirement(contents: &str) -> Option<Retirement<'_>> {
+  let parts: Vec<&str> = contents.split_whitespace().collect();
+  let (signer, end, signature) = match parts[..] {
+    [signer, signature] => (signer, None, signature),
+    [signer, segment, offset, signature] => {
+      (signer, Some((segment.parse().ok()?, offset.parse().ok()?)), signature)
+    },
+    _ => return None,
+  };
+  if signer.len() != 16 || hex::decode(signer).is_err() {
+    return None
   }
+  Some((signer, end, signature))
+}
 
-  pub fn new_with_data(data: Vec<u8>) -> Self {
-    Self {
-      data,
-    }
+fn retirement_message(peerid: &str, end: Option<(u64, u64)>) -> String {
+  match end {
+    Some((segment, offset)) => format!("retire {} {} {}", peerid, segment, offset),
+    None => format!("retire {}", peerid),
   }
+}
 
-  fn load(file: &Path) -> Result<Self, c_int> {
-    let mut file = match fs::File::open(&file) {
-      Ok(f) => f,
-      Err(_) => return Err(libc::EIO),
-    };
-    let mut buffer = Vec::new();
-    match file.read_to_end(&mut buffer) {
-      Ok(_) => {},
-      Err(_) => return Err(libc::EIO),
-    }
-    Ok(Self::new_with_data(buffer))
+pub fn decode_entry(hash: &BlobHash, buffer: &[u8]) -> Result<FSEntry, SyncerError> {
+  FSEntry::decode(buffer).map_err(|e| SyncerError::Corruption(format!("node in blob {}: {}", hash, e)))
+}
+
+// Get the settings a repository was created with from the server into the data dir.
+// Returns false if the server is from before they were stored there.
+pub fn fetch_repository(server: &str, source: &Path) -> Result<bool, Error> {
+  fs::create_dir_all(source)?;
+  let mut remote = server.to_string();
+  remote.push_str("/data/repository");
+  let mut cmd = RsyncCommand::new();
+  cmd.arg(&remote);
+  cmd.arg(source);
+  cmd.run_optional()
+}
+
+pub fn publish_repository(server: &str, source: &Path) -> Result<(), Error> {
+  let mut remote = server.to_string();
+  remote.push_str("/data/repository");
+  let mut cmd = RsyncCommand::new();
+  cmd.arg(source.join("repository"));
+  cmd.arg(&remote);
+  cmd.run()
+}
+
+// The format of the repository on the server. None if it's from before the format was
+// kept there.
+pub fn fetch_format(server: &str, source: &Path) -> Result<Option<u64>, Error> {
+  let path = source.join("remoteformat");
+  let mut remote = server.to_string();
+  remote.push_str("/data/format");
+  let mut cmd = RsyncCommand::new();
+  cmd.arg(&remote);
+  cmd.arg(&path);
+  if !cmd.run_optional()? {
+    return Ok(None)
   }
+  let text = fs::read_to_string(&path)?;
+  fs::remove_file(&path)?;
+  match text.trim().parse::<u64>() {
+    Ok(version) => Ok(Some(version)),
+    Err(e) => Err(Error::new(ErrorKind::InvalidData, format!("server has format {:?}: {}", text.trim(), e))),
+  }
+}
 
-  fn store(&self, file: &Path) -> Result<(), c_int> {
-    if !file.exists() {
-      let mut file = match fs::File::create(&file) {
-        Ok(f) => f,
-        Err(_) => return Err(libc::EIO),
-      };
-      match file.write_all(&self.data) {
-        Ok(_) => {},
-        Err(_) => return Err(libc::EIO),
+// Put our format on the server so peers too old to read what we write stop there
+pub fn publish_format(server: &str, source: &Path) -> Result<(), Error> {
+  let mut remote = server.to_string();
+  remote.push_str("/data/format");
+  let mut cmd = RsyncCommand::new();
+  cmd.arg(source.join("format"));
+  cmd.arg(&remote);
+  cmd.run()
+}
+
+// All the log segments for a peer in order
+pub fn log_segments(dir: &Path, peerid: &str) -> Vec<(u64, PathBuf)> {
+  let mut segments = Vec::new();
+  if let Ok(files) = fs::read_dir(dir) {
+    for file in files.flatten() {
+      let path = file.path();
+      if let Some((filepeer, segment)) = path.file_name().and_then(|n| n.to_str()).and_then(parse_log_name) {
+        if filepeer == peerid {
+          segments.push((segment, path.clone()));
+        }
       }
     }
-    Ok(())
+  }
+  segments.sort();
+  segments
+}
+
+// Node id for the conflict copy of a losing version. Always hashed the same way so
+// peers that are migrating between hash kinds still agree on it.
+fn conflict_copy_id(node: NodeId, loser: &FSEntry) -> NodeId {
+  let mut data = Vec::new();
+  data.extend_from_slice(&node.0.to_le_bytes());
+  data.extend_from_slice(&node.1.to_le_bytes());
+  data.extend_from_slice(&loser.encode());
+  let hash = BlobHash::compute(HashKind::default(), &data);
+  let digest = hash.digest();
+  let mut first = [0u8; 8];
+  let mut second = [0u8; 8];
+  first.copy_from_slice(&digest[0..8]);
+  second.copy_from_slice(&digest[8..16]);
+  (i64::from_le_bytes(first), i64::from_le_bytes(second))
+}
+
+#[derive(Clone)]
+pub struct Blob {
+  data: Vec<u8>,
+}
+
+impl Blob {
+  pub fn new_with_data(data: Vec<u8>) -> Self {
+    Self {
+      data,
+    }
   }
 
+  // Blocks can be shorter than the file says they are and the rest reads as zeros
   fn read(&self, offset: usize, bytes: usize) -> Vec<u8> {
-    assert!(offset < self.data.len());
+    if offset >= self.data.len() { return Vec::new() }
     let start = offset;
     let end = cmp::min(offset+bytes, self.data.len());
     self.data[start..end].to_vec()
@@ -88,12 +199,16 @@ impl Blob {
     self.data[start..end].copy_from_slice(&data[..]);
   }
 
-  fn hash(&self) -> BlobHash {
-    let mut hasher = Blake2b::new(HASHSIZE).unwrap();
-    hasher.process(&self.data);
-    let mut buf = [0u8; HASHSIZE];
-    hasher.variable_result(&mut buf).unwrap();
-    buf
+  fn truncate(&mut self, len: usize) {
+    self.data.truncate(len);
+  }
+
+  fn hash(&self, kind: HashKind) -> BlobHash {
+    BlobHash::compute(kind, &self.data)
+  }
+
+  fn is_zero(&self) -> bool {
+    self.data.iter().all(|b| *b == 0)
   }
 
   fn len(&self) -> usize {
@@ -103,67 +218,145 @@ impl Blob {
 
 pub struct BlobStorage {
   maxbytes: u64,
+  maxdirty: u64,
+  blksize: usize,
+  hashkind: HashKind,
   peerid: String,
+  peernum: i64,
+  node_counter: Mutex<i64>,
+  segment: Mutex<u64>,
+  last_checkpoint: Mutex<Vec<(i64, u64, u64)>>,
+  retired: RwLock<HashSet<i64>>,
+  // Retired peers whose log we still have to read up to where it ended
+  retiring: Mutex<HashMap<i64, (u64, u64)>>,
+  // Nodes opened since another peer last changed them, so whatever the kernel has
+  // cached for them is still good. Only grows with the files opened locally.
+  fresh_nodes: Mutex<HashSet<NodeId>>,
+  notifier: Notifier,
+  signer: Signer,
+  trustnew: bool,
+  key_published: Mutex<bool>,
   local: PathBuf,
+  store: LocalStore,
   server: String,
   ongoing: RwHashes<BlobHash, Arc<Mutex<bool>>>,
+  // One lock per pack being fetched so the same pack isn't fetched twice at once
+  // while fetches of different packs still run side by side
+  pack_fetch: Mutex<HashMap<String, Arc<Mutex<()>>>>,
   metadata: MetadataDB,
   written_blobs: RwLock<Vec<(BlobHash, u64, i64)>>,
+  // Blobs stored since they were last made durable, see sync_saved()
+  unsynced_blobs: Mutex<HashSet<BlobHash>>,
   touched_blobs: RwLock<HashMap<BlobHash,(i64, usize)>>,
   blob_cache: RwHashes<NodeId, HashMap<usize, Blob>>,
+  // Blocks in blob_cache in the order they were first written
+  dirty: Mutex<VecDeque<(NodeId, usize)>>,
 }
 
 impl BlobStorage {
-  pub fn new(peerid: &str, source: &Path, server: &str, maxbytes: u64) -> Result<Self, c_int> {
-    // Make sure the local blobs dir exists
-    let mut path = PathBuf::from(source);
-    path.push("blobs");
-    match fs::create_dir_all(&path) {
-      Ok(_) => {},
-      Err(_) => return Err(libc::EIO),
-    }
+  pub fn new(source: &Path, config: &Config) -> Result<Self, SyncerError> {
+    let peerid = &config.peerid;
+    let server = &config.server;
+    let signer = match Signer::new(&config.secretkey) {
+      Some(s) => s,
+      None => return Err(SyncerError::Invalid("secret key".to_string())),
+    };
+
+    let store = LocalStore::new(source, config.localstore)?;
 
     // Make sure the local nodes dir exists
     let mut path = PathBuf::from(source);
     path.push("nodes");
-    match fs::create_dir_all(&path) {
-      Ok(_) => {},
-      Err(_) => return Err(libc::EIO),
+    fs::create_dir_all(&path)?;
+    let segment = log_segments(&path, peerid).last().map_or(0, |s| s.0);
+
+    // Make sure the local checkpoints dir exists
+    let mut path = PathBuf::from(source);
+    path.push("checkpoints");
+    fs::create_dir_all(&path)?;
+
+    // Make sure the local retired peers dir exists
+    let mut path = PathBuf::from(source);
+    path.push("retired");
+    fs::create_dir_all(&path)?;
+
+    // Make sure the dirs for blobs being fetched and the ones that failed checks exist
+    for dir in &["incoming", "quarantine"] {
+      let mut path = PathBuf::from(source);
+      path.push(dir);
+      fs::create_dir_all(&path)?;
     }
 
+    // Make sure the local packs dir exists, it keeps the indexes of the packs on the server
+    let mut path = PathBuf::from(source);
+    path.push("packs");
+    fs::create_dir_all(&path)?;
+
+    // Make sure the local keys dir exists and has our public key
+    let mut path = PathBuf::from(source);
+    path.push("keys");
+    fs::create_dir_all(&path)?;
+    path.push(peerid);
+    fs::write(&path, format!("{}\n", signer.public_key()))?;
+
     // Create the db file to pass to MetadataDB
     let mut file = PathBuf::from(source);
     file.push("metadata.sqlite3");
-    let connection = Connection::open(&file).unwrap();
-    let meta = MetadataDB::new(connection);
+    let connection = Connection::open(&file)?;
+    let meta = MetadataDB::new(connection)?;
+    let peernum = convert_peerid(peerid);
+    let nodecount = meta.max_node(peernum)? + 1;
+    let retired = meta.retired_peers()?.into_iter().collect();
 
     Ok(BlobStorage {
-      maxbytes,
+      maxbytes: config.maxbytes,
+      maxdirty: config.maxdirty,
+      blksize: config.blksize,
+      hashkind: config.hashkind(),
       peerid: peerid.to_string(),
+      peernum,
+      node_counter: Mutex::new(nodecount),
+      segment: Mutex::new(segment),
+      last_checkpoint: Mutex::new(Vec::new()),
+      retired: RwLock::new(retired),
+      retiring: Mutex::new(HashMap::new()),
+      fresh_nodes: Mutex::new(HashSet::new()),
+      notifier: Notifier::new(server, peerid),
+      signer,
+      trustnew: config.trustnewpeers,
+      key_published: Mutex::new(false),
       local: PathBuf::from(source),
+      store,
       server: server.to_string(),
       ongoing: RwHashes::new(8),
+      pack_fetch: Mutex::new(HashMap::new()),
       metadata: meta,
       written_blobs: RwLock::new(Vec::new()),
+      unsynced_blobs: Mutex::new(HashSet::new()),
       touched_blobs: RwLock::new(HashMap::new()),
       blob_cache: RwHashes::new(8),
+      dirty: Mutex::new(VecDeque::new()),
     })
   }
 
-  pub fn fsync_file(&self, hash: &BlobHash) -> Result<(), c_int> {
-    let path = self.local_path(hash);
-    let file = match fs::File::open(&path) {
-      Ok(f) => f,
-      Err(_) => return Err(libc::EIO),
-    };
-    match file.sync_all() {
-      Ok(_) => {},
-      Err(_) => return Err(libc::EIO),
+  // Get the blobs stored since the last call and the rows pointing at them onto the
+  // disk. Ones that were removed in between don't need to be.
+  pub fn sync_saved(&self) -> Result<(), SyncerError> {
+    let hashes: Vec<BlobHash> = self.unsynced_blobs.lock().unwrap().drain().collect();
+    for hash in hashes {
+      if self.store.contains(&hash) {
+        self.fsync_file(&hash)?;
+      }
     }
-    Ok(())
+    self.metadata.sync()
+  }
+
+  pub fn fsync_file(&self, hash: &BlobHash) -> Result<(), SyncerError> {
+    if *hash == HASHZERO { return Ok(()) }
+    self.store.sync(hash)
   }
 
-  pub fn read(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, bytes: usize, readahead: &[BlobHash]) -> Result<Vec<u8>, c_int> {
+  pub fn read(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, bytes: usize, readahead: &[BlobHash]) -> Result<Vec<u8>, SyncerError> {
     // First figure out if this isn't a cached blob
     let blob_cache = self.blob_cache.read(&node);
     if let Some(blocks) = blob_cache.get(&node) {
@@ -176,7 +369,7 @@ impl BlobStorage {
     Ok(blob.read(offset, bytes))
   }
 
-  pub fn write(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, data: &[u8], readahead: &[BlobHash]) -> Result<(), c_int> {
+  pub fn write(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, data: &[u8], readahead: &[BlobHash]) -> Result<(), SyncerError> {
     // First figure out if this isn't a cached blob
     {
       let mut blob_cache = self.blob_cache.write(&node);
@@ -193,30 +386,59 @@ impl BlobStorage {
     // Store the blob in the cache
     let mut blob_cache = self.blob_cache.write(&node);
     let blocks = blob_cache.entry(node).or_insert(HashMap::new());
-    blocks.insert(block, blob);
+    if blocks.insert(block, blob).is_none() {
+      self.dirty.lock().unwrap().push_back((node, block));
+    }
 
     Ok(hash)
   }
 
-  pub fn sync_node(&self, node: NodeId) -> Result<Vec<(usize, BlobHash)>, c_int> {
+  // Cut a block short, bringing it into the cache first if it isn't there yet
+  pub fn truncate_block(&self, node: NodeId, block: usize, hash: &BlobHash, len: usize) -> Result<(), SyncerError> {
+    {
+      let mut blob_cache = self.blob_cache.write(&node);
+      if let Some(blocks) = blob_cache.get_mut(&node) {
+        if let Some(blob) = blocks.get_mut(&block) {
+          blob.truncate(len);
+          return Ok(())
+        }
+      }
+    }
+
+    let mut blob = self.get_blob(hash, &[])?;
+    blob.truncate(len);
+
+    let mut blob_cache = self.blob_cache.write(&node);
+    let blocks = blob_cache.entry(node).or_default();
+    if blocks.insert(block, blob).is_none() {
+      self.dirty.lock().unwrap().push_back((node, block));
+    }
+    Ok(())
+  }
+
+  pub fn sync_node(&self, node: NodeId) -> Result<Vec<(usize, BlobHash)>, SyncerError> {
     let mut stored = Vec::new();
     let mut blob_cache = self.blob_cache.write(&node);
     if let Some(mut blocks) = blob_cache.remove(&node) {
+      self.dirty.lock().unwrap().retain(|d| d.0 != node);
       for (i, blob) in blocks.drain() {
-        let hash = self.store_blob(blob)?;
+        let hash = self.store_block(blob)?;
         stored.push((i, hash));
       }
     }
     Ok(stored)
   }
 
-  fn get_blob(&self, hash: &BlobHash, readahead: &[BlobHash]) -> Result<Blob, c_int> {
+  fn get_blob(&self, hash: &BlobHash, readahead: &[BlobHash]) -> Result<Blob, SyncerError> {
     self.readahead_from_server(readahead);
-    let file = self.local_path(hash);
-    if !file.exists() {
+    // Holes never touch the disk or the server
+    if *hash == HASHZERO {
+      return Ok(Blob::new_with_data(Vec::new()))
+    }
+    if !self.store.contains(hash) {
       self.fetch_from_server(hash)?;
     }
-    let blob = Blob::load(&file)?;
+    let blob = Blob::new_with_data(self.store.read(hash)?);
     {
       let timeval = timeval();
       let mut touched = self.touched_blobs.write().unwrap();
@@ -225,35 +447,136 @@ impl BlobStorage {
     Ok(blob)
   }
 
-  fn store_blob(&self, blob: Blob) -> Result<BlobHash, c_int> {
-    let hash = blob.hash();
-    let file = self.local_path(&hash);
-    blob.store(&file)?;
+  fn store_blob(&self, blob: Blob) -> Result<BlobHash, SyncerError> {
+    let hash = blob.hash(self.hashkind);
+    self.store.write(&hash, &blob.data)?;
     {
       let mut written_blobs = self.written_blobs.write().unwrap();
       written_blobs.push((hash, blob.data.len() as u64, timeval()));
     }
+    self.unsynced_blobs.lock().unwrap().insert(hash);
     Ok(hash)
   }
 
-  pub fn zero(size: usize) -> BlobHash {
-    Blob::zero(size).hash()
+  // Blocks that are all zeros become holes instead of blobs
+  fn store_block(&self, blob: Blob) -> Result<BlobHash, SyncerError> {
+    if blob.is_zero() {
+      return Ok(HASHZERO)
+    }
+    self.store_blob(blob)
   }
 
-  pub fn add_blob(&self, data: &[u8]) -> Result<BlobHash, c_int> {
-    let blob = Blob::new_with_data(data.to_vec());
-    let hash = blob.hash();
-    self.store_blob(blob)?;
-    Ok(hash)
+  // Forget the cached blocks of the part of a file that was truncated away
+  pub fn drop_blocks(&self, node: NodeId, from: usize) {
+    let mut blob_cache = self.blob_cache.write(&node);
+    if let Some(blocks) = blob_cache.get_mut(&node) {
+      blocks.retain(|block, _| *block < from);
+      if blocks.is_empty() {
+        blob_cache.remove(&node);
+      }
+    }
+    self.dirty.lock().unwrap().retain(|d| d.0 != node || d.1 < from);
+  }
+
+  pub fn is_cached(&self, node: NodeId, block: usize) -> bool {
+    let blob_cache = self.blob_cache.read(&node);
+    blob_cache.get(&node).map(|b| b.contains_key(&block)).unwrap_or(false)
+  }
+
+  // Put back the contents of a block that hadn't been saved yet
+  pub fn cache_block(&self, node: NodeId, block: usize, data: Vec<u8>) {
+    let mut blob_cache = self.blob_cache.write(&node);
+    let blocks = blob_cache.entry(node).or_default();
+    if blocks.insert(block, Blob::new_with_data(data)).is_none() {
+      self.dirty.lock().unwrap().push_back((node, block));
+    }
+  }
+
+  pub fn dirty_blocks(&self) -> usize {
+    self.dirty.lock().unwrap().len()
+  }
+
+  // Blocks are counted at their full size as that's what they can grow to
+  pub fn over_dirty_limit(&self) -> bool {
+    (self.dirty_blocks() * self.blksize) as u64 > self.maxdirty
+  }
+
+  pub fn oldest_dirty(&self) -> Option<(NodeId, usize)> {
+    self.dirty.lock().unwrap().front().cloned()
+  }
+
+  pub fn requeue_dirty(&self, node: NodeId, block: usize) {
+    let mut dirty = self.dirty.lock().unwrap();
+    if let Some(pos) = dirty.iter().position(|d| *d == (node, block)) {
+      dirty.remove(pos);
+      dirty.push_back((node, block));
+    }
+  }
+
+  // Save a single cached block, returning its new hash if it was still cached
+  pub fn flush_block(&self, node: NodeId, block: usize) -> Result<Option<BlobHash>, SyncerError> {
+    // Hold on to the cache until the blob is stored so readers don't miss it
+    let mut blob_cache = self.blob_cache.write(&node);
+    let blob = match blob_cache.get_mut(&node) {
+      Some(blocks) => blocks.remove(&block),
+      None => None,
+    };
+    if blob_cache.get(&node).map(|b| b.is_empty()).unwrap_or(false) {
+      blob_cache.remove(&node);
+    }
+    self.dirty.lock().unwrap().retain(|d| *d != (node, block));
+    match blob {
+      Some(blob) => Ok(Some(self.store_block(blob)?)),
+      None => Ok(None),
+    }
   }
 
-  pub fn max_node(&self, peernum: i64) -> Result<i64, c_int> {
-    self.metadata.max_node(peernum)
+  pub fn has_cached_blocks(&self) -> bool {
+    !self.blob_cache.all_empty()
   }
 
-  pub fn save_node(&self, node: NodeId, entry: &FSEntry) -> Result<(), c_int> {
-    let encoded: Vec<u8> = bincode::serialize(&entry).unwrap();
-    let hash = self.add_blob(&encoded)?;
+  pub fn add_blob(&self, data: &[u8]) -> Result<BlobHash, SyncerError> {
+    self.store_blob(Blob::new_with_data(data.to_vec()))
+  }
+
+  pub fn new_node_id(&self) -> NodeId {
+    let mut counter = self.node_counter.lock().unwrap();
+    *counter += 1;
+    (self.peernum, *counter)
+  }
+
+  // Compare vector clocks leaving out the peers that have been retired
+  fn cmp_entries(&self, first: &FSEntry, second: &FSEntry) -> VectorOrdering {
+    let retired = self.retired.read().unwrap();
+    if retired.is_empty() {
+      return first.cmp_vclock(second)
+    }
+    let mut vclock1 = first.vclock.clone();
+    let mut vclock2 = second.vclock.clone();
+    for peer in retired.iter() {
+      vclock1.forget(*peer);
+      vclock2.forget(*peer);
+    }
+    vclock1.cmp(&vclock2)
+  }
+
+  pub fn save_node(&self, node: NodeId, entry: &FSEntry) -> Result<(), SyncerError> {
+    // Our own changes are where retired peers get compacted out of the vector clocks
+    let mut compacted;
+    let entry = if entry.peernum == self.peernum {
+      compacted = entry.clone();
+      let mut changed = false;
+      for peer in self.retired.read().unwrap().iter() {
+        changed |= compacted.vclock.forget(*peer);
+      }
+      if changed { &compacted } else { entry }
+    } else {
+      entry
+    };
+    if entry.peernum == self.peernum {
+      self.notifier.local_change();
+    }
+    let hash = self.add_blob(&entry.encode())?;
     if self.metadata.node_exists_long(node, &hash, entry.timeval())? {
       // this is a duplicate, skip it
       return Ok(())
@@ -264,8 +587,8 @@ impl BlobStorage {
       return Ok(())
     }
     let (hash2, buffer) = self.read_node(node)?;
-    let currnode: FSEntry = bincode::deserialize(&buffer[..]).unwrap();
-    match entry.cmp_vclock(&currnode) {
+    let currnode = decode_entry(&hash2, &buffer)?;
+    match self.cmp_entries(entry, &currnode) {
       VectorOrdering::Greater => {
         self.metadata.set_node(node, &hash, entry.timeval())?;
       },
@@ -275,47 +598,189 @@ impl BlobStorage {
       },
       VectorOrdering::Equal => {
         eprintln!("WARNING: found node {:?} with same vector clock that isn't identical", node);
-        eprintln!("1st hash is {}", hex::encode(&hash));
-        eprintln!("2nd hash is {}", hex::encode(&hash2));
-        eprintln!("1st from peer {} is {:?}", entry.peernum, entry);
-        eprintln!("2nd from peer {} is {:?}", currnode.peernum, currnode);
         self.metadata.set_node(node, &hash, entry.timeval())?;
+        self.metadata.add_conflict(&ConflictInfo {
+          id: 0,
+          kind: ConflictKind::Equal,
+          node,
+          base: hash2,
+          local: hash2,
+          remote: hash,
+          merged: hash,
+          copy: None,
+          creation: timeval(),
+          resolved: false,
+        })?;
       },
       VectorOrdering::Conflict => {
         // We're in a conflict situation, we're going to need to merge and for that we
         // need a common base to do the three way merge
 
         let base = self.read_earlier_node(node, entry)?;
-        let merged = base.merge_3way(entry, &currnode);
-        let encoded: Vec<u8> = bincode::serialize(&merged).unwrap();
-        let hash = self.add_blob(&encoded)?;
-        self.metadata.set_node(node, &hash, merged.timeval())?;
+        let merged = base.merge_3way(entry, &currnode, self.blksize);
+        let mergedhash = self.add_blob(&merged.encode())?;
+        self.metadata.set_node(node, &mergedhash, merged.timeval())?;
+
+        // Every peer that merges keeps a copy of the losing side, the one whose edits
+        // lost may be offline or retired. The copies all come out the same so they end
+        // up as a single node once synced.
+        let loser = base.content_conflict(entry, &currnode, self.blksize);
+        let copy = match loser {
+          Some(loser) => Some(self.create_conflict_copy(node, &merged, loser)?),
+          None => None,
+        };
+        let basehash = self.add_blob(&base.encode())?;
+        self.metadata.add_conflict(&ConflictInfo {
+          id: 0,
+          kind: if loser.is_some() { ConflictKind::Content } else { ConflictKind::Merged },
+          node,
+          base: basehash,
+          local: hash2,
+          remote: hash,
+          merged: mergedhash,
+          copy,
+          creation: timeval(),
+          // Clean merges are only recorded for auditing
+          resolved: loser.is_none(),
+        })?;
+        if loser.is_some() {
+          eprintln!("WARNING: conflicting contents in node {:?}, kept copy {:?}", node, copy);
+        }
       },
     }
     Ok(())
   }
 
-  pub fn read_node(&self, node: NodeId) -> Result<(BlobHash, Vec<u8>), c_int> {
+  pub fn read_blob(&self, hash: &BlobHash) -> Result<Vec<u8>, SyncerError> {
+    let blob = self.get_blob(hash, &[])?;
+    Ok(blob.read(0, usize::MAX))
+  }
+
+  pub fn conflicts(&self, node: Option<NodeId>, all: bool) -> Result<Vec<ConflictInfo>, SyncerError> {
+    self.metadata.get_conflicts(node, all)
+  }
+
+  pub fn mark_resolved(&self, conflict: &ConflictInfo) -> Result<(), SyncerError> {
+    self.metadata.mark_resolved(conflict.id)
+  }
+
+  pub fn read_node(&self, node: NodeId) -> Result<(BlobHash, Vec<u8>), SyncerError> {
     let hash = self.metadata.get_node(node)?;
     let blob = self.get_blob(&hash, &[])?;
     Ok((hash, blob.read(0, usize::MAX)))
   }
 
-  pub fn read_earlier_node(&self, node: NodeId, comparison: &FSEntry) -> Result<FSEntry, c_int> {
+  fn read_entry(&self, node: NodeId) -> Result<FSEntry, SyncerError> {
+    let (hash, buffer) = self.read_node(node)?;
+    decode_entry(&hash, &buffer)
+  }
+
+  // Save a change we made ourselves while processing remote nodes so it gets its own
+  // version and propagates to the other peers
+  fn save_local_change(&self, node: NodeId, mut entry: FSEntry) -> Result<(), SyncerError> {
+    entry.clock = time::get_time();
+    entry.vclock.increment(self.peernum);
+    entry.peernum = self.peernum;
+    self.save_node(node, &entry)
+  }
+
+  // Keep the losing side of a content conflict as a new sibling of the node. The copy's
+  // id and contents only depend on the node and the losing version so every peer that
+  // makes it makes the same one, and one that's already there is left alone.
+  pub fn create_conflict_copy(&self, node: NodeId, merged: &FSEntry, loser: &FSEntry) -> Result<NodeId, SyncerError> {
+    let copynode = conflict_copy_id(node, loser);
+    if self.node_exists(copynode)? {
+      return Ok(copynode)
+    }
+    let mut copy = loser.clone();
+    copy.vclock = VectorClock::new();
+    copy.nlink = 1;
+    copy.parent = merged.parent;
+    self.save_node(copynode, &copy)?;
+
+    if self.node_exists(merged.parent)? {
+      let mut dir = self.read_entry(merged.parent)?;
+      let name = match dir.children.iter().find(|(_, c)| c.0 == node) {
+        Some((name, _)) => name.clone(),
+        None => format!("{:016x}-{}", node.0 as u64, node.1),
+      };
+      dir.children.insert(loser.conflict_name(&name), (copynode, loser.filetype));
+      self.save_local_change(merged.parent, dir)?;
+    }
+    Ok(copynode)
+  }
+
+  // After a node from another peer has been merged make sure every node ends up in
+  // exactly one directory. The node's parent field is the source of truth since it gets
+  // merged deterministically on all peers, directory listings that disagree get fixed.
+  // Hardlinked nodes live in several directories by definition so they're skipped.
+  fn reconcile_parents(&self, node: NodeId, oldparent: Option<NodeId>) -> Result<(), SyncerError> {
+    let entry = self.read_entry(node)?;
+
+    if entry.filetype == FileTypeDef::Directory {
+      let mut stale = Vec::new();
+      for (name, &(child, _)) in entry.children.iter() {
+        if !self.node_exists(child)? { continue }
+        let childentry = self.read_entry(child)?;
+        if childentry.nlink > 1 || childentry.parent == node { continue }
+        // Only drop it from here if it's really in the other directory, otherwise it
+        // would end up nowhere
+        if !self.node_exists(childentry.parent)? { continue }
+        let parententry = self.read_entry(childentry.parent)?;
+        if parententry.children.values().any(|c| c.0 == child) {
+          stale.push(name.clone());
+        }
+      }
+      if !stale.is_empty() {
+        let mut entry = entry.clone();
+        for name in stale {
+          entry.children.remove(&name);
+        }
+        self.save_local_change(node, entry)?;
+      }
+    }
+
+    // If the node itself moved and its new directory doesn't list it yet (e.g., we
+    // pruned it when the directory arrived before the node did) move it over from
+    // the old directory
+    if let Some(oldparent) = oldparent {
+      if node == (0,0) || entry.nlink > 1 || entry.parent == oldparent { return Ok(()) }
+      if !self.node_exists(entry.parent)? || !self.node_exists(oldparent)? { return Ok(()) }
+      let mut newdir = self.read_entry(entry.parent)?;
+      if newdir.children.values().any(|c| c.0 == node) { return Ok(()) }
+      let mut olddir = self.read_entry(oldparent)?;
+      let name = match olddir.children.iter().find(|(_, c)| c.0 == node) {
+        Some((name, _)) => name.clone(),
+        None => return Ok(()),
+      };
+      if newdir.children.contains_key(&name) {
+        eprintln!("WARNING: couldn't move node {:?} to {:?} as {:?} is taken", node, entry.parent, name);
+        return Ok(())
+      }
+      let child = olddir.children.remove(&name).unwrap();
+      newdir.children.insert(name, child);
+      self.save_local_change(entry.parent, newdir)?;
+      self.save_local_change(oldparent, olddir)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn read_earlier_node(&self, node: NodeId, comparison: &FSEntry) -> Result<FSEntry, SyncerError> {
     let mut maxrowid = i64::MAX;
     loop {
       let (row, hash) = self.metadata.get_earlier_node(node, maxrowid)?;
       maxrowid = row;
       let blob = self.get_blob(&hash, &[])?;
       let encoded = blob.read(0, usize::MAX);
-      let entry: FSEntry = bincode::deserialize(&encoded[..]).unwrap();
-      if comparison.cmp_vclock(&entry) == VectorOrdering::Greater {
+      let entry = decode_entry(&hash, &encoded)?;
+      if self.cmp_entries(comparison, &entry) == VectorOrdering::Greater {
         return Ok(entry)
       }
     }
   }
 
-  pub fn node_exists(&self, node: NodeId) -> Result<bool, c_int> {
+  pub fn node_exists(&self, node: NodeId) -> Result<bool, SyncerError> {
     self.metadata.node_exists(node)
   }
 
@@ -324,41 +789,254 @@ impl BlobStorage {
     self.metadata.set_blobs(written_blobs.drain(..));
   }
 
-  pub fn do_uploads(&self) -> Result<(), c_int> {
+  // Small blobs go up in packs and the rest on their own
+  pub fn do_uploads(&self) -> Result<(), SyncerError> {
     loop {
-      let mut hashes = self.metadata.to_upload();
+      let hashes = self.metadata.to_upload(TO_PACK);
       if hashes.len() == 0 { break }
-      self.upload_to_server(&hashes)?;
-      self.metadata.mark_synced_blobs(hashes.drain(..));
+      let mut single = Vec::new();
+      let mut pack = PackWriter::new();
+      for hash in hashes {
+        // Blobs that were fetched from a pack are already on the server
+        if !self.metadata.packs_with(&hash)?.is_empty() {
+          self.metadata.mark_synced_blobs(std::iter::once(hash));
+          continue
+        }
+        match self.store.size(&hash) {
+          Some(size) if (size as usize) < PACK_MAXBLOB => {
+            pack.add(hash, &self.store.read(&hash)?);
+            if pack.len() >= PACK_SIZE {
+              self.upload_pack(mem::replace(&mut pack, PackWriter::new()))?;
+            }
+          },
+          _ => single.push(hash),
+        }
+      }
+      self.upload_pack(pack)?;
+      for hashes in single.chunks(TO_UPLOAD) {
+        self.upload_to_server(hashes)?;
+        self.metadata.mark_synced_blobs(hashes.iter().cloned());
+      }
+    }
+    Ok(())
+  }
+
+  // The pack goes up before its index so peers never see an index for a pack that
+  // isn't there yet. Only the index is kept locally.
+  fn upload_pack(&self, pack: PackWriter) -> Result<(), SyncerError> {
+    if pack.is_empty() { return Ok(()) }
+    let (name, data, entries) = pack.finish(self.hashkind);
+    let packpath = self.pack_path(&name, "pack");
+    let indexpath = self.pack_path(&name, "idx");
+    fs::write(&packpath, &data)?;
+    fs::write(&indexpath, encode_index(&entries))?;
+    let mut remote = self.server.clone();
+    remote.push_str("/data/packs/");
+    for path in &[&packpath, &indexpath] {
+      let mut cmd = RsyncCommand::new();
+      cmd.arg(path);
+      cmd.arg(&remote);
+      if let Err(e) = cmd.run() {
+        return Err(SyncerError::Remote(format!("couldn't upload pack {}: {}", name, e)))
+      }
+    }
+    fs::remove_file(&packpath)?;
+    self.metadata.add_pack(&name, &entries)?;
+    self.metadata.mark_synced_blobs(entries.iter().map(|e| e.hash));
+    Ok(())
+  }
+
+  fn pack_path(&self, name: &str, ext: &str) -> PathBuf {
+    let mut path = self.local.clone();
+    path.push("packs");
+    path.push(format!("{}.{}", name, ext));
+    path
+  }
+
+  // Pick up the indexes of the packs other peers have uploaded
+  fn fetch_pack_indexes(&self) -> Result<(), SyncerError> {
+    let mut path = self.local.clone();
+    path.push("packs");
+    let mut remote = self.server.clone();
+    remote.push_str("/data/packs/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg("-r");
+    cmd.arg("--include=*.idx");
+    cmd.arg("--exclude=*");
+    cmd.arg(&remote);
+    cmd.arg(&path);
+    if cmd.run().is_err() {
+      // Servers nobody has uploaded a pack to yet don't have the dir
+      return Ok(())
+    }
+
+    for file in fs::read_dir(&path)? {
+      let file = file?;
+      let filename = file.file_name();
+      let name = match filename.to_str().and_then(parse_pack_name) {
+        Some((name, "idx")) => name.to_string(),
+        _ => continue,
+      };
+      if self.metadata.has_pack(&name)? { continue }
+      let entries = match decode_index(&fs::read(file.path())?) {
+        Ok(entries) => entries,
+        Err(e) => {
+          // Recorded as empty so it isn't looked at again
+          eprintln!("WARNING: ignoring pack index {}: {}", name, e);
+          self.metadata.add_quarantined("packindex", &name, &e)?;
+          Vec::new()
+        },
+      };
+      self.metadata.add_pack(&name, &entries)?;
+    }
+    Ok(())
+  }
+
+  // rsync can't fetch part of a file so the whole pack comes in. Packs are made from
+  // blobs that were uploaded together so whatever else is in it gets kept as well.
+  fn fetch_pack(&self, name: &str) -> Result<(), SyncerError> {
+    let lock = self.pack_fetch.lock().unwrap()
+      .entry(name.to_string()).or_insert_with(|| Arc::new(Mutex::new(()))).clone();
+    let res = {
+      let _fetching = lock.lock().unwrap();
+      self.real_fetch_pack(name)
+    };
+    let mut fetches = self.pack_fetch.lock().unwrap();
+    // Nobody else is waiting on this pack so the lock can go
+    if Arc::strong_count(&lock) == 2 {
+      fetches.remove(name);
+    }
+    res
+  }
+
+  fn real_fetch_pack(&self, name: &str) -> Result<(), SyncerError> {
+    let wanted: Vec<PackEntry> = self.metadata.pack_entries(name)?.into_iter()
+      .filter(|e| !self.store.contains(&e.hash))
+      .collect();
+    if wanted.is_empty() { return Ok(()) }
+
+    let mut incoming = self.local.clone();
+    incoming.push("incoming");
+    let filename = format!("{}.pack", name);
+    let mut cmd = RsyncCommand::new();
+    cmd.arg(format!("{}/data/packs/{}", self.server, filename));
+    cmd.arg(&incoming);
+    if let Err(e) = cmd.run() {
+      return Err(SyncerError::Remote(format!("couldn't fetch pack {}: {}", name, e)))
+    }
+    let path = incoming.join(&filename);
+    let pack = fs::read(&path)?;
+
+    let mut bad = 0;
+    let timeval = timeval();
+    for entry in wanted {
+      match extract(&pack, &entry) {
+        Some(blob) if entry.hash.matches(blob) => {
+          // The same blob can be in other packs being fetched at the same time
+          let fetched = incoming.join(format!("{}.{}", entry.hash, name));
+          fs::write(&fetched, blob)?;
+          self.store.insert_file(&entry.hash, &fetched)?;
+          self.touched_blobs.write().unwrap().insert(entry.hash, (timeval, blob.len()));
+        },
+        _ => bad += 1,
+      }
+    }
+    if bad > 0 {
+      eprintln!("WARNING: {} blobs in pack {} from the server don't match their hash", bad, name);
+      self.quarantine_pack(&path, name, bad);
+    } else {
+      fs::remove_file(&path)?;
     }
     Ok(())
   }
 
-  pub fn init_server(&self) -> Result<(), Error> {
+  // Get a blob out of any of the packs it's in
+  fn fetch_from_packs(&self, hash: &BlobHash) -> bool {
+    let packs = match self.metadata.packs_with(hash) {
+      Ok(p) => p,
+      Err(e) => {
+        eprintln!("WARNING: couldn't look up the packs for blob {}: {}", hash, e);
+        return false
+      },
+    };
+    for (name, _) in packs {
+      if let Err(e) = self.fetch_pack(&name) {
+        eprintln!("WARNING: {}", e);
+      }
+      if self.store.contains(hash) { return true }
+    }
+    false
+  }
+
+  pub fn blksize(&self) -> usize {
+    self.blksize
+  }
+
+  pub fn init_server(&self) -> Result<(), SyncerError> {
     let mut cmd = RsyncCommand::new();
     cmd.arg("-r");
     cmd.arg("--exclude=metadata*");
+    // Blobs go up through the uploads, they may not be files or in the server's layout
+    cmd.arg("--exclude=blobs*");
+    cmd.arg("--exclude=outgoing");
+    cmd.arg("--exclude=localstore");
+    cmd.arg("--exclude=incoming");
+    cmd.arg("--exclude=quarantine");
     cmd.arg(&self.local);
     cmd.arg(&self.server);
-    cmd.run()
+    Ok(cmd.run()?)
   }
 
-  pub fn do_uploads_nodes(&self) -> Result<(), Error> {
+  fn log_path(&self, segment: u64) -> PathBuf {
     let mut path = self.local.clone();
     path.push("nodes");
-    path.push(&self.peerid.to_string());
-    let mut written = false;
+    path.push(log_name(&self.peerid, segment));
+    path
+  }
+
+  // The log segment to append to, starting a new one if the current one is full
+  fn current_log(&self, rotate: bool) -> (u64, PathBuf) {
+    let mut segment = self.segment.lock().unwrap();
+    let path = self.log_path(*segment);
+    let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    if size >= NODELOG_SEGMENT_SIZE || (rotate && size > 0) {
+      *segment += 1;
+      return (*segment, self.log_path(*segment))
+    }
+    (*segment, path)
+  }
+
+  // Others need our key on the server before they can take anything from our log
+  fn publish_key(&self) -> Result<(), SyncerError> {
+    let mut published = self.key_published.lock().unwrap();
+    if *published { return Ok(()) }
+    let mut path = self.local.clone();
+    path.push("keys");
+    path.push(&self.peerid);
+    let mut remote = self.server.clone();
+    remote.push_str("/data/keys/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg(&path);
+    cmd.arg(&remote);
+    cmd.run()?;
+    *published = true;
+    Ok(())
+  }
+
+  pub fn do_uploads_nodes(&self) -> Result<(), SyncerError> {
+    let mut written = Vec::new();
 
     loop {
       let nodes = self.metadata.to_upload_nodes();
       if nodes.len() == 0 { break }
+      let (_, path) = self.current_log(false);
       let mut file = match OpenOptions::new().append(true).create(true).open(&path) {
         Err(e) => {eprintln!("ERROR: couldn't write to entries file: {}", e); break;},
         Ok(f) => f,
       };
       let mut synced = Vec::new();
       for (rowid, nodeinfo) in nodes {
-        let mut encoded = base64::encode(&bincode::serialize(&nodeinfo).unwrap());
+        let mut encoded = self.signer.sign_node(&nodeinfo);
         encoded.push('\n');
         match file.write_all(&encoded.into_bytes()) {
           Err(e) => {eprintln!("ERROR: couldn't write entry in entries file: {}", e); break;},
@@ -370,28 +1048,58 @@ impl BlobStorage {
         Ok(_) => {},
       }
       self.metadata.mark_synced_nodes(&synced);
-      written = true;
+      if !written.contains(&path) {
+        written.push(path);
+      }
     }
 
-    if written {
+    if !written.is_empty() {
+      self.publish_key()?;
       let mut remote = self.server.clone();
-      remote.push_str(&"/data/nodes/");
+      remote.push_str("/data/nodes/");
       let mut cmd = RsyncCommand::new();
-      cmd.arg(&path);
+      for path in written {
+        cmd.arg(&path);
+      }
       cmd.arg(&remote);
-      return cmd.run();
+      cmd.run()?;
     }
 
     Ok(())
   }
 
-  pub fn do_downloads_nodes(&self) -> Result<(), Error> {
+  fn peer_key(&self, peernum: i64) -> Result<Option<String>, SyncerError> {
+    if peernum == self.peernum {
+      Ok(Some(self.signer.public_key()))
+    } else {
+      self.metadata.get_peer_key(peernum)
+    }
+  }
+
+  // Only take log lines signed by the key we have for the peer, anything else gets
+  // reported and skipped
+  fn decode_log_line(&self, peernum: i64, pubkey: Option<&str>, source: &str, line: &str) -> Result<Option<NodeInfo>, SyncerError> {
+    match parse_node_line(line, pubkey) {
+      Ok(node) => Ok(Some(node)),
+      Err(e) => {
+        eprintln!("WARNING: rejected log entry from peer {:016x}: {}", peernum as u64, e);
+        self.metadata.add_rejected(peernum)?;
+        // Lines from peers we don't trust are fine, we just don't want them
+        if pubkey.is_some() {
+          self.metadata.add_quarantined("logline", source, e)?;
+        }
+        Ok(None)
+      },
+    }
+  }
+
+  // Pin the keys peers publish. New peers are only trusted when the config allows it
+  // and a key that changes is never taken.
+  fn fetch_keys(&self) -> Result<(), SyncerError> {
     let mut path = self.local.clone();
-    path.push("nodes");
+    path.push("keys");
     let mut remote = self.server.clone();
-    remote.push_str(&"/data/nodes/");
-
-    // First fetch all the nodes files in the server except our own
+    remote.push_str("/data/keys/");
     let mut cmd = RsyncCommand::new();
     cmd.arg("-r");
     cmd.arg(format!("--exclude={}", self.peerid));
@@ -399,45 +1107,609 @@ impl BlobStorage {
     cmd.arg(&path);
     cmd.run()?;
 
-    for file in fs::read_dir(&path).unwrap() {
-      let path = file.unwrap().path();
-      if path.is_dir() { continue }
-      let filename: String = path.file_name().unwrap().to_str().unwrap().to_string();
-      if filename.len() != 16 { continue }
-      if filename == self.peerid { continue }
-      let peernum = convert_peerid(&filename);
-
-      let mut buffer = BufReader::new(File::open(&path).unwrap());
-      let mut offset = self.metadata.get_peer(peernum).unwrap();
-      buffer.seek(SeekFrom::Start(offset)).unwrap();
-
-      for line in buffer.lines() {
-        let line = line.unwrap();
-        offset += line.len() as u64 + 1;
-        let buffer = base64::decode(&line).unwrap();
-        let node: NodeInfo = bincode::deserialize(&buffer).unwrap();
-        let blob = self.get_blob(&node.hash, &[]).unwrap();
-        let entry: FSEntry = bincode::deserialize(&blob.read(0, usize::MAX)).unwrap();
-        self.save_node(node.id, &entry).unwrap();
-        self.metadata.set_peer(peernum, offset).unwrap();
+    for (peernum, key) in self.published_keys()? {
+      let key = key.as_str();
+      match self.metadata.get_peer_key(peernum)? {
+        Some(ref pinned) if pinned == key => {},
+        Some(_) => {
+          eprintln!("WARNING: peer {:016x} published a different key, ignoring it", peernum as u64);
+        },
+        None if !valid_public_key(key) => {
+          eprintln!("WARNING: peer {:016x} published an invalid key", peernum as u64);
+        },
+        None if self.trustnew => {
+          self.metadata.set_peer_key(peernum, key)?;
+        },
+        None => {
+          if self.metadata.get_peer_rejected(peernum)? == 0 {
+            eprintln!("WARNING: peer {:016x} isn't trusted, use 'syncer peers trust' to accept it",
+                      peernum as u64);
+          }
+        },
       }
     }
-
     Ok(())
   }
 
-  pub fn do_removals(&self) -> Result<(), Error> {
-    {
-      let mut touched = self.touched_blobs.write().unwrap();
-      self.metadata.touch_blobs(touched.drain());
+  // The keys other peers have put on the server as of the last fetch, whether we
+  // trust them or not
+  fn published_keys(&self) -> Result<Vec<(i64, String)>, SyncerError> {
+    let mut path = self.local.clone();
+    path.push("keys");
+    let mut keys = Vec::new();
+    if !path.exists() { return Ok(keys) }
+    for file in fs::read_dir(&path)? {
+      let file = file?;
+      let peernum = match file.file_name().to_str() {
+        Some(p) if p.len() == 16 && hex::decode(p).is_ok() => convert_peerid(p),
+        _ => continue,
+      };
+      if peernum == self.peernum { continue }
+      let mut key = String::new();
+      File::open(file.path())?.read_to_string(&mut key)?;
+      keys.push((peernum, key.trim().to_string()));
     }
+    keys.sort();
+    Ok(keys)
+  }
 
-    let bytes_to_delete = {
-      let localbytes = self.metadata.localbytes();
-      if localbytes > self.maxbytes { localbytes - self.maxbytes } else { return Ok(()) }
-    };
+  // Peers that published a key we haven't pinned, so their changes are being skipped
+  pub fn untrusted_peers(&self) -> Result<Vec<(i64, String)>, SyncerError> {
+    let mut untrusted = Vec::new();
+    for (peernum, key) in self.published_keys()? {
+      if self.metadata.get_peer_key(peernum)?.is_none() {
+        untrusted.push((peernum, key));
+      }
+    }
+    Ok(untrusted)
+  }
 
-    let mut deleted_bytes = 0;
+  pub fn trust_peer(&self, peernum: i64, pubkey: &str) -> Result<(), SyncerError> {
+    if !valid_public_key(pubkey) {
+      return Err(SyncerError::Invalid(format!("public key {:?}", pubkey)))
+    }
+    if self.metadata.get_peer_key(peernum)?.as_deref() == Some(pubkey) {
+      return Ok(())
+    }
+    self.metadata.set_peer_key(peernum, pubkey)?;
+    // The lines we rejected before are skipped over, read them again now that they
+    // can be checked
+    self.metadata.rewind_peer(peernum)?;
+    Ok(())
+  }
+
+  fn apply_node(&self, node: &NodeInfo) -> Result<(), SyncerError> {
+    let blob = self.get_blob(&node.hash, &[])?;
+    let entry = decode_entry(&node.hash, &blob.read(0, usize::MAX))?;
+    let oldparent = if self.node_exists(node.id)? {
+      Some(self.read_entry(node.id)?.parent)
+    } else {
+      None
+    };
+    self.save_node(node.id, &entry)?;
+    self.reconcile_parents(node.id, oldparent)?;
+    self.fresh_nodes.lock().unwrap().remove(&node.id);
+    Ok(())
+  }
+
+  // Bring in a node from another peer. One that's broken gets set aside so the rest of
+  // the log still goes in, anything else is likely to work when tried again later.
+  fn apply_remote_node(&self, node: &NodeInfo, source: &str) -> Result<(), SyncerError> {
+    match self.apply_node(node) {
+      Err(SyncerError::Corruption(e)) | Err(SyncerError::NotFound(e)) => {
+        eprintln!("WARNING: skipping node {:?} from {}: {}", node.id, source, e);
+        self.metadata.add_quarantined("node", source, &e)
+      },
+      res => res,
+    }
+  }
+
+  // Whether a node may have been changed by another peer since the last time we
+  // asked. Forgetting about a node only costs dropping its cache once more.
+  pub fn take_remote_change(&self, node: NodeId) -> bool {
+    let mut fresh = self.fresh_nodes.lock().unwrap();
+    if fresh.contains(&node) {
+      return false
+    }
+    if fresh.len() >= MAX_FRESH_NODES {
+      fresh.clear();
+    }
+    fresh.insert(node);
+    true
+  }
+
+  // Go through the log lines of a peer we haven't seen yet. When track is set the
+  // position is saved after every line, otherwise the final position is just returned.
+  fn read_peer_log<F>(&self, peernum: i64, segments: &[(u64, PathBuf)], track: bool, mut apply: F)
+    -> Result<(u64, u64), SyncerError> where F: FnMut(&str, &str) -> Result<(), SyncerError> {
+    let (mut segment, mut offset) = self.metadata.get_peer(peernum)?;
+    let first = match segments.first() {
+      Some(s) => s.0,
+      None => return Ok((segment, offset)),
+    };
+    if first > segment {
+      // The segment we were in the middle of has been dropped after a checkpoint so we
+      // need to catch up from one before carrying on
+      self.load_checkpoint(false)?;
+      let pos = self.metadata.get_peer(peernum)?;
+      segment = pos.0;
+      offset = pos.1;
+      if first > segment {
+        eprintln!("WARNING: log segments {}-{} of peer {:016x} are gone, changes may be missing",
+                  segment, first - 1, peernum as u64);
+      }
+    }
+
+    for (seg, path) in segments {
+      if *seg < segment { continue }
+      if *seg > segment {
+        segment = *seg;
+        offset = 0;
+      }
+      let mut buffer = BufReader::new(File::open(path)?);
+      buffer.seek(SeekFrom::Start(offset))?;
+
+      let mut line = Vec::new();
+      loop {
+        line.clear();
+        let read = buffer.read_until(b'\n', &mut line)?;
+        if read == 0 { break }
+        let source = format!("{}@{}", log_name(&format!("{:016x}", peernum as u64), segment), offset);
+        if line.last() == Some(&b'\n') { line.pop(); }
+        match std::str::from_utf8(&line) {
+          Ok(text) => apply(text, &source)?,
+          Err(_) => {
+            eprintln!("WARNING: skipping garbled line at {}", source);
+            self.metadata.add_quarantined("logline", &source, "not text")?;
+          },
+        }
+        offset += read as u64;
+        if track {
+          self.metadata.set_peer(peernum, segment, offset)?;
+        }
+      }
+    }
+    Ok((segment, offset))
+  }
+
+  // Pick up the peers others have retired
+  fn fetch_retired(&self) -> Result<(), SyncerError> {
+    let mut path = self.local.clone();
+    path.push("retired");
+    let mut remote = self.server.clone();
+    remote.push_str("/data/retired/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg("-r");
+    cmd.arg(&remote);
+    cmd.arg(&path);
+    if !cmd.run_optional()? {
+      // Servers set up before peers could be retired don't have the dir
+      return Ok(())
+    }
+
+    for file in fs::read_dir(&path)? {
+      let file = file?;
+      let peerid = match file.file_name().to_str() {
+        Some(p) if p.len() == 16 && hex::decode(p).is_ok() => p.to_string(),
+        _ => continue,
+      };
+      let peernum = convert_peerid(&peerid);
+      if peernum == self.peernum || self.retired.read().unwrap().contains(&peernum) {
+        continue
+      }
+      // The marker has to be signed by a peer we trust
+      let mut contents = String::new();
+      File::open(file.path())?.read_to_string(&mut contents)?;
+      let end = match parse_retirement(&contents) {
+        Some((signer, end, signature)) => {
+          match self.peer_key(convert_peerid(signer))? {
+            Some(key) if verify(&key, retirement_message(&peerid, end).as_bytes(), signature) => Some(end),
+            _ => None,
+          }
+        },
+        None => None,
+      };
+      match end {
+        Some(Some(end)) => { self.retiring.lock().unwrap().insert(peernum, end); },
+        // Markers from before the end of the log was recorded retire the peer right away
+        Some(None) => self.finish_retirement(peernum)?,
+        None => eprintln!("WARNING: ignoring unsigned retirement of peer {}", peerid),
+      }
+    }
+    self.finish_retirements()
+  }
+
+  fn finish_retirement(&self, peernum: i64) -> Result<(), SyncerError> {
+    self.metadata.retire_peer(peernum)?;
+    self.retired.write().unwrap().insert(peernum);
+    self.retiring.lock().unwrap().remove(&peernum);
+    Ok(())
+  }
+
+  // Stop reading the logs of retired peers once we've caught up with all they wrote
+  fn finish_retirements(&self) -> Result<(), SyncerError> {
+    let retiring: Vec<(i64, (u64, u64))> = self.retiring.lock().unwrap().iter()
+      .map(|(p, e)| (*p, *e)).collect();
+    for (peernum, end) in retiring {
+      if self.metadata.get_peer(peernum)? >= end {
+        self.finish_retirement(peernum)?;
+      }
+    }
+    Ok(())
+  }
+
+  // Fetch all the nodes files in the server except our own and the retired peers',
+  // grouped by peer
+  fn fetch_node_logs(&self) -> Result<BTreeMap<String, Vec<(u64, PathBuf)>>, SyncerError> {
+    self.fetch_keys()?;
+    self.fetch_retired()?;
+    self.fetch_pack_indexes()?;
+
+    let mut path = self.local.clone();
+    path.push("nodes");
+    let mut remote = self.server.clone();
+    remote.push_str("/data/nodes/");
+
+    let mut cmd = RsyncCommand::new();
+    cmd.arg("-r");
+    cmd.arg(format!("--exclude={}*", self.peerid));
+    for peer in self.retired.read().unwrap().iter() {
+      cmd.arg(format!("--exclude={:016x}*", *peer as u64));
+    }
+    cmd.arg(&remote);
+    cmd.arg(&path);
+    cmd.run()?;
+
+    let mut logs: BTreeMap<String, Vec<(u64, PathBuf)>> = BTreeMap::new();
+    for file in fs::read_dir(&path)? {
+      let path = file?.path();
+      if path.is_dir() { continue }
+      // Whatever else is on the server isn't ours to read
+      let filename = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n.to_string(),
+        None => {
+          eprintln!("WARNING: ignoring {:?} in the node logs", path);
+          continue
+        },
+      };
+      if let Some((peerid, segment)) = parse_log_name(&filename) {
+        if peerid == self.peerid { continue }
+        if self.retired.read().unwrap().contains(&convert_peerid(peerid)) { continue }
+        logs.entry(peerid.to_string()).or_default().push((segment, path.clone()));
+      }
+    }
+    for segments in logs.values_mut() {
+      segments.sort();
+    }
+    Ok(logs)
+  }
+
+  pub fn do_downloads_nodes(&self) -> Result<(), SyncerError> {
+    for (peerid, segments) in self.fetch_node_logs()? {
+      let peernum = convert_peerid(&peerid);
+      let pubkey = self.peer_key(peernum)?;
+      self.read_peer_log(peernum, &segments, true, |line, source| {
+        let node = match self.decode_log_line(peernum, pubkey.as_deref(), source, line)? {
+          Some(n) => n,
+          None => return Ok(()),
+        };
+        self.apply_remote_node(&node, source)?;
+        self.metadata.peer_seen(peernum, node.creation)
+      })?;
+    }
+    self.finish_retirements()
+  }
+
+  // Wait for other peers to change something and then bring it in
+  pub fn wait_downloads_nodes(&self) -> Result<(), SyncerError> {
+    if self.notifier.wait_remote() {
+      self.do_downloads_nodes()
+    } else {
+      Ok(())
+    }
+  }
+
+  // Returns false once we're shutting down
+  pub fn wait_local_changes(&self) -> bool {
+    self.notifier.wait_local(std::time::Duration::from_secs(LIVE_POLL_INTERVAL))
+  }
+
+  pub fn stop_notifications(&self) {
+    self.notifier.stop();
+  }
+
+  // Like do_downloads_nodes but for nodes we don't have yet only the newest version
+  // from each peer is used. Older versions are just recorded so history can still
+  // fetch them from the server when it's needed. Nodes with a single newest version
+  // aren't even fetched until they're used.
+  pub fn do_downloads_nodes_latest(&self) -> Result<(), SyncerError> {
+    let mut lazy = HashSet::new();
+    let mut heads: BTreeMap<NodeId, Vec<NodeInfo>> = BTreeMap::new();
+    let mut positions = Vec::new();
+
+    for (peerid, segments) in self.fetch_node_logs()? {
+      let peernum = convert_peerid(&peerid);
+      let pubkey = self.peer_key(peernum)?;
+      let mut peerheads: BTreeMap<NodeId, NodeInfo> = BTreeMap::new();
+      let pos = self.read_peer_log(peernum, &segments, false, |line, source| {
+        let node = match self.decode_log_line(peernum, pubkey.as_deref(), source, line)? {
+          Some(n) => n,
+          None => return Ok(()),
+        };
+        self.metadata.peer_seen(peernum, node.creation)?;
+        if !lazy.contains(&node.id) {
+          if self.node_exists(node.id)? {
+            return self.apply_remote_node(&node, source)
+          }
+          lazy.insert(node.id);
+        }
+        if let Some(old) = peerheads.insert(node.id, node) {
+          self.metadata.import_node(old.id, &old.hash, old.creation)?;
+        }
+        Ok(())
+      })?;
+      for (id, node) in peerheads {
+        heads.entry(id).or_default().push(node);
+      }
+      positions.push((peernum, pos));
+    }
+
+    for (_, nodes) in heads {
+      let mut nodes = nodes.into_iter();
+      if let Some(first) = nodes.next() {
+        self.metadata.import_node(first.id, &first.hash, first.creation)?;
+      }
+      for node in nodes {
+        self.apply_remote_node(&node, &format!("head of {:?}", node.id))?;
+      }
+    }
+    for (peernum, (segment, offset)) in positions {
+      self.metadata.set_peer(peernum, segment, offset)?;
+    }
+    self.finish_retirements()
+  }
+
+  // All the peers we know of, including ourselves
+  pub fn peers(&self) -> Result<Vec<PeerInfo>, SyncerError> {
+    let mut peers = self.metadata.get_peer_infos()?;
+    if !peers.iter().any(|p| p.id == self.peernum) {
+      let segment = *self.segment.lock().unwrap();
+      let offset = fs::metadata(self.log_path(segment)).map(|m| m.len()).unwrap_or(0);
+      peers.push(PeerInfo {
+        id: self.peernum,
+        label: None,
+        segment,
+        offset,
+        lastseen: 0,
+        retired: false,
+        pubkey: None,
+        rejected: 0,
+        counter: 0,
+      });
+      peers.sort_by_key(|p| p.id);
+    }
+    for peer in peers.iter_mut().filter(|p| p.id == self.peernum) {
+      peer.pubkey = Some(self.signer.public_key());
+    }
+    for node in self.metadata.latest_nodes()? {
+      let entry = decode_entry(&node.hash, &self.read_blob(&node.hash)?)?;
+      for peer in peers.iter_mut() {
+        peer.counter = cmp::max(peer.counter, entry.vclock.get(peer.id));
+        if entry.peernum == peer.id {
+          peer.lastseen = cmp::max(peer.lastseen, node.creation);
+        }
+      }
+    }
+    Ok(peers)
+  }
+
+  pub fn label_peer(&self, peernum: i64, label: &str) -> Result<(), SyncerError> {
+    self.metadata.set_peer_label(peernum, label)
+  }
+
+  // Stop following a peer that's gone for good. Its entries get dropped from the
+  // vector clocks as nodes are changed from then on and every other peer picks up
+  // the retirement from the server.
+  pub fn retire_peer(&self, peernum: i64) -> Result<(), SyncerError> {
+    if peernum == self.peernum {
+      return Err(SyncerError::Invalid("can't retire ourselves".to_string()))
+    }
+    let peerid = format!("{:016x}", peernum as u64);
+    let mut path = self.local.clone();
+    path.push("retired");
+    path.push(&peerid);
+    // Other peers keep reading the log until they get as far as we did
+    let (segment, offset) = self.metadata.get_peer(peernum)?;
+    let signature = self.signer.sign(retirement_message(&peerid, Some((segment, offset))).as_bytes());
+    let mut file = File::create(&path)?;
+    file.write_all(&format!("{} {} {} {}\n", self.peerid, segment, offset, signature).into_bytes())?;
+    file.sync_all()?;
+    let mut remote = self.server.clone();
+    remote.push_str("/data/retired/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg(&path);
+    cmd.arg(&remote);
+    cmd.run()?;
+
+    self.metadata.retire_peer(peernum)?;
+    self.retired.write().unwrap().insert(peernum);
+    Ok(())
+  }
+
+  // Where each peer's log is at, including our own
+  fn log_positions(&self) -> Result<Vec<(i64, u64, u64)>, SyncerError> {
+    let mut logs: Vec<(i64, u64, u64)> = self.metadata.get_peers()?.into_iter()
+      .filter(|l| l.0 != self.peernum).collect();
+    let segment = *self.segment.lock().unwrap();
+    let size = fs::metadata(self.log_path(segment)).map(|m| m.len()).unwrap_or(0);
+    logs.push((self.peernum, segment, size));
+    logs.sort();
+    Ok(logs)
+  }
+
+  pub fn do_checkpoint(&self, force: bool) -> Result<(), SyncerError> {
+    // Everything the checkpoint points to needs to be in the logs on the server already
+    if self.metadata.pending_node_uploads()? {
+      return Err(SyncerError::Invalid("can't checkpoint with nodes pending upload".to_string()))
+    }
+    let logs = self.log_positions()?;
+    if !force && *self.last_checkpoint.lock().unwrap() == logs {
+      return Ok(())
+    }
+
+    let mut nodes = Vec::new();
+    for node in self.metadata.latest_nodes()? {
+      let entry = decode_entry(&node.hash, &self.read_blob(&node.hash)?)?;
+      nodes.push(CheckpointNode {
+        id: node.id,
+        hash: node.hash,
+        creation: node.creation,
+        vclock: entry.vclock,
+      });
+    }
+    let checkpoint = Checkpoint {
+      peernum: self.peernum,
+      creation: timeval(),
+      logs: logs.clone(),
+      nodes,
+    };
+    let hash = self.add_blob(&bincode::serialize(&checkpoint).unwrap())?;
+    self.do_save();
+    self.do_uploads()?;
+
+    // Only point to the checkpoint once it's on the server
+    let mut path = self.local.clone();
+    path.push("checkpoints");
+    path.push(&self.peerid);
+    let mut file = File::create(&path)?;
+    let signature = self.signer.sign(hash.digest());
+    file.write_all(&format!("{} {}\n", hash, signature).into_bytes())?;
+    file.sync_all()?;
+    self.publish_key()?;
+    let mut remote = self.server.clone();
+    remote.push_str("/data/checkpoints/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg(&path);
+    cmd.arg(&remote);
+    cmd.run()?;
+
+    *self.last_checkpoint.lock().unwrap() = logs;
+    Ok(())
+  }
+
+  // Bring in the newest checkpoint from any peer. When starting from scratch the nodes
+  // are just added as they are, otherwise they're merged like any other remote change.
+  pub fn load_checkpoint(&self, fresh: bool) -> Result<bool, SyncerError> {
+    let mut path = self.local.clone();
+    path.push("checkpoints");
+    let mut remote = self.server.clone();
+    remote.push_str("/data/checkpoints/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg("-r");
+    cmd.arg(&remote);
+    cmd.arg(&path);
+    if cmd.run().is_err() {
+      eprintln!("WARNING: couldn't fetch checkpoints from the server");
+      return Ok(false)
+    }
+
+    self.fetch_keys()?;
+
+    let mut newest: Option<Checkpoint> = None;
+    for file in fs::read_dir(&path)? {
+      let file = file?;
+      let peernum = match file.file_name().to_str() {
+        Some(p) if p.len() == 16 && hex::decode(p).is_ok() => convert_peerid(p),
+        _ => continue,
+      };
+      let mut contents = String::new();
+      File::open(file.path())?.read_to_string(&mut contents)?;
+      let mut parts = contents.split_whitespace();
+      let hash = match BlobHash::parse(parts.next().unwrap_or("")) {
+        Some(h) => h,
+        None => continue,
+      };
+      let signed = match (self.peer_key(peernum)?, parts.next()) {
+        (Some(key), Some(signature)) => verify(&key, hash.digest(), signature),
+        _ => false,
+      };
+      if !signed {
+        eprintln!("WARNING: ignoring unsigned checkpoint from peer {:016x}", peernum as u64);
+        continue
+      }
+      let checkpoint = match self.read_blob(&hash).map(|buffer| decode_checkpoint(&buffer)) {
+        Ok(Some(checkpoint)) => checkpoint,
+        Ok(None) => {
+          eprintln!("WARNING: ignoring unreadable checkpoint {} from peer {:016x}", hash, peernum as u64);
+          continue
+        },
+        Err(_) => continue,
+      };
+      if checkpoint.peernum != peernum { continue }
+      match newest {
+        Some(ref n) if n.creation >= checkpoint.creation => {},
+        _ => newest = Some(checkpoint),
+      }
+    }
+    let checkpoint = match newest {
+      Some(c) => c,
+      None => return Ok(false),
+    };
+
+    for node in checkpoint.nodes {
+      if fresh {
+        self.metadata.import_node(node.id, &node.hash, node.creation)?;
+      } else if !self.metadata.node_exists_long(node.id, &node.hash, node.creation)? {
+        let entry = decode_entry(&node.hash, &self.read_blob(&node.hash)?)?;
+        self.save_node(node.id, &entry)?;
+      }
+    }
+    for (peernum, segment, offset) in checkpoint.logs {
+      if peernum == self.peernum { continue }
+      if (segment, offset) > self.metadata.get_peer(peernum)? {
+        self.metadata.set_peer(peernum, segment, offset)?;
+      }
+    }
+    Ok(true)
+  }
+
+  // Start a new log segment, checkpoint and then drop the older segments of our own
+  // log both locally and from the server
+  pub fn compact(&self) -> Result<(), SyncerError> {
+    let (segment, _) = self.current_log(true);
+    self.do_checkpoint(true)?;
+
+    for (seg, path) in log_segments(&self.local.join("nodes"), &self.peerid) {
+      if seg < segment {
+        fs::remove_file(&path)?;
+      }
+    }
+    let mut path = self.local.join("nodes");
+    path.push("");
+    let mut remote = self.server.clone();
+    remote.push_str("/data/nodes/");
+    let mut cmd = RsyncCommand::new();
+    cmd.arg("-r");
+    cmd.arg("--delete");
+    cmd.arg(format!("--include={}*", self.peerid));
+    cmd.arg("--exclude=*");
+    cmd.arg(&path);
+    cmd.arg(&remote);
+    Ok(cmd.run()?)
+  }
+
+  pub fn do_removals(&self) -> Result<(), SyncerError> {
+    {
+      let mut touched = self.touched_blobs.write().unwrap();
+      self.metadata.touch_blobs(touched.drain());
+    }
+
+    let bytes_to_delete = {
+      let localbytes = self.metadata.localbytes();
+      if localbytes > self.maxbytes { localbytes - self.maxbytes } else { return Ok(()) }
+    };
+
+    let mut deleted_bytes = 0;
     loop {
       let hashes_to_delete = self.metadata.to_delete();
       if hashes_to_delete.len() == 0 {
@@ -446,14 +1718,12 @@ impl BlobStorage {
       }
       let mut deleted = Vec::new();
       for (hash, size) in hashes_to_delete {
-        let path = self.local_path(&hash);
-        let delete_worked = fs::remove_file(&path).is_ok();
-        if !delete_worked {
-          if !path.exists() {
-            eprintln!("WARNING: tried to delete file that's already gone {:?}", path);
+        if let Err(e) = self.store.remove(&hash) {
+          if !self.store.contains(&hash) {
+            eprintln!("WARNING: tried to delete blob that's already gone {}", hash);
           } else {
-            eprintln!("WARNING: failed to delete {:?}", path);
-            continue; // We couldn't delete the file so space is not reclaimed
+            eprintln!("WARNING: failed to delete blob {}: {}", hash, e);
+            continue; // We couldn't delete the blob so space is not reclaimed
           }
         }
 
@@ -469,52 +1739,46 @@ impl BlobStorage {
         break
       }
     }
+    self.store.reclaim();
 
     Ok(())
   }
 
-  pub fn local_path(&self, hash: &BlobHash) -> PathBuf {
-    // As far as I can tell from online references there's no penalty in ext4 for
-    // random lookup in a directory with lots of files. So just store all the hashed
-    // files in a straight directory with no fanout to not waste space with directory
-    // entries. Just doing a 12bit fanout (4096 directories) wastes 17MB on ext4.
-    let mut path = self.local.clone();
-    path.push("blobs");
-    path.push(hex::encode(hash));
-    path
-  }
-
   fn remote_path(&self, hash: &BlobHash) -> String {
     let mut remote = self.server.clone();
     remote.push_str(&"/data/blobs/");
-    remote.push_str(&hex::encode(hash));
+    remote.push_str(&hash.to_string());
     remote
   }
 
-  pub fn upload_to_server(&self, hashes: &[BlobHash]) -> Result<(), c_int> {
+  pub fn upload_to_server(&self, hashes: &[BlobHash]) -> Result<(), SyncerError> {
     let mut cmd = RsyncCommand::new();
+    let mut files = Vec::new();
     for hash in hashes {
-      let path = self.local_path(hash);
-      if !path.exists() {
-        eprintln!("ERROR: couldn't find file {:?} to upload!", path);
-      } else {
-        cmd.arg(&path);
+      match self.store.file(hash) {
+        Ok(file) => {
+          cmd.arg(&file.0);
+          files.push(file);
+        },
+        Err(e) => eprintln!("ERROR: couldn't find blob {} to upload: {}", hash, e),
       }
     }
     let mut remote = self.server.clone();
     remote.push_str(&"/data/blobs/");
     cmd.arg(&remote);
-    match cmd.run() {
-      Ok(_) => return Ok(()),
-      Err(_) => {},
+    let res = cmd.run();
+    for (path, copy) in files {
+      self.store.done_with_file(&path, copy);
+    }
+    match res {
+      Ok(_) => Ok(()),
+      Err(e) => Err(SyncerError::Remote(format!("couldn't upload blobs: {}", e))),
     }
-    eprintln!("ERROR: Failed to upload blocks to server");
-    Err(libc::EIO)
   }
 
   pub fn readahead_from_server<'a>(&'a self, hashes: &[BlobHash]) {
     for hash in hashes {
-      if hash != &HASHZERO && !self.local_path(hash).exists() {
+      if hash != &HASHZERO && !self.store.contains(hash) {
         let hash = hash.clone();
         unsafe{crossbeam_utils::thread::spawn_unchecked(move || {
           let mut ongoing = self.ongoing.write(&hash);
@@ -530,14 +1794,10 @@ impl BlobStorage {
             }
             // If we've loaded the file we need to make sure it gets touch()ed so that
             // it shows up in the blobs table if it didn't exist before
-            let file = self.local_path(&hash);
-            match Blob::load(&file) {
-              Err(_) => {}, 
-              Ok(blob) => {
-                let timeval = timeval();
-                let mut touched = self.touched_blobs.write().unwrap();
-                touched.insert(hash.clone(), (timeval, blob.len()));
-              },
+            if let Some(size) = self.store.size(&hash) {
+              let timeval = timeval();
+              let mut touched = self.touched_blobs.write().unwrap();
+              touched.insert(hash.clone(), (timeval, size as usize));
             }
           }
         });}
@@ -545,7 +1805,7 @@ impl BlobStorage {
     }
   }
 
-  pub fn fetch_from_server(&self, hash: &BlobHash) -> Result<(), c_int> {
+  pub fn fetch_from_server(&self, hash: &BlobHash) -> Result<(), SyncerError> {
     let mutex = {
       let mut ongoing = self.ongoing.write(hash);
       if ongoing.contains_key(hash) {
@@ -560,24 +1820,575 @@ impl BlobStorage {
         *res = self.real_fetch_from_server(hash);
         let mut ongoing = self.ongoing.write(hash); // Grab the lock again
         ongoing.remove(hash); // Remove from the hash as it's already done now
-        return if *res {Ok(())} else {Err(libc::EIO)}
+        return if *res {Ok(())} else {Err(Self::fetch_error(hash))}
       }
     };
 
     let res = mutex.lock().unwrap();
-    if *res {Ok(())} else {Err(libc::EIO)}
+    if *res {Ok(())} else {Err(Self::fetch_error(hash))}
+  }
+
+  fn fetch_error(hash: &BlobHash) -> SyncerError {
+    SyncerError::Remote(format!("couldn't fetch blob {}", hash))
   }
 
   fn real_fetch_from_server(&self, hash: &BlobHash) -> bool {
+    if self.fetch_from_packs(hash) {
+      return true
+    }
+    // Fetch to the side and only move it into place once it matches its hash
     let remote = self.remote_path(hash);
-    let mut cmd = RsyncCommand::new();
-    cmd.arg(&remote);
-    let mut path = self.local.clone();
-    path.push("blobs");
-    cmd.arg(&path);
-    match cmd.run() {
-      Ok(_) => true,
+    let mut incoming = self.local.clone();
+    incoming.push("incoming");
+    let path = incoming.join(hash.to_string());
+    for _ in 0..FETCH_RETRIES {
+      let mut cmd = RsyncCommand::new();
+      cmd.arg(&remote);
+      cmd.arg(&incoming);
+      if cmd.run().is_err() {
+        // It may be in a pack we haven't seen the index for yet
+        return self.fetch_pack_indexes().is_ok() && self.fetch_from_packs(hash)
+      }
+      if Self::check_file(&path, hash) {
+        return self.store.insert_file(hash, &path).is_ok()
+      }
+      eprintln!("WARNING: blob {} from the server doesn't match its hash", hash);
+      self.quarantine(&path, hash);
+    }
+    false
+  }
+
+  fn check_file(path: &Path, hash: &BlobHash) -> bool {
+    match fs::read(path) {
+      Ok(data) => hash.matches(&data),
+      Err(_) => false,
+    }
+  }
+
+  fn check_local(&self, hash: &BlobHash) -> bool {
+    match self.store.read(hash) {
+      Ok(data) => hash.matches(&data),
       Err(_) => false,
     }
   }
+
+  // Keep bad blobs around for inspection instead of deleting them
+  fn quarantine(&self, path: &Path, hash: &BlobHash) {
+    let mut dest = self.local.clone();
+    dest.push("quarantine");
+    let name = format!("{}.{}", hash, timeval());
+    dest.push(&name);
+    if fs::rename(path, &dest).is_err() {
+      fs::remove_file(path).ok();
+    }
+    if let Err(e) = self.metadata.add_quarantined("blob", &name, "doesn't match its hash") {
+      eprintln!("WARNING: couldn't record quarantined blob {}: {}", name, e);
+    }
+  }
+
+  fn quarantine_pack(&self, path: &Path, name: &str, bad: usize) {
+    let mut dest = self.local.clone();
+    dest.push("quarantine");
+    let quarantined = format!("{}.pack.{}", name, timeval());
+    dest.push(&quarantined);
+    if fs::rename(path, &dest).is_err() {
+      fs::remove_file(path).ok();
+    }
+    let reason = format!("{} blobs don't match their hash", bad);
+    if let Err(e) = self.metadata.add_quarantined("pack", &quarantined, &reason) {
+      eprintln!("WARNING: couldn't record quarantined pack {}: {}", quarantined, e);
+    }
+  }
+
+  fn quarantine_local(&self, hash: &BlobHash) {
+    let mut dest = self.local.clone();
+    dest.push("quarantine");
+    let name = format!("{}.{}", hash, timeval());
+    dest.push(&name);
+    if self.store.take(hash, &dest).is_err() {
+      self.store.remove(hash).ok();
+    }
+    if let Err(e) = self.metadata.add_quarantined("blob", &name, "doesn't match its hash") {
+      eprintln!("WARNING: couldn't record quarantined blob {}: {}", name, e);
+    }
+  }
+
+  pub fn quarantined(&self) -> Result<Vec<QuarantineInfo>, SyncerError> {
+    self.metadata.get_quarantined()
+  }
+
+  // Check every local blob against its hash. Bad ones are quarantined and get fetched
+  // again from the server when needed, unless they never made it there.
+  pub fn verify_local(&self, report: &mut VerifyReport) -> Result<(), SyncerError> {
+    for (hash, _) in self.store.blobs()? {
+      report.checked += 1;
+      if self.check_local(&hash) { continue }
+
+      self.quarantine_local(&hash);
+      self.metadata.mark_deleted_blobs(&[hash], true);
+      if self.metadata.blob_synced(&hash)? {
+        report.corrupted.push(hash);
+      } else {
+        report.lost.push(hash);
+      }
+    }
+    Ok(())
+  }
+
+  // Fetch every blob the server should have and check it, uploading our own copy
+  // again when the server's is bad and ours is fine
+  pub fn verify_remote(&self, report: &mut VerifyReport) -> Result<(), SyncerError> {
+    let mut incoming = self.local.clone();
+    incoming.push("incoming");
+    let mut single = Vec::new();
+    let mut packed: BTreeMap<String, Vec<PackEntry>> = BTreeMap::new();
+    for hash in self.metadata.synced_blobs()? {
+      match self.metadata.packs_with(&hash)?.into_iter().next() {
+        Some((name, entry)) => packed.entry(name).or_default().push(entry),
+        None => single.push(hash),
+      }
+    }
+
+    for (name, entries) in packed {
+      let filename = format!("{}.pack", name);
+      let mut cmd = RsyncCommand::new();
+      cmd.arg(format!("{}/data/packs/{}", self.server, filename));
+      cmd.arg(&incoming);
+      let path = incoming.join(&filename);
+      let pack = if cmd.run().is_ok() { Some(fs::read(&path)?) } else { None };
+      let mut bad = 0;
+      for entry in entries {
+        report.remote_checked += 1;
+        match pack.as_ref().map(|p| extract(p, &entry)) {
+          None => report.remote_missing.push(entry.hash),
+          Some(Some(blob)) if entry.hash.matches(blob) => continue,
+          Some(_) => {
+            bad += 1;
+            report.remote_corrupted.push(entry.hash);
+          },
+        }
+        // Good copies go up on their own, those get fetched when the pack doesn't work
+        self.repair_remote(&entry.hash, report);
+      }
+      if bad > 0 {
+        self.quarantine_pack(&path, &name, bad);
+      } else if pack.is_some() {
+        fs::remove_file(&path)?;
+      }
+    }
+
+    for hashes in single.chunks(TO_VERIFY) {
+      let mut cmd = RsyncCommand::new();
+      for hash in hashes {
+        cmd.arg(self.remote_path(hash));
+      }
+      cmd.arg(&incoming);
+      // Missing blobs make rsync fail but the others still get fetched
+      cmd.run().ok();
+
+      for hash in hashes {
+        report.remote_checked += 1;
+        let path = incoming.join(hash.to_string());
+        if !path.exists() {
+          report.remote_missing.push(*hash);
+        } else if Self::check_file(&path, hash) {
+          fs::remove_file(&path)?;
+          continue
+        } else {
+          self.quarantine(&path, hash);
+          report.remote_corrupted.push(*hash);
+        }
+        self.repair_remote(hash, report);
+      }
+    }
+    Ok(())
+  }
+
+  // Upload our own copy of a blob the server lost or has a bad copy of
+  fn repair_remote(&self, hash: &BlobHash, report: &mut VerifyReport) {
+    if self.check_local(hash) && self.upload_to_server(&[*hash]).is_ok() {
+      report.repaired.push(*hash);
+    }
+  }
+
+  // Whether a blob is here or has made it to the server so it can be fetched
+  pub fn blob_available(&self, hash: &BlobHash) -> bool {
+    *hash == HASHZERO || self.store.contains(hash) || self.metadata.blob_synced(hash).unwrap_or(false)
+  }
+
+  // Store every node that isn't in the current FSEntry layout again. The ones from
+  // before parents and hardlink counts were tracked get them from the directories that
+  // link to them. Nothing else changes so every peer that upgrades ends up with the
+  // same blobs.
+  pub fn upgrade_entries(&self) -> Result<usize, SyncerError> {
+    let root = (0, 0);
+    let mut parents = HashMap::new();
+    let mut links: HashMap<NodeId, u32> = HashMap::new();
+    let mut queue = vec![root];
+    parents.insert(root, root);
+    while let Some(node) = queue.pop() {
+      let entry = match self.read_entry(node) {
+        Ok(e) => e,
+        Err(e) => {
+          eprintln!("WARNING: can't read node {:?} to upgrade it: {}", node, e);
+          continue
+        },
+      };
+      for (child, _) in entry.children.values() {
+        *links.entry(*child).or_insert(0) += 1;
+        if !parents.contains_key(child) {
+          parents.insert(*child, node);
+          queue.push(*child);
+        }
+      }
+    }
+
+    let mut count = 0;
+    for node in self.latest_node_ids()? {
+      let (hash, buffer) = self.read_node(node)?;
+      let (mut entry, version) = match FSEntry::decode_versioned(&buffer) {
+        Ok(e) => e,
+        Err(e) => {
+          eprintln!("WARNING: can't upgrade node {:?} in blob {}: {}", node, hash, e);
+          continue
+        },
+      };
+      if version < 2 {
+        if let Some(parent) = parents.get(&node) {
+          entry.parent = *parent;
+        }
+        if entry.filetype != FileTypeDef::Directory {
+          entry.nlink = cmp::max(1, links.get(&node).cloned().unwrap_or(0));
+        }
+      }
+      let encoded = entry.encode();
+      if encoded == buffer { continue }
+      let newhash = self.add_blob(&encoded)?;
+      self.metadata.set_node(node, &newhash, entry.timeval())?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  pub fn latest_node_ids(&self) -> Result<Vec<NodeId>, SyncerError> {
+    Ok(self.metadata.latest_nodes()?.into_iter().map(|n| n.id).collect())
+  }
+
+  // Check that the blobs table matches what's actually in the local store
+  pub fn fsck_blobs(&self, repair: bool, report: &mut FsckReport) -> Result<(), SyncerError> {
+    let mut rows = HashSet::new();
+    let (blobs, bad) = self.metadata.all_blobs()?;
+    for name in bad {
+      if repair {
+        self.metadata.remove_blob_row(&name)?;
+      }
+      report.issue(format!("blobs table has a row for invalid hash {:?}", name), repair);
+    }
+    for (hash, present, size) in blobs {
+      rows.insert(hash);
+      report.blobs += 1;
+      let name = hash.to_string();
+      match self.store.size(&hash) {
+        Some(local) => {
+          if !present || size != local {
+            if repair {
+              self.metadata.fix_blob(&hash, true, local)?;
+            }
+            report.issue(format!("blob {} is listed as present={} size={} but has {} bytes locally",
+                                 name, present, size, local), repair);
+          }
+        },
+        None => {
+          if present {
+            // One that made it to the server can be fetched again, otherwise it's gone
+            if !self.metadata.blob_synced(&hash)? {
+              report.issue(format!("blob {} was lost before it was uploaded", name), false);
+              if repair {
+                self.metadata.fix_blob(&hash, false, size)?;
+              }
+              continue
+            }
+            let fetched = repair && self.fetch_from_server(&hash).is_ok();
+            if repair {
+              match self.store.size(&hash) {
+                Some(local) if fetched => self.metadata.fix_blob(&hash, true, local)?,
+                _ => self.metadata.fix_blob(&hash, false, size)?,
+              }
+            }
+            report.issue(format!("blob {} is listed as present but isn't there", name), fetched);
+          }
+        },
+      }
+    }
+
+    let mut missing = Vec::new();
+    for (hash, size) in self.store.blobs()? {
+      if rows.contains(&hash) { continue }
+      report.issue(format!("blob {} isn't in the blobs table", hash), repair);
+      missing.push((hash, (timeval(), size as usize)));
+    }
+    if repair {
+      self.metadata.touch_blobs(missing.drain(..));
+    }
+    Ok(())
+  }
+
+  // Check that every line of the node logs we have parses and is properly signed
+  pub fn fsck_logs(&self, report: &mut FsckReport) -> Result<(), SyncerError> {
+    let mut path = self.local.clone();
+    path.push("nodes");
+    let mut files = Vec::new();
+    for file in fs::read_dir(&path)? {
+      let path = file?.path();
+      if let Some((peerid, _)) = path.file_name().and_then(|n| n.to_str()).and_then(parse_log_name) {
+        files.push((convert_peerid(peerid), path.clone()));
+      }
+    }
+    files.sort();
+
+    for (peernum, path) in files {
+      let pubkey = self.peer_key(peernum)?;
+      if pubkey.is_none() {
+        report.issue(format!("{:?} is from a peer we don't have a key for", path), false);
+        continue
+      }
+      for (i, line) in BufReader::new(File::open(&path)?).split(b'\n').enumerate() {
+        report.loglines += 1;
+        let res = match String::from_utf8(line?) {
+          Ok(line) => parse_node_line(&line, pubkey.as_deref()),
+          Err(_) => Err("not text"),
+        };
+        if let Err(e) = res {
+          report.issue(format!("line {} of {:?}: {}", i+1, path, e), false);
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::testutil::*;
+
+  #[test]
+  fn dirty_limit() {
+    let dir = TempDir::new("dirty");
+    let mut config = test_config();
+    config.maxdirty = 2 * BLKSIZE as u64;
+    let bs = BlobStorage::new(dir.path(), &config).unwrap();
+    let zero = bs.add_blob(&[0]).unwrap();
+    for block in 0..3 {
+      bs.write((1, 1), block, &zero, 0, &[block as u8 + 1], &[]).unwrap();
+    }
+    bs.write((1, 1), 0, &zero, 1, &[9], &[]).unwrap();
+    assert_eq!(3, bs.dirty_blocks());
+    assert!(bs.over_dirty_limit());
+    assert_eq!(Some(((1, 1), 0)), bs.oldest_dirty());
+    bs.requeue_dirty((1, 1), 0);
+    assert_eq!(Some(((1, 1), 1)), bs.oldest_dirty());
+
+    let hash = bs.flush_block((1, 1), 1).unwrap().unwrap();
+    assert_eq!(vec![2], bs.read_blob(&hash).unwrap());
+    assert_eq!(None, bs.flush_block((1, 1), 1).unwrap());
+    assert!(!bs.over_dirty_limit());
+    assert_eq!(Some(((1, 1), 2)), bs.oldest_dirty());
+
+    let mut stored = bs.sync_node((1, 1)).unwrap();
+    stored.sort();
+    assert_eq!(2, stored.len());
+    assert_eq!(vec![1, 9], bs.read_blob(&stored[0].1).unwrap());
+    assert_eq!(0, bs.dirty_blocks());
+  }
+
+  #[test]
+  fn conflict_copies_collapse() {
+    let dir = TempDir::new("conflict-copies");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let node = (5, 1);
+    let mut root = FSEntry::new(FileTypeDef::Directory, 0);
+    root.children.insert("file".to_string(), (node, FileTypeDef::RegularFile));
+    bs.save_node((0, 0), &root).unwrap();
+    let mut merged = FSEntry::new(FileTypeDef::RegularFile, 0);
+    merged.vclock.increment(1);
+    merged.vclock.increment(2);
+    bs.save_node(node, &merged).unwrap();
+
+    // The losing version is from a third peer and every merging peer makes the copy
+    let mut loser = merged.clone();
+    loser.peernum = 3;
+    loser.size = 10;
+    let copy = bs.create_conflict_copy(node, &merged, &loser).unwrap();
+    assert_eq!(copy, bs.create_conflict_copy(node, &merged, &loser).unwrap());
+    assert_eq!(copy, conflict_copy_id(node, &loser));
+    assert_eq!(10, bs.read_entry(copy).unwrap().size);
+    let root = bs.read_entry((0, 0)).unwrap();
+    assert_eq!(2, root.children.len());
+    assert!(root.children.values().any(|c| c.0 == copy));
+
+    loser.size = 20;
+    assert!(copy != conflict_copy_id(node, &loser));
+    assert!(conflict_copy_id(node, &loser) != conflict_copy_id((5, 2), &loser));
+  }
+
+  #[test]
+  fn hash_names() {
+    let hash = Blob::new_with_data(vec![0; 10]).hash(HashKind::default());
+    assert_eq!(Some(hash), BlobHash::parse(&hash.to_string()));
+    assert_eq!(None, BlobHash::parse("00"));
+    assert_eq!(None, BlobHash::parse("not a hash"));
+  }
+
+  #[test]
+  fn log_names() {
+    let peerid = "0123456789abcdef";
+    assert_eq!(Some((peerid, 0)), parse_log_name(&log_name(peerid, 0)));
+    assert_eq!(Some((peerid, 12)), parse_log_name(&log_name(peerid, 12)));
+    assert_eq!(None, parse_log_name("0123456789abcdef.0"));
+    assert_eq!(None, parse_log_name("0123456789abcdef.foo"));
+    assert_eq!(None, parse_log_name("0123456789abcdeg"));
+    assert_eq!(None, parse_log_name("0123456789abcde"));
+  }
+
+  #[test]
+  fn retirement_waits_for_log() {
+    let signer = "0123456789abcdef";
+    assert_eq!(Some((signer, Some((2, 340)), "sig")), parse_retirement("0123456789abcdef 2 340 sig\n"));
+    assert_eq!(Some((signer, None, "sig")), parse_retirement("0123456789abcdef sig\n"));
+    assert_eq!(None, parse_retirement("0123456789abcdef two 340 sig"));
+    assert_eq!(None, parse_retirement("nothex sig"));
+    assert!(retirement_message(signer, Some((2, 340))) != retirement_message(signer, Some((2, 341))));
+
+    // The peer stays around until we've read its log up to where it ended
+    let dir = TempDir::new("retiring");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let peer = 42;
+    bs.metadata.set_peer(peer, 2, 100).unwrap();
+    bs.retiring.lock().unwrap().insert(peer, (2, 340));
+    bs.finish_retirements().unwrap();
+    assert!(!bs.retired.read().unwrap().contains(&peer));
+    bs.metadata.set_peer(peer, 2, 340).unwrap();
+    bs.finish_retirements().unwrap();
+    assert!(bs.retired.read().unwrap().contains(&peer));
+    assert!(bs.retiring.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn remote_changes() {
+    let dir = TempDir::new("remote-changes");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    // Nothing is cached yet the first time a node is opened
+    assert!(bs.take_remote_change((0, 1)));
+    assert!(!bs.take_remote_change((0, 1)));
+    bs.fresh_nodes.lock().unwrap().remove(&(0, 1));
+    assert!(bs.take_remote_change((0, 1)));
+
+    for i in 0..MAX_FRESH_NODES as i64 {
+      bs.take_remote_change((1, i));
+    }
+    assert!(bs.fresh_nodes.lock().unwrap().len() <= MAX_FRESH_NODES);
+    assert!(bs.take_remote_change((0, 1)));
+  }
+
+  #[test]
+  fn trust_rereads_log() {
+    let dir = TempDir::new("trust");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let peer = 42;
+    bs.metadata.set_peer(peer, 1, 200).unwrap();
+    bs.metadata.add_rejected(peer).unwrap();
+    assert!(bs.trust_peer(peer, "notakey").is_err());
+    assert_eq!((1, 200), bs.metadata.get_peer(peer).unwrap());
+
+    // The key it published is listed until it's trusted
+    let key = Signer::new(&Config::new_secretkey()).unwrap().public_key();
+    fs::create_dir_all(dir.path().join("keys")).unwrap();
+    fs::write(dir.path().join("keys").join(format!("{:016x}", peer)), format!("{}\n", key)).unwrap();
+    assert_eq!(vec![(peer, key.clone())], bs.untrusted_peers().unwrap());
+    bs.trust_peer(peer, &key).unwrap();
+    assert!(bs.untrusted_peers().unwrap().is_empty());
+    assert_eq!((0, 0), bs.metadata.get_peer(peer).unwrap());
+    assert_eq!(0, bs.metadata.get_peer_rejected(peer).unwrap());
+
+    // Trusting the same key again doesn't start over
+    bs.metadata.set_peer(peer, 1, 200).unwrap();
+    bs.trust_peer(peer, &key).unwrap();
+    assert_eq!((1, 200), bs.metadata.get_peer(peer).unwrap());
+  }
+
+  #[test]
+  fn fsck_blobs() {
+    let dir = TempDir::new("fsck-blobs");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let lost = BlobHash::compute(bs.hashkind, b"lost");
+    let uploaded = BlobHash::compute(bs.hashkind, b"uploaded");
+    let resized = bs.add_blob(b"resized").unwrap();
+    let untracked = bs.add_blob(b"untracked").unwrap();
+    bs.metadata.touch_blobs(vec![(lost, (0, 4)), (uploaded, (0, 8)), (resized, (0, 1))].into_iter());
+    bs.metadata.mark_synced_blobs(vec![uploaded].into_iter());
+
+    let mut report = FsckReport::default();
+    bs.fsck_blobs(false, &mut report).unwrap();
+    assert_eq!(4, report.issues.len());
+    assert!(report.issues.iter().all(|(_, fixed)| !fixed));
+
+    // Without a server the uploaded one can't be fetched again but it's no longer
+    // listed as here, so it gets fetched when it's used
+    let mut report = FsckReport::default();
+    bs.fsck_blobs(true, &mut report).unwrap();
+    let fixed: Vec<&String> = report.issues.iter().filter(|i| i.1).map(|i| &i.0).collect();
+    assert_eq!(2, fixed.len());
+    assert!(report.issues.iter().any(|(i, fixed)| !fixed && i.contains(&lost.to_string()) && i.contains("lost")));
+    assert!(report.issues.iter().any(|(i, fixed)| !fixed && i.contains(&uploaded.to_string())));
+
+    let mut report = FsckReport::default();
+    bs.fsck_blobs(false, &mut report).unwrap();
+    assert!(report.issues.is_empty());
+    let rows = bs.metadata.all_blobs().unwrap().0;
+    assert!(rows.contains(&(untracked, true, 9)));
+    assert!(rows.contains(&(resized, true, 7)));
+    assert!(rows.contains(&(uploaded, false, 8)));
+  }
+
+  #[test]
+  fn fsck_logs() {
+    let dir = TempDir::new("fsck-logs");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let signer = Signer::new(&Config::new_secretkey()).unwrap();
+    let trusted = "00000000000000aa";
+    bs.trust_peer(convert_peerid(trusted), &signer.public_key()).unwrap();
+    let node = NodeInfo { id: (1, 2), hash: BlobHash::compute(bs.hashkind, b"node"), creation: 3 };
+    let good = signer.sign_node(&node);
+    let forged = Signer::new(&Config::new_secretkey()).unwrap().sign_node(&node);
+    let nodes = dir.path().join("nodes");
+    let mut log = format!("{}\n{}\ngarbage\n", good, forged).into_bytes();
+    log.extend_from_slice(b"\xff\xfe\n");
+    fs::write(nodes.join(trusted), log).unwrap();
+    fs::write(nodes.join("00000000000000bb"), format!("{}\n", good)).unwrap();
+
+    let mut report = FsckReport::default();
+    bs.fsck_logs(&mut report).unwrap();
+    assert_eq!(4, report.loglines);
+    let issues: Vec<&String> = report.issues.iter().map(|i| &i.0).collect();
+    assert_eq!(4, issues.len());
+    assert!(issues[0].contains("line 2") && issues[0].contains("bad signature"));
+    assert!(issues[1].contains("line 3"));
+    assert!(issues[2].contains("line 4") && issues[2].contains("not text"));
+    assert!(issues[3].contains("don't have a key"));
+  }
+
+  #[test]
+  fn sync_saved() {
+    let dir = TempDir::new("sync-saved");
+    let bs = BlobStorage::new(dir.path(), &test_config()).unwrap();
+    let kept = bs.add_blob(b"kept").unwrap();
+    let removed = bs.add_blob(b"removed").unwrap();
+    bs.store.remove(&removed).unwrap();
+    assert_eq!(2, bs.unsynced_blobs.lock().unwrap().len());
+    bs.do_save();
+    bs.sync_saved().unwrap();
+    assert!(bs.unsynced_blobs.lock().unwrap().is_empty());
+    assert!(bs.store.contains(&kept));
+  }
 }
@@ -0,0 +1,102 @@
+extern crate libc;
+extern crate rusqlite;
+
+use self::libc::c_int;
+use std::fmt;
+use std::io;
+
+// What went wrong in the backing store. This only gets turned into an errno when it
+// reaches the FUSE side, the CLI commands and the logs get the whole story.
+#[derive(Debug)]
+pub enum SyncerError {
+  // The metadata database failed
+  Db(String),
+  // Reading or writing local files failed
+  Io(io::Error),
+  // Talking to the server failed
+  Remote(String),
+  // Something we read doesn't match what it should be
+  Corruption(String),
+  // A node, blob or row that should be there isn't
+  NotFound(String),
+  // Bad configuration or arguments
+  Invalid(String),
+}
+
+impl SyncerError {
+  pub fn errno(&self) -> c_int {
+    match self {
+      SyncerError::NotFound(_) => libc::ENOENT,
+      SyncerError::Invalid(_) => libc::EINVAL,
+      SyncerError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+      _ => libc::EIO,
+    }
+  }
+}
+
+impl fmt::Display for SyncerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SyncerError::Db(e) => write!(f, "database error: {}", e),
+      SyncerError::Io(e) => write!(f, "I/O error: {}", e),
+      SyncerError::Remote(e) => write!(f, "server error: {}", e),
+      SyncerError::Corruption(e) => write!(f, "corruption: {}", e),
+      SyncerError::NotFound(e) => write!(f, "not found: {}", e),
+      SyncerError::Invalid(e) => write!(f, "invalid: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for SyncerError {}
+
+impl From<io::Error> for SyncerError {
+  fn from(e: io::Error) -> Self {
+    SyncerError::Io(e)
+  }
+}
+
+impl From<rusqlite::Error> for SyncerError {
+  fn from(e: rusqlite::Error) -> Self {
+    match e {
+      rusqlite::Error::QueryReturnedNoRows => SyncerError::NotFound("no such row in the database".to_string()),
+      e => SyncerError::Db(e.to_string()),
+    }
+  }
+}
+
+impl From<SyncerError> for io::Error {
+  fn from(e: SyncerError) -> Self {
+    match e {
+      SyncerError::Io(e) => e,
+      e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+  }
+}
+
+// Where the backing store meets FUSE. Anything other than a missing file is logged
+// here as the kernel only gets the errno.
+impl From<SyncerError> for c_int {
+  fn from(e: SyncerError) -> Self {
+    match e {
+      SyncerError::NotFound(_) => {},
+      ref e => eprintln!("ERROR: {}", e),
+    }
+    e.errno()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn errnos() {
+    assert_eq!(libc::ENOENT, SyncerError::NotFound("node".to_string()).errno());
+    assert_eq!(libc::EIO, SyncerError::Remote("rsync".to_string()).errno());
+    assert_eq!(libc::ENOSPC, SyncerError::from(io::Error::from_raw_os_error(libc::ENOSPC)).errno());
+    let e: SyncerError = rusqlite::Error::QueryReturnedNoRows.into();
+    assert_eq!(libc::ENOENT, c_int::from(e));
+    let e: io::Error = SyncerError::Corruption("blob".to_string()).into();
+    assert_eq!("corruption: blob", e.to_string());
+  }
+}
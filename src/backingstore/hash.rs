@@ -0,0 +1,256 @@
+extern crate blake2;
+extern crate blake3;
+extern crate hex;
+extern crate serde;
+
+use self::blake2::Blake2b;
+use self::blake2::digest::{Input, VariableOutput};
+use self::serde::{Serialize, Serializer, Deserialize, Deserializer};
+use self::serde::de::Error as DeError;
+use crate::settings::*;
+use std::fmt;
+
+// The algorithms blobs can be hashed with. The ids are what gets stored so they can
+// never be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+  Blake2b,
+  Blake3,
+}
+
+impl HashAlgo {
+  fn id(self) -> u8 {
+    match self {
+      HashAlgo::Blake2b => 1,
+      HashAlgo::Blake3 => 2,
+    }
+  }
+
+  fn from_id(id: u8) -> Option<Self> {
+    match id {
+      1 => Some(HashAlgo::Blake2b),
+      2 => Some(HashAlgo::Blake3),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      HashAlgo::Blake2b => "blake2b",
+      HashAlgo::Blake3 => "blake3",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "blake2b" => Some(HashAlgo::Blake2b),
+      "blake3" => Some(HashAlgo::Blake3),
+      _ => None,
+    }
+  }
+}
+
+// How new blobs get hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashKind {
+  pub algo: HashAlgo,
+  pub len: usize,
+}
+
+impl Default for HashKind {
+  fn default() -> Self {
+    Self {
+      algo: HashAlgo::Blake2b,
+      len: HASHSIZE,
+    }
+  }
+}
+
+impl HashKind {
+  pub fn valid(&self) -> bool {
+    self.len >= MINHASHSIZE && self.len <= MAXHASHSIZE
+  }
+}
+
+// A blob's hash along with the algorithm and length that made it so hashes of
+// different kinds can be mixed in the same repository. The unused part of the digest
+// is always zeroed so the derived comparisons work.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlobHash {
+  algo: HashAlgo,
+  len: u8,
+  digest: [u8; MAXHASHSIZE],
+}
+
+// Repositories from before hashes carried their algorithm only had these
+pub type LegacyHash = [u8; HASHSIZE];
+
+// A zeroed out hash. In a file's blocks it marks a hole that reads as zeros and isn't
+// stored anywhere.
+pub const HASHZERO: BlobHash = BlobHash {
+  algo: HashAlgo::Blake2b,
+  len: HASHSIZE as u8,
+  digest: [0; MAXHASHSIZE],
+};
+
+impl BlobHash {
+  fn from_digest(algo: HashAlgo, bytes: &[u8]) -> Option<Self> {
+    if bytes.len() < MINHASHSIZE || bytes.len() > MAXHASHSIZE {
+      return None
+    }
+    let mut digest = [0; MAXHASHSIZE];
+    digest[..bytes.len()].copy_from_slice(bytes);
+    Some(Self {
+      algo,
+      len: bytes.len() as u8,
+      digest,
+    })
+  }
+
+  pub fn compute(kind: HashKind, data: &[u8]) -> Self {
+    let mut digest = [0; MAXHASHSIZE];
+    match kind.algo {
+      HashAlgo::Blake2b => {
+        let mut hasher = Blake2b::new(kind.len).unwrap();
+        hasher.process(data);
+        hasher.variable_result(&mut digest[..kind.len]).unwrap();
+      },
+      HashAlgo::Blake3 => {
+        // Shorter BLAKE3 hashes are just the start of the full one
+        let full = blake3::hash(data);
+        digest[..kind.len].copy_from_slice(&full.as_bytes()[..kind.len]);
+      },
+    }
+    Self {
+      algo: kind.algo,
+      len: kind.len as u8,
+      digest,
+    }
+  }
+
+  pub fn kind(&self) -> HashKind {
+    HashKind {
+      algo: self.algo,
+      len: self.len as usize,
+    }
+  }
+
+  pub fn digest(&self) -> &[u8] {
+    &self.digest[..self.len as usize]
+  }
+
+  // Whether the data hashes to this with the same algorithm and length
+  pub fn matches(&self, data: &[u8]) -> bool {
+    Self::compute(self.kind(), data) == *self
+  }
+
+  fn is_legacy(&self) -> bool {
+    self.kind() == HashKind::default()
+  }
+
+  // The name blobs are stored under locally and on the server. Hashes of the original
+  // kind keep the plain hex names so existing blobs don't need to move.
+  pub fn parse(name: &str) -> Option<Self> {
+    let mut parts = name.splitn(2, '-');
+    let first = parts.next()?;
+    match parts.next() {
+      None => {
+        let bytes = hex::decode(first).ok()?;
+        if bytes.len() != HASHSIZE { return None }
+        Self::from_digest(HashAlgo::Blake2b, &bytes)
+      },
+      Some(digest) => {
+        let algo = HashAlgo::from_name(first)?;
+        Self::from_digest(algo, &hex::decode(digest).ok()?)
+      },
+    }
+  }
+}
+
+impl From<LegacyHash> for BlobHash {
+  fn from(hash: LegacyHash) -> Self {
+    Self::from_digest(HashAlgo::Blake2b, &hash).unwrap()
+  }
+}
+
+impl fmt::Display for BlobHash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.is_legacy() {
+      write!(f, "{}", hex::encode(self.digest()))
+    } else {
+      write!(f, "{}-{}", self.algo.name(), hex::encode(self.digest()))
+    }
+  }
+}
+
+impl fmt::Debug for BlobHash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+impl Serialize for BlobHash {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    (self.algo.id(), self.digest()).serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for BlobHash {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let (id, digest): (u8, Vec<u8>) = Deserialize::deserialize(deserializer)?;
+    let algo = match HashAlgo::from_id(id) {
+      Some(a) => a,
+      None => return Err(D::Error::custom(format!("unknown hash algorithm {}", id))),
+    };
+    match Self::from_digest(algo, &digest) {
+      Some(hash) => Ok(hash),
+      None => Err(D::Error::custom(format!("invalid hash length {}", digest.len()))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate bincode;
+  use super::*;
+
+  #[test]
+  fn names_roundtrip() {
+    let legacy = BlobHash::compute(HashKind::default(), b"data");
+    assert_eq!(2 * HASHSIZE, legacy.to_string().len());
+    assert_eq!(Some(legacy), BlobHash::parse(&legacy.to_string()));
+    let blake3 = BlobHash::compute(HashKind { algo: HashAlgo::Blake3, len: 32 }, b"data");
+    assert!(blake3.to_string().starts_with("blake3-"));
+    assert_eq!(Some(blake3), BlobHash::parse(&blake3.to_string()));
+    assert!(blake3 != legacy);
+    assert_eq!(None, BlobHash::parse("00"));
+    assert_eq!(None, BlobHash::parse("sha1-00112233445566778899aabbccddeeff00112233"));
+    assert_eq!(None, BlobHash::parse("blake3-00"));
+  }
+
+  #[test]
+  fn kinds() {
+    let long = BlobHash::compute(HashKind { algo: HashAlgo::Blake2b, len: 32 }, b"data");
+    let short = BlobHash::compute(HashKind { algo: HashAlgo::Blake2b, len: 20 }, b"data");
+    assert_eq!(32, long.digest().len());
+    assert!(long.matches(b"data"));
+    assert!(!long.matches(b"other"));
+    assert!(short.matches(b"data"));
+    assert!(short != long);
+    let blake3 = BlobHash::compute(HashKind { algo: HashAlgo::Blake3, len: 20 }, b"data");
+    assert_eq!(&blake3::hash(b"data").as_bytes()[..20], blake3.digest());
+  }
+
+  #[test]
+  fn serialization_roundtrips() {
+    let hash = BlobHash::compute(HashKind { algo: HashAlgo::Blake3, len: 32 }, b"data");
+    let encoded = bincode::serialize(&hash).unwrap();
+    assert_eq!(hash, bincode::deserialize::<BlobHash>(&encoded).unwrap());
+    let mut bad = encoded.clone();
+    bad[0] = 9;
+    assert!(bincode::deserialize::<BlobHash>(&bad).is_err());
+    let legacy: BlobHash = [1; HASHSIZE].into();
+    assert_eq!(hex::encode([1; HASHSIZE]), legacy.to_string());
+  }
+}
@@ -0,0 +1,207 @@
+extern crate bincode;
+
+use super::{NodeId, BlobHash};
+use crate::filesystem::FSEntry;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, Error, SeekFrom};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JournalRecord {
+  // Data written to a block that was at the given hash when the write started
+  Write {
+    node: NodeId,
+    block: usize,
+    hash: BlobHash,
+    offset: usize,
+    data: Vec<u8>,
+  },
+  // The latest cached version of a node
+  Node {
+    node: NodeId,
+    entry: Box<FSEntry>,
+  },
+  // A block cut short by a truncate that was at the given hash when it started
+  Truncate {
+    node: NodeId,
+    block: usize,
+    hash: BlobHash,
+    len: usize,
+  },
+}
+
+struct JournalState {
+  file: Option<File>,
+  size: u64,
+  unsynced: bool,
+}
+
+// Writes only go to the caches until the file is closed or the next periodic sync so
+// they're appended here as well to be replayed after a crash. Once everything in the
+// caches has been saved to disk the journal starts over.
+pub struct Journal {
+  path: PathBuf,
+  state: Mutex<JournalState>,
+}
+
+impl Journal {
+  pub fn new(source: &Path) -> Self {
+    let mut path = PathBuf::from(source);
+    path.push("journal");
+    Self {
+      path,
+      state: Mutex::new(JournalState {
+        file: None,
+        size: 0,
+        unsynced: false,
+      }),
+    }
+  }
+
+  // Read back whatever was left from the last run and start journaling. A record that
+  // was only partly written when we crashed is dropped along with anything after it.
+  pub fn start(&self) -> Result<Vec<JournalRecord>, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)?;
+    let mut records = Vec::new();
+    let total = file.metadata()?.len();
+    let mut good = 0;
+    {
+      let mut reader = BufReader::new(&mut file);
+      let mut len = [0u8; 8];
+      while reader.read_exact(&mut len).is_ok() {
+        let len = u64::from_le_bytes(len);
+        if len > total - good - 8 { break }
+        let mut buffer = vec![0; len as usize];
+        if reader.read_exact(&mut buffer).is_err() { break }
+        match bincode::deserialize(&buffer) {
+          Ok(record) => records.push(record),
+          Err(_) => break,
+        }
+        good += 8 + buffer.len() as u64;
+      }
+    }
+    if good < total {
+      eprintln!("WARNING: dropping a partly written record at the end of the journal");
+      file.set_len(good)?;
+    }
+    file.seek(SeekFrom::Start(good))?;
+
+    let mut state = self.state.lock().unwrap();
+    state.file = Some(file);
+    state.size = good;
+    Ok(records)
+  }
+
+  pub fn append(&self, record: &JournalRecord) {
+    let mut state = self.state.lock().unwrap();
+    let encoded = bincode::serialize(record).unwrap();
+    let res = match state.file {
+      None => return,
+      Some(ref mut file) => {
+        file.write_all(&(encoded.len() as u64).to_le_bytes())
+          .and_then(|_| file.write_all(&encoded))
+      },
+    };
+    match res {
+      Ok(_) => {
+        state.size += 8 + encoded.len() as u64;
+        state.unsynced = true;
+      },
+      Err(e) => eprintln!("WARNING: couldn't write to the journal: {}", e),
+    }
+  }
+
+  // Get what's been appended so far onto the disk
+  pub fn sync(&self) -> Result<(), Error> {
+    let mut state = self.state.lock().unwrap();
+    if !state.unsynced { return Ok(()) }
+    if let Some(ref file) = state.file {
+      file.sync_data()?;
+    }
+    state.unsynced = false;
+    Ok(())
+  }
+
+  // Start over once clean() says nothing is left in the caches. Records are always
+  // appended after the change is in the caches so anything already in the journal is
+  // either still there or has been saved. sync() gets what was saved onto the disk
+  // before the records that would otherwise bring it back go away.
+  pub fn reset<F, S>(&self, clean: F, sync: S) -> Result<(), Error>
+    where F: Fn() -> bool, S: Fn() -> Result<(), Error> {
+    let mut state = self.state.lock().unwrap();
+    if state.size == 0 || !clean() { return Ok(()) }
+    sync()?;
+    match state.file {
+      None => return Ok(()),
+      Some(ref mut file) => {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.sync_all()?;
+      },
+    }
+    state.size = 0;
+    state.unsynced = false;
+    Ok(())
+  }
+
+  #[allow(dead_code)] pub fn is_empty(&self) -> bool {
+    self.state.lock().unwrap().size == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::settings::*;
+  use crate::filesystem::FileTypeDef;
+  use super::super::testutil::TempDir;
+  use std::fs;
+
+  #[test]
+  fn replays_and_resets() {
+    let tmp = TempDir::new("journal-replay");
+    let dir = tmp.path();
+    let journal = Journal::new(dir);
+    // Nothing gets written before it's started
+    journal.append(&JournalRecord::Node { node: (1, 1), entry: Box::new(FSEntry::new(FileTypeDef::RegularFile, 1)) });
+    assert!(journal.start().unwrap().is_empty());
+    journal.append(&JournalRecord::Write { node: (1, 1), block: 0, hash: BlobHash::from([0; HASHSIZE]), offset: 5, data: vec![1, 2, 3] });
+    journal.append(&JournalRecord::Node { node: (1, 1), entry: Box::new(FSEntry::new(FileTypeDef::RegularFile, 1)) });
+    journal.sync().unwrap();
+    drop(journal);
+
+    let journal = Journal::new(dir);
+    let records = journal.start().unwrap();
+    assert_eq!(2, records.len());
+    match records[0] {
+      JournalRecord::Write { offset, ref data, .. } => assert_eq!((5, &vec![1, 2, 3]), (offset, data)),
+      _ => panic!("expected a write"),
+    }
+    journal.reset(|| false, || Ok(())).unwrap();
+    assert!(!journal.is_empty());
+    journal.reset(|| true, || Ok(())).unwrap();
+    assert!(journal.is_empty());
+    drop(journal);
+    assert!(Journal::new(dir).start().unwrap().is_empty());
+  }
+
+  #[test]
+  fn drops_torn_records() {
+    let tmp = TempDir::new("journal-torn");
+    let dir = tmp.path();
+    let journal = Journal::new(dir);
+    journal.start().unwrap();
+    journal.append(&JournalRecord::Write { node: (1, 1), block: 0, hash: BlobHash::from([0; HASHSIZE]), offset: 0, data: vec![1] });
+    journal.append(&JournalRecord::Write { node: (1, 1), block: 0, hash: BlobHash::from([0; HASHSIZE]), offset: 1, data: vec![2] });
+    drop(journal);
+    let path = dir.join("journal");
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+    let journal = Journal::new(dir);
+    assert_eq!(1, journal.start().unwrap().len());
+    journal.reset(|| true, || Ok(())).unwrap();
+  }
+}
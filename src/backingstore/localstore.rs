@@ -0,0 +1,353 @@
+extern crate rusqlite;
+extern crate hex;
+
+use super::{BlobHash, SyncerError};
+use self::rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::fmt;
+
+// How blobs are kept on local disk. Flat is one file per blob in a single dir which
+// costs nothing extra on ext4, Fanout spreads the files over 256 subdirs for filesystems
+// and tools that get slow with huge dirs, and Sqlite keeps them all in a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalLayout {
+  Flat,
+  Fanout,
+  Sqlite,
+}
+
+impl LocalLayout {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "flat" => Some(LocalLayout::Flat),
+      "fanout" => Some(LocalLayout::Fanout),
+      "sqlite" => Some(LocalLayout::Sqlite),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for LocalLayout {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", match self {
+      LocalLayout::Flat => "flat",
+      LocalLayout::Fanout => "fanout",
+      LocalLayout::Sqlite => "sqlite",
+    })
+  }
+}
+
+pub struct LocalStore {
+  layout: LocalLayout,
+  dir: PathBuf,
+  // Blobs that need to be a file for rsync are written here when they aren't one already
+  outgoing: PathBuf,
+  db: Option<Mutex<Connection>>,
+}
+
+impl LocalStore {
+  // The layout is fixed once the local dir has blobs in it, switching would make them
+  // all disappear
+  pub fn new(source: &Path, layout: LocalLayout) -> Result<Self, SyncerError> {
+    let mut dir = PathBuf::from(source);
+    dir.push("blobs");
+    fs::create_dir_all(&dir)?;
+    let mut outgoing = PathBuf::from(source);
+    outgoing.push("outgoing");
+    fs::create_dir_all(&outgoing)?;
+
+    let mut marker = PathBuf::from(source);
+    marker.push("localstore");
+    let existing = match fs::read_to_string(&marker) {
+      Ok(existing) => existing.trim().to_string(),
+      Err(ref e) if e.kind() == ErrorKind::NotFound => {
+        // Local dirs from before the layout could be picked are flat
+        let existing = if fs::read_dir(&dir)?.next().is_some() {
+          LocalLayout::Flat
+        } else {
+          layout
+        };
+        fs::write(&marker, format!("{}\n", existing))?;
+        existing.to_string()
+      },
+      Err(e) => return Err(e.into()),
+    };
+    if existing != layout.to_string() {
+      return Err(SyncerError::Invalid(format!(
+        "local store is {} but the config asks for {}", existing, layout)))
+    }
+
+    let db = if layout == LocalLayout::Sqlite {
+      let mut file = PathBuf::from(source);
+      file.push("blobs.sqlite3");
+      let connection = Connection::open(&file)?;
+      // Space from evicted blobs only goes back to the filesystem with auto_vacuum which
+      // has to be set before the table is created
+      connection.execute("PRAGMA auto_vacuum=INCREMENTAL", &[]).ok();
+      connection.execute("PRAGMA journal_mode=WAL", &[]).ok();
+      // Callers expect a blob to be durable once it's been synced
+      connection.execute("PRAGMA synchronous=FULL", &[]).ok();
+      connection.execute("CREATE TABLE IF NOT EXISTS blobs (
+        hash            TEXT PRIMARY KEY ON CONFLICT IGNORE,
+        data            BLOB NOT NULL
+      )", &[])?;
+      Some(Mutex::new(connection))
+    } else {
+      None
+    };
+
+    Ok(Self {
+      layout,
+      dir,
+      outgoing,
+      db,
+    })
+  }
+
+  fn path(&self, hash: &BlobHash) -> PathBuf {
+    let mut path = self.dir.clone();
+    if self.layout == LocalLayout::Fanout {
+      path.push(hex::encode(&hash.digest()[..1]));
+    }
+    path.push(hash.to_string());
+    path
+  }
+
+  fn db(&self) -> &Mutex<Connection> {
+    self.db.as_ref().unwrap()
+  }
+
+  pub fn contains(&self, hash: &BlobHash) -> bool {
+    self.size(hash).is_some()
+  }
+
+  pub fn size(&self, hash: &BlobHash) -> Option<u64> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let conn = self.db().lock().unwrap();
+        let size: Result<i64, _> = conn.query_row(
+          "SELECT length(data) FROM blobs WHERE hash = ?1", &[&(hash.to_string())], |row| row.get(0));
+        size.ok().map(|s| s as u64)
+      },
+      _ => fs::metadata(self.path(hash)).ok().map(|m| m.len()),
+    }
+  }
+
+  pub fn read(&self, hash: &BlobHash) -> Result<Vec<u8>, SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let conn = self.db().lock().unwrap();
+        Ok(conn.query_row("SELECT data FROM blobs WHERE hash = ?1", &[&(hash.to_string())], |row| row.get(0))?)
+      },
+      _ => {
+        let mut buffer = Vec::new();
+        fs::File::open(self.path(hash))?.read_to_end(&mut buffer)?;
+        Ok(buffer)
+      },
+    }
+  }
+
+  // Blobs are named by their contents so one that's already there is left alone
+  pub fn write(&self, hash: &BlobHash, data: &[u8]) -> Result<(), SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let conn = self.db().lock().unwrap();
+        conn.execute("INSERT INTO blobs (hash, data) VALUES (?1, ?2)", &[&(hash.to_string()), &data])?;
+      },
+      _ => {
+        let path = self.path(hash);
+        if !path.exists() {
+          if self.layout == LocalLayout::Fanout {
+            fs::create_dir_all(path.parent().unwrap())?;
+          }
+          fs::File::create(&path)?.write_all(data)?;
+        }
+      },
+    }
+    Ok(())
+  }
+
+  // Move a file that was checked against its hash into the store
+  pub fn insert_file(&self, hash: &BlobHash, file: &Path) -> Result<(), SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        self.write(hash, &fs::read(file)?)?;
+        fs::remove_file(file)?;
+      },
+      _ => {
+        let path = self.path(hash);
+        if self.layout == LocalLayout::Fanout {
+          fs::create_dir_all(path.parent().unwrap())?;
+        }
+        fs::rename(file, path)?;
+      },
+    }
+    Ok(())
+  }
+
+  pub fn remove(&self, hash: &BlobHash) -> Result<(), SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let conn = self.db().lock().unwrap();
+        conn.execute("DELETE FROM blobs WHERE hash = ?1", &[&(hash.to_string())])?;
+      },
+      _ => fs::remove_file(self.path(hash))?,
+    }
+    Ok(())
+  }
+
+  // Move a blob out of the store into a file of its own
+  pub fn take(&self, hash: &BlobHash, dest: &Path) -> Result<(), SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        fs::write(dest, self.read(hash)?)?;
+        self.remove(hash)
+      },
+      _ => Ok(fs::rename(self.path(hash), dest)?),
+    }
+  }
+
+  // Give back the space of removed blobs
+  pub fn reclaim(&self) {
+    if self.layout == LocalLayout::Sqlite {
+      let conn = self.db().lock().unwrap();
+      conn.execute("PRAGMA incremental_vacuum", &[]).ok();
+    }
+  }
+
+  pub fn sync(&self, hash: &BlobHash) -> Result<(), SyncerError> {
+    if self.layout != LocalLayout::Sqlite {
+      fs::File::open(self.path(hash))?.sync_all()?;
+    }
+    Ok(())
+  }
+
+  // A file with the blob's contents named by its hash, for rsync. Returns whether it's
+  // a copy that needs to be removed with done_with_file() after use.
+  pub fn file(&self, hash: &BlobHash) -> Result<(PathBuf, bool), SyncerError> {
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let path = self.outgoing.join(hash.to_string());
+        fs::write(&path, self.read(hash)?)?;
+        Ok((path, true))
+      },
+      _ => {
+        let path = self.path(hash);
+        if !path.exists() {
+          return Err(SyncerError::NotFound(format!("blob {}", hash)))
+        }
+        Ok((path, false))
+      },
+    }
+  }
+
+  pub fn done_with_file(&self, path: &Path, copy: bool) {
+    if copy {
+      fs::remove_file(path).ok();
+    }
+  }
+
+  // Every blob in the store along with its size
+  pub fn blobs(&self) -> Result<Vec<(BlobHash, u64)>, SyncerError> {
+    let mut vals = Vec::new();
+    match self.layout {
+      LocalLayout::Sqlite => {
+        let conn = self.db().lock().unwrap();
+        let mut stmt = conn.prepare("SELECT hash, length(data) FROM blobs ORDER BY rowid")?;
+        let iter = stmt.query_map(&[], |row| -> (String, i64) { (row.get(0), row.get(1)) })?;
+        for val in iter {
+          let (name, size) = val?;
+          if let Some(hash) = BlobHash::parse(&name) {
+            vals.push((hash, size as u64));
+          }
+        }
+      },
+      LocalLayout::Flat => Self::list_dir(&self.dir, &mut vals)?,
+      LocalLayout::Fanout => {
+        for file in fs::read_dir(&self.dir)? {
+          let path = file?.path();
+          if path.is_dir() {
+            Self::list_dir(&path, &mut vals)?;
+          }
+        }
+      },
+    }
+    Ok(vals)
+  }
+
+  fn list_dir(dir: &Path, vals: &mut Vec<(BlobHash, u64)>) -> Result<(), SyncerError> {
+    for file in fs::read_dir(dir)? {
+      let file = file?;
+      if let Some(hash) = file.file_name().to_str().and_then(BlobHash::parse) {
+        vals.push((hash, file.metadata()?.len()));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::HashKind;
+  use super::super::testutil::TempDir;
+
+  #[test]
+  fn layouts() {
+    for layout in &[LocalLayout::Flat, LocalLayout::Fanout, LocalLayout::Sqlite] {
+      let tmp = TempDir::new(&format!("localstore-{}", layout));
+      let dir = tmp.path();
+      let store = LocalStore::new(dir, *layout).unwrap();
+      let hash = BlobHash::compute(HashKind::default(), b"data");
+      assert!(!store.contains(&hash));
+      store.write(&hash, b"data").unwrap();
+      store.write(&hash, b"data").unwrap();
+      assert_eq!(Some(4), store.size(&hash));
+      assert_eq!(b"data".to_vec(), store.read(&hash).unwrap());
+      assert_eq!(vec![(hash, 4)], store.blobs().unwrap());
+
+      let (file, copy) = store.file(&hash).unwrap();
+      assert_eq!(b"data".to_vec(), fs::read(&file).unwrap());
+      store.done_with_file(&file, copy);
+      assert!(store.contains(&hash));
+
+      let taken = dir.join("taken");
+      store.take(&hash, &taken).unwrap();
+      assert!(!store.contains(&hash));
+      store.insert_file(&hash, &taken).unwrap();
+      assert!(!taken.exists());
+      assert!(store.contains(&hash));
+      store.remove(&hash).unwrap();
+      store.reclaim();
+      assert_eq!(0, store.blobs().unwrap().len());
+
+      // The layout sticks with the local dir
+      let other = if *layout == LocalLayout::Flat { LocalLayout::Sqlite } else { LocalLayout::Flat };
+      assert!(LocalStore::new(dir, other).is_err());
+    }
+  }
+
+  #[test]
+  fn unmarked_dirs() {
+    let tmp = TempDir::new("localstore-unmarked");
+    let dir = tmp.path();
+    let hash = BlobHash::compute(HashKind::default(), b"data");
+    fs::create_dir_all(dir.join("blobs")).unwrap();
+    fs::write(dir.join("blobs").join(hash.to_string()), b"data").unwrap();
+    // Blobs from before the marker are flat whatever the config says
+    assert!(LocalStore::new(dir, LocalLayout::Sqlite).is_err());
+    assert_eq!("flat\n", fs::read_to_string(dir.join("localstore")).unwrap());
+    let store = LocalStore::new(dir, LocalLayout::Flat).unwrap();
+    assert!(store.contains(&hash));
+
+    // An empty dir takes whatever the config asks for
+    let tmp = TempDir::new("localstore-empty");
+    assert!(LocalStore::new(tmp.path(), LocalLayout::Sqlite).is_ok());
+    assert_eq!("sqlite\n", fs::read_to_string(tmp.path().join("localstore")).unwrap());
+  }
+}
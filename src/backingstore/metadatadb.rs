@@ -1,13 +1,11 @@
 extern crate rusqlite;
-extern crate libc;
 extern crate hex;
 extern crate time;
 
-use super::blobstorage::*;
-use super::{NodeInfo, NodeId};
+use super::{NodeInfo, NodeId, ConflictInfo, ConflictKind, PeerInfo, QuarantineInfo, SyncerError, BlobHash};
+use super::packs::PackEntry;
 use crate::settings::*;
 use self::rusqlite::Connection;
-use self::libc::c_int;
 use std::sync::Mutex;
 
 pub fn timeval() -> i64 {
@@ -19,6 +17,12 @@ pub struct MetadataDB {
   connection: Mutex<Connection>,
 }
 
+// Kept in PRAGMA user_version and bumped with every change to the tables below
+const SCHEMA_VERSION: i64 = 1;
+
+// A blobs table row as (hash, present, size)
+pub type BlobRow = (BlobHash, bool, u64);
+
 fn dberror_print(error: self::rusqlite::Error) {
   eprintln!("WARNING: db error: \"{}\"", error);
 }
@@ -36,23 +40,33 @@ macro_rules! dberror_return {
   ( $e:expr ) => {
     match $e {
       Ok(vals) => vals,
-      Err(e) => {dberror_print(e); return Err(libc::EIO)},
+      Err(e) => return Err(SyncerError::from(e)),
     }
   }
 }
 
 impl MetadataDB {
-  fn hash_from_string(hash: String) -> BlobHash {
-    assert!(hash.len() == HASHSIZE*2);
-    let mut hasharray = [0; HASHSIZE];
-    let vals = hex::decode(hash).unwrap();
-    for i in 0..HASHSIZE {
-      hasharray[i] = vals[i];
+  fn hash_from_string(hash: String) -> Result<BlobHash, SyncerError> {
+    match BlobHash::parse(&hash) {
+      Some(h) => Ok(h),
+      None => Err(SyncerError::Corruption(format!("invalid hash {:?} in the database", hash))),
+    }
+  }
+
+  // For the background jobs a bad row is reported and left alone so the rest still go
+  fn skip_bad_rows<I, T>(iter: I) -> Vec<T>
+    where I: Iterator<Item = Result<Result<T, SyncerError>, rusqlite::Error>> {
+    let mut vals = Vec::new();
+    for val in iter {
+      match val.map_err(SyncerError::from).and_then(|v| v) {
+        Ok(v) => vals.push(v),
+        Err(e) => eprintln!("WARNING: skipping bad row: {}", e),
+      }
     }
-    hasharray
+    vals
   }
 
-  pub fn new(connection: Connection) -> Self {
+  pub fn new(mut connection: Connection) -> Result<Self, SyncerError> {
     // Make the database faster at the cost of losing data but without causing corruption
     // https://www.sqlite.org/pragma.html#pragma_synchronous
     // If durability is not a concern, then synchronous=NORMAL is normally all one needs
@@ -60,43 +74,112 @@ impl MetadataDB {
     connection.execute("PRAGMA journal_mode=WAL", &[]).ok();
     connection.execute("PRAGMA synchronous=NORMAL", &[]).ok();
 
-    connection.execute("CREATE TABLE IF NOT EXISTS nodes (
+    let version: i64 = connection.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+      return Err(SyncerError::Invalid(format!(
+        "metadata database is at schema {} but this syncer only knows up to {}", version, SCHEMA_VERSION)))
+    }
+    let tran = connection.transaction()?;
+
+    tran.execute("CREATE TABLE IF NOT EXISTS nodes (
       peernum         INTEGER NOT NULL,
       id              INTEGER NOT NULL,
       hash            TEXT NOT NULL,
       creation        INTEGER NOT NULL,
       synced          INTEGER NOT NULL,
       UNIQUE (peernum, id, hash, creation) ON CONFLICT IGNORE
-    )", &[]).unwrap();
+    )", &[])?;
 
-    connection.execute("CREATE TABLE IF NOT EXISTS blobs (
+    tran.execute("CREATE TABLE IF NOT EXISTS blobs (
       hash            TEXT PRIMARY KEY,
       synced          INTEGER NOT NULL,
       present         INTEGER NOT NULL,
       size            INTEGER NOT NULL,
       last_use        INTEGER NOT NULL
-    )", &[]).unwrap();
+    )", &[])?;
 
-    connection.execute("CREATE TABLE IF NOT EXISTS peers (
+    tran.execute("CREATE TABLE IF NOT EXISTS peers (
       id              INTEGER PRIMARY KEY,
-      offset          INTEGER NOT NULL
-    )", &[]).unwrap();
+      segment         INTEGER NOT NULL DEFAULT 0,
+      offset          INTEGER NOT NULL,
+      label           TEXT,
+      lastseen        INTEGER NOT NULL DEFAULT 0,
+      retired         INTEGER NOT NULL DEFAULT 0,
+      pubkey          TEXT,
+      rejected        INTEGER NOT NULL DEFAULT 0
+    )", &[])?;
+    if version < 1 {
+      // Databases from before the schema was versioned got these columns added one at
+      // a time so any of them may already be there. Everything read from a log before
+      // segments was in segment 0.
+      for column in &["segment INTEGER NOT NULL DEFAULT 0", "label TEXT",
+                      "lastseen INTEGER NOT NULL DEFAULT 0", "retired INTEGER NOT NULL DEFAULT 0",
+                      "pubkey TEXT", "rejected INTEGER NOT NULL DEFAULT 0"] {
+        Self::add_column(&tran, "peers", column)?;
+      }
+    }
+
+    tran.execute("CREATE TABLE IF NOT EXISTS conflicts (
+      kind            TEXT NOT NULL,
+      peernum         INTEGER NOT NULL,
+      id              INTEGER NOT NULL,
+      base            TEXT NOT NULL,
+      local           TEXT NOT NULL,
+      remote          TEXT NOT NULL,
+      merged          TEXT NOT NULL,
+      copy_peernum    INTEGER,
+      copy_id         INTEGER,
+      creation        INTEGER NOT NULL,
+      resolved        INTEGER NOT NULL DEFAULT 0
+    )", &[])?;
 
-    connection.execute("CREATE INDEX IF NOT EXISTS node_id
-                        ON nodes (peernum, id)", &[]).unwrap();
+    tran.execute("CREATE TABLE IF NOT EXISTS quarantine (
+      kind            TEXT NOT NULL,
+      source          TEXT NOT NULL,
+      reason          TEXT NOT NULL,
+      creation        INTEGER NOT NULL,
+      UNIQUE (kind, source) ON CONFLICT IGNORE
+    )", &[])?;
+
+    // Where the blobs in the packs on the server are. The same blob can end up in more
+    // than one pack when different peers pack it.
+    tran.execute("CREATE TABLE IF NOT EXISTS packed (
+      hash            TEXT NOT NULL,
+      pack            TEXT NOT NULL,
+      offset          INTEGER NOT NULL,
+      size            INTEGER NOT NULL,
+      UNIQUE (hash, pack) ON CONFLICT IGNORE
+    )", &[])?;
 
-    connection.execute("CREATE INDEX IF NOT EXISTS blob_upload
-                        ON blobs (synced)", &[]).unwrap();
+    tran.execute("CREATE TABLE IF NOT EXISTS packs (
+      name            TEXT PRIMARY KEY ON CONFLICT IGNORE
+    )", &[])?;
 
-    connection.execute("CREATE INDEX IF NOT EXISTS blob_delete
-                        ON blobs (synced, present, last_use)", &[]).unwrap();
+    tran.execute("CREATE INDEX IF NOT EXISTS node_id
+                        ON nodes (peernum, id)", &[])?;
 
-    Self {
+    tran.execute("CREATE INDEX IF NOT EXISTS blob_upload
+                        ON blobs (synced)", &[])?;
+
+    tran.execute("CREATE INDEX IF NOT EXISTS blob_delete
+                        ON blobs (synced, present, last_use)", &[])?;
+
+    tran.execute(&format!("PRAGMA user_version = {}", SCHEMA_VERSION), &[])?;
+    tran.commit()?;
+
+    Ok(Self {
       connection: Mutex::new(connection),
+    })
+  }
+
+  fn add_column(conn: &Connection, table: &str, column: &str) -> Result<(), SyncerError> {
+    match conn.execute(&format!("ALTER TABLE {} ADD COLUMN {}", table, column), &[]) {
+      Err(rusqlite::Error::SqliteFailure(_, Some(ref msg))) if msg.starts_with("duplicate column name") => Ok(()),
+      res => { res?; Ok(()) },
     }
   }
 
-  pub fn max_node(&self, peernum: i64) -> Result<i64, c_int> {
+  pub fn max_node(&self, peernum: i64) -> Result<i64, SyncerError> {
     let conn = self.connection.lock().unwrap();
     let node: i64 = dberror_return!(conn.query_row(
       "SELECT COALESCE(MAX(id), 0) FROM nodes WHERE peernum=?1",
@@ -104,7 +187,7 @@ impl MetadataDB {
     Ok(node)
   }
 
-  pub fn node_exists(&self, node: NodeId) -> Result<bool, c_int> {
+  pub fn node_exists(&self, node: NodeId) -> Result<bool, SyncerError> {
     let conn = self.connection.lock().unwrap();
     let count: i64 = dberror_return!(conn.query_row(
       "SELECT count(*) FROM nodes WHERE peernum=?1 AND id=?2 LIMIT 1",
@@ -112,55 +195,268 @@ impl MetadataDB {
     Ok(count > 0)
   }
 
-  pub fn node_exists_long(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<bool, c_int> {
+  pub fn node_exists_long(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<bool, SyncerError> {
     let conn = self.connection.lock().unwrap();
     let count: i64 = dberror_return!(conn.query_row(
       "SELECT count(*) FROM nodes WHERE peernum=?1 AND id=?2 AND hash=?3 AND creation=?4 LIMIT 1",
-      &[&node.0, &node.1, &(hex::encode(hash)), &creation], |row| row.get(0)));
+      &[&node.0, &node.1, &(hash.to_string()), &creation], |row| row.get(0)));
     Ok(count > 0)
   }
 
-  pub fn get_node(&self, node: NodeId) -> Result<BlobHash, c_int> {
+  pub fn get_node(&self, node: NodeId) -> Result<BlobHash, SyncerError> {
     let conn = self.connection.lock().unwrap();
     let hash: String = dberror_return!(conn.query_row(
       "SELECT hash FROM nodes WHERE peernum=?1 AND id=?2 ORDER BY rowid DESC LIMIT 1",
       &[&node.0, &node.1], |row| row.get(0)));
-    Ok(Self::hash_from_string(hash))
+    Self::hash_from_string(hash)
   }
 
-  pub fn get_earlier_node(&self, node: NodeId, maxrowid: i64) -> Result<(i64, BlobHash), c_int> {
+  pub fn get_earlier_node(&self, node: NodeId, maxrowid: i64) -> Result<(i64, BlobHash), SyncerError> {
     let conn = self.connection.lock().unwrap();
     let (row, hash): (i64, String) = dberror_return!(conn.query_row(
       "SELECT rowid, hash FROM nodes WHERE peernum=?1 AND id=?2 AND rowid < ?3 ORDER BY rowid DESC LIMIT 1",
       &[&node.0, &node.1, &maxrowid], |row| (row.get(0), row.get(1))));
-    Ok((row, Self::hash_from_string(hash)))
+    Ok((row, Self::hash_from_string(hash)?))
+  }
+
+  // With synchronous=NORMAL commits only reach the disk at a WAL checkpoint
+  pub fn sync(&self) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.query_row("PRAGMA wal_checkpoint(FULL)", &[], |_| ()));
+    Ok(())
+  }
+
+  fn add_peer(conn: &Connection, id: i64) -> Result<(), SyncerError> {
+    dberror_return!(conn.execute(
+      "INSERT OR IGNORE INTO peers (id, segment, offset) VALUES (?1, 0, 0)",
+      &[&id]));
+    Ok(())
+  }
+
+  pub fn set_peer(&self, id: i64, segment: u64, offset: u64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
+    dberror_return!(conn.execute(
+      "UPDATE peers SET segment=?2, offset=?3 WHERE id=?1",
+      &[&id, &(segment as i64), &(offset as i64)]));
+    Ok(())
+  }
+
+  // Record the time of the latest change we've seen from a peer
+  pub fn peer_seen(&self, id: i64, creation: i64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
+    dberror_return!(conn.execute(
+      "UPDATE peers SET lastseen=MAX(lastseen, ?2) WHERE id=?1",
+      &[&id, &creation]));
+    Ok(())
+  }
+
+  pub fn set_peer_label(&self, id: i64, label: &str) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
+    dberror_return!(conn.execute(
+      "UPDATE peers SET label=?2 WHERE id=?1",
+      &[&id, &label]));
+    Ok(())
+  }
+
+  pub fn retire_peer(&self, id: i64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
+    dberror_return!(conn.execute(
+      "UPDATE peers SET retired=1 WHERE id=?1",
+      &[&id]));
+    Ok(())
+  }
+
+  pub fn set_peer_key(&self, id: i64, pubkey: &str) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
+    dberror_return!(conn.execute(
+      "UPDATE peers SET pubkey=?2 WHERE id=?1",
+      &[&id, &pubkey]));
+    Ok(())
+  }
+
+  // Start reading a peer's log from the beginning again
+  pub fn rewind_peer(&self, id: i64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.execute(
+      "UPDATE peers SET segment=0, offset=0, rejected=0 WHERE id=?1",
+      &[&id]));
+    Ok(())
+  }
+
+  pub fn get_peer_key(&self, id: i64) -> Result<Option<String>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare("SELECT pubkey FROM peers WHERE id=?1"));
+    let mut rows = dberror_return!(stmt.query(&[&id]));
+    match rows.next() {
+      Some(row) => Ok(dberror_return!(row).get(0)),
+      None => Ok(None),
+    }
   }
 
-  pub fn set_peer(&self, id: i64, offset: u64) -> Result<(), c_int> {
+  // Count log entries from a peer we didn't take
+  pub fn add_rejected(&self, id: i64) -> Result<(), SyncerError> {
     let conn = self.connection.lock().unwrap();
+    Self::add_peer(&conn, id)?;
     dberror_return!(conn.execute(
-      "INSERT OR REPLACE INTO peers (id, offset) VALUES (?1, ?2)",
-      &[&id, &(offset as i64)]));
+      "UPDATE peers SET rejected=rejected+1 WHERE id=?1",
+      &[&id]));
     Ok(())
   }
 
-  pub fn get_peer(&self, id: i64) -> Result<u64, c_int> {
+  pub fn get_peer_rejected(&self, id: i64) -> Result<u64, SyncerError> {
     let conn = self.connection.lock().unwrap();
-    let val: i64 = dberror_return!(conn.query_row(
-      "SELECT COALESCE(SUM(offset), 0) FROM peers WHERE id=?1",
+    let rejected: i64 = dberror_return!(conn.query_row(
+      "SELECT COALESCE(SUM(rejected), 0) FROM peers WHERE id=?1",
       &[&id], |row| row.get(0)));
-    Ok(val as u64)
+    Ok(rejected as u64)
   }
 
-  pub fn set_node(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<(), c_int> {
+  // Keep track of a record we couldn't use so it can be looked at later
+  pub fn add_quarantined(&self, kind: &str, source: &str, reason: &str) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.execute(
+      "INSERT INTO quarantine (kind, source, reason, creation) VALUES (?1, ?2, ?3, ?4)",
+      &[&kind, &source, &reason, &timeval()]));
+    Ok(())
+  }
+
+  pub fn get_quarantined(&self) -> Result<Vec<QuarantineInfo>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT kind, source, reason, creation FROM quarantine ORDER BY rowid"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| {
+      QuarantineInfo {
+        kind: row.get(0),
+        source: row.get(1),
+        reason: row.get(2),
+        creation: row.get(3),
+      }
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val));
+    }
+    Ok(vals)
+  }
+
+  pub fn retired_peers(&self) -> Result<Vec<i64>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT id FROM peers WHERE retired=1 ORDER BY id"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| row.get(0)));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val));
+    }
+    Ok(vals)
+  }
+
+  pub fn get_peer_infos(&self) -> Result<Vec<PeerInfo>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT id, label, segment, offset, lastseen, retired, pubkey, rejected FROM peers ORDER BY id"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| {
+      let segment: i64 = row.get(2);
+      let offset: i64 = row.get(3);
+      let retired: i64 = row.get(5);
+      let rejected: i64 = row.get(7);
+      PeerInfo {
+        id: row.get(0),
+        label: row.get(1),
+        segment: segment as u64,
+        offset: offset as u64,
+        lastseen: row.get(4),
+        retired: retired != 0,
+        pubkey: row.get(6),
+        rejected: rejected as u64,
+        counter: 0,
+      }
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val));
+    }
+    Ok(vals)
+  }
+
+  // Returns the log segment and offset within it we've read up to for a peer
+  pub fn get_peer(&self, id: i64) -> Result<(u64, u64), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let (segment, offset): (i64, i64) = dberror_return!(conn.query_row(
+      "SELECT COALESCE(SUM(segment), 0), COALESCE(SUM(offset), 0) FROM peers WHERE id=?1",
+      &[&id], |row| (row.get(0), row.get(1))));
+    Ok((segment as u64, offset as u64))
+  }
+
+  pub fn get_peers(&self) -> Result<Vec<(i64, u64, u64)>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT id, segment, offset FROM peers ORDER BY id"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| {
+      let segment: i64 = row.get(1);
+      let offset: i64 = row.get(2);
+      (row.get(0), segment as u64, offset as u64)
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val));
+    }
+    Ok(vals)
+  }
+
+  // The current version of every node
+  pub fn latest_nodes(&self) -> Result<Vec<NodeInfo>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT peernum, id, hash, creation FROM nodes
+       WHERE rowid IN (SELECT MAX(rowid) FROM nodes GROUP BY peernum, id)
+       ORDER BY peernum, id"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| -> Result<NodeInfo, SyncerError> {
+      Ok(NodeInfo {
+        id: (row.get(0), row.get(1)),
+        hash: Self::hash_from_string(row.get(2))?,
+        creation: row.get(3),
+      })
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val)?);
+    }
+    Ok(vals)
+  }
+
+  // Add a node that's already in a log on the server (e.g., from a checkpoint) so it
+  // doesn't need to be uploaded again
+  pub fn import_node(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.execute(
+      "INSERT INTO nodes (peernum, id, hash, creation, synced) VALUES (?1, ?2, ?3, ?4, 1)",
+      &[&node.0, &node.1, &(hash.to_string()), &creation]));
+    Ok(())
+  }
+
+  pub fn pending_node_uploads(&self) -> Result<bool, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let count: i64 = dberror_return!(conn.query_row(
+      "SELECT count(*) FROM nodes WHERE synced = 0",
+      &[], |row| row.get(0)));
+    Ok(count > 0)
+  }
+
+  pub fn set_node(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<(), SyncerError> {
     let conn = self.connection.lock().unwrap();
     dberror_return!(conn.execute(
       "INSERT INTO nodes (peernum, id, hash, creation, synced) VALUES (?1, ?2, ?3, ?4, 0)",
-      &[&node.0, &node.1, &(hex::encode(hash)), &creation]));
+      &[&node.0, &node.1, &(hash.to_string()), &creation]));
     Ok(())
   }
 
-  pub fn set_node_behind(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<(), c_int> {
+  pub fn set_node_behind(&self, node: NodeId, hash: &BlobHash, creation: i64) -> Result<(), SyncerError> {
     let mut conn = self.connection.lock().unwrap();
     let tran = conn.transaction().unwrap();
     let (rowid, oldhash, oldcreation): (i64, String, i64) = dberror_return!(tran.query_row(
@@ -171,7 +467,7 @@ impl MetadataDB {
       &[&rowid]));
     dberror_return!(tran.execute(
       "INSERT INTO nodes (peernum, id, hash, creation, synced) VALUES (?1, ?2, ?3, ?4, 0)",
-      &[&node.0, &node.1, &(hex::encode(hash)), &creation]));
+      &[&node.0, &node.1, &(hash.to_string()), &creation]));
     dberror_return!(tran.execute(
       "INSERT INTO nodes (peernum, id, hash, creation, synced) VALUES (?1, ?2, ?3, ?4, 0)",
       &[&node.0, &node.1, &oldhash, &oldcreation]));
@@ -179,6 +475,69 @@ impl MetadataDB {
     Ok(())
   }
 
+  pub fn add_conflict(&self, conflict: &ConflictInfo) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let (copy_peernum, copy_id) = match conflict.copy {
+      Some((p, i)) => (Some(p), Some(i)),
+      None => (None, None),
+    };
+    dberror_return!(conn.execute(
+      "INSERT INTO conflicts (kind, peernum, id, base, local, remote, merged, copy_peernum, copy_id, creation, resolved)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+      &[&conflict.kind.name(), &conflict.node.0, &conflict.node.1, &(conflict.base.to_string()),
+        &(conflict.local.to_string()), &(conflict.remote.to_string()), &(conflict.merged.to_string()),
+        &copy_peernum, &copy_id, &conflict.creation, &conflict.resolved]));
+    Ok(())
+  }
+
+  // Conflicts involving a node (either as the merged node or its copy), or for all nodes
+  pub fn get_conflicts(&self, node: Option<NodeId>, all: bool) -> Result<Vec<ConflictInfo>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let (peernum, id) = match node {
+      Some((p, i)) => (Some(p), Some(i)),
+      None => (None, None),
+    };
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT rowid, kind, peernum, id, base, local, remote, merged, copy_peernum, copy_id, creation, resolved
+       FROM conflicts
+       WHERE (?1 OR resolved = 0)
+         AND (?2 IS NULL OR (peernum = ?2 AND id = ?3) OR (copy_peernum = ?2 AND copy_id = ?3))
+       ORDER BY rowid"));
+    let iter = dberror_return!(stmt.query_map(&[&all, &peernum, &id], |row| -> Result<ConflictInfo, SyncerError> {
+      let kind: String = row.get(1);
+      let copy_peernum: Option<i64> = row.get(8);
+      let copy_id: Option<i64> = row.get(9);
+      Ok(ConflictInfo {
+        id: row.get(0),
+        kind: ConflictKind::from_name(&kind).unwrap_or(ConflictKind::Content),
+        node: (row.get(2), row.get(3)),
+        base: Self::hash_from_string(row.get(4))?,
+        local: Self::hash_from_string(row.get(5))?,
+        remote: Self::hash_from_string(row.get(6))?,
+        merged: Self::hash_from_string(row.get(7))?,
+        copy: match (copy_peernum, copy_id) {
+          (Some(p), Some(i)) => Some((p, i)),
+          _ => None,
+        },
+        creation: row.get(10),
+        resolved: row.get(11),
+      })
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val)?);
+    }
+    Ok(vals)
+  }
+
+  pub fn mark_resolved(&self, id: i64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.execute(
+      "UPDATE conflicts SET resolved = 1 WHERE rowid = ?1",
+      &[&id]));
+    Ok(())
+  }
+
   pub fn to_upload_nodes(&self) -> Vec<(i64, NodeInfo)> {
     let conn = self.connection.lock().unwrap();
     let mut stmt = conn.prepare(&format!(
@@ -186,19 +545,15 @@ impl MetadataDB {
        FROM nodes JOIN blobs ON nodes.hash = blobs.hash
        WHERE nodes.synced = 0 AND blobs.synced = 1
        ORDER BY nodes.rowid LIMIT {}", TO_UPLOAD_NODES)).unwrap();
-    let iter = stmt.query_map(&[], |row| {
-      (row.get(0),
+    let iter = stmt.query_map(&[], |row| -> Result<(i64, NodeInfo), SyncerError> {
+      Ok((row.get(0),
       NodeInfo {
         id: (row.get(1), row.get(2)),
-        hash: Self::hash_from_string(row.get(3)),
+        hash: Self::hash_from_string(row.get(3))?,
         creation: row.get(4),
-      })
+      }))
     }).unwrap();
-    let mut vals = Vec::new();
-    for val in iter {
-      vals.push(val.unwrap());
-    }
-    vals
+    Self::skip_bad_rows(iter)
   }
 
   pub fn mark_synced_nodes(&self, vals: &[i64]) {
@@ -212,11 +567,11 @@ impl MetadataDB {
     tran.commit().unwrap();
   }
 
-  #[allow(dead_code)] pub fn get_blob(&self, hash: &BlobHash) -> Result<(bool, u64, i64), c_int> {
+  #[allow(dead_code)] pub fn get_blob(&self, hash: &BlobHash) -> Result<(bool, u64, i64), SyncerError> {
     let conn = self.connection.lock().unwrap();
     let vals: (i64, i64, i64) = dberror_return!(conn.query_row(
       "SELECT synced, size, last_use FROM blobs WHERE hash=?1",
-      &[&(hex::encode(hash))], |row| (row.get(0), row.get(1), row.get(2))));
+      &[&(hash.to_string())], |row| (row.get(0), row.get(1), row.get(2))));
     Ok((vals.0 != 0, vals.1 as u64, vals.2))
   }
 
@@ -233,7 +588,7 @@ impl MetadataDB {
       dberror_test!(tran.execute(
         "INSERT OR REPLACE INTO blobs (hash, size, last_use, present, synced)
          VALUES (?1, ?2, ?3, 1,COALESCE((SELECT synced FROM blobs WHERE hash = ?1), 0))",
-        &[&(hex::encode(hash)), &(size as i64), &time]));
+        &[&(hash.to_string()), &(size as i64), &time]));
     }
     tran.commit().unwrap();
   }
@@ -248,7 +603,7 @@ impl MetadataDB {
          VALUES (?1, 1, ?2, ?3,
            COALESCE((SELECT synced FROM blobs WHERE hash = ?1), 0)
          );",
-         &[&(hex::encode(hash)), &time, &(size as i64)]));
+         &[&(hash.to_string()), &time, &(size as i64)]));
     }
     tran.commit().unwrap();
   }
@@ -265,7 +620,7 @@ impl MetadataDB {
     for hash in vals {
       dberror_test!(tran.execute(
         "UPDATE OR IGNORE blobs SET synced = 1 WHERE hash = ?1",
-        &[&(hex::encode(hash))]));
+        &[&(hash.to_string())]));
     }
     tran.commit().unwrap();
   }
@@ -277,23 +632,131 @@ impl MetadataDB {
     for hash in vals {
       dberror_test!(
         tran.execute("UPDATE OR IGNORE blobs SET present = ?2 WHERE hash = ?1",
-        &[&(hex::encode(hash)), &present]));
+        &[&(hash.to_string()), &present]));
     }
     tran.commit().unwrap();
   }
 
-  pub fn to_upload(&self) -> Vec<BlobHash> {
+  pub fn blob_synced(&self, hash: &BlobHash) -> Result<bool, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let count: i64 = dberror_return!(conn.query_row(
+      "SELECT count(*) FROM blobs WHERE hash = ?1 AND synced = 1",
+      &[&(hash.to_string())], |row| row.get(0)));
+    Ok(count > 0)
+  }
+
+  pub fn synced_blobs(&self) -> Result<Vec<BlobHash>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT hash FROM blobs WHERE synced = 1 ORDER BY rowid"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| Self::hash_from_string(row.get(0))));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val)?);
+    }
+    Ok(vals)
+  }
+
+  // Every blob row along with the hash column of the rows that don't have a valid hash
+  pub fn all_blobs(&self) -> Result<(Vec<BlobRow>, Vec<String>), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT hash, present, size FROM blobs ORDER BY rowid"));
+    let iter = dberror_return!(stmt.query_map(&[], |row| {
+      let present: i64 = row.get(1);
+      let size: i64 = row.get(2);
+      (row.get::<_, String>(0), present != 0, size as u64)
+    }));
+    let mut vals = Vec::new();
+    let mut bad = Vec::new();
+    for val in iter {
+      let (name, present, size) = dberror_return!(val);
+      match BlobHash::parse(&name) {
+        Some(hash) => vals.push((hash, present, size)),
+        None => bad.push(name),
+      }
+    }
+    Ok((vals, bad))
+  }
+
+  pub fn remove_blob_row(&self, name: &str) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    dberror_return!(conn.execute("DELETE FROM blobs WHERE hash = ?1", &[&name]));
+    Ok(())
+  }
+
+  // Bring a row back in line with what's actually in the blobs dir
+  pub fn fix_blob(&self, hash: &BlobHash, present: bool, size: u64) -> Result<(), SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let present: i64 = if present { 1 } else { 0 };
+    dberror_return!(conn.execute(
+      "UPDATE blobs SET present = ?2, size = ?3 WHERE hash = ?1",
+      &[&(hash.to_string()), &present, &(size as i64)]));
+    Ok(())
+  }
+
+  pub fn to_upload(&self, limit: usize) -> Vec<BlobHash> {
     let conn = self.connection.lock().unwrap();
     let mut stmt = conn.prepare(&format!(
-      "SELECT hash FROM blobs WHERE synced = 0 ORDER BY rowid LIMIT {}", TO_UPLOAD)).unwrap();
+      "SELECT hash FROM blobs WHERE synced = 0 ORDER BY rowid LIMIT {}", limit)).unwrap();
     let hash_iter = stmt.query_map(&[], |row| {
       Self::hash_from_string(row.get(0))
     }).unwrap();
-    let mut hashes = Vec::new();
-    for hash in hash_iter {
-      hashes.push(hash.unwrap());
+    Self::skip_bad_rows(hash_iter)
+  }
+
+  // Record the blobs in a pack that's on the server
+  pub fn add_pack(&self, name: &str, entries: &[PackEntry]) -> Result<(), SyncerError> {
+    let mut conn = self.connection.lock().unwrap();
+    let tran = dberror_return!(conn.transaction());
+    for entry in entries {
+      dberror_return!(tran.execute(
+        "INSERT INTO packed (hash, pack, offset, size) VALUES (?1, ?2, ?3, ?4)",
+        &[&(entry.hash.to_string()), &name, &(entry.offset as i64), &(entry.size as i64)]));
+    }
+    dberror_return!(tran.execute("INSERT INTO packs (name) VALUES (?1)", &[&name]));
+    dberror_return!(tran.commit());
+    Ok(())
+  }
+
+  pub fn has_pack(&self, name: &str) -> Result<bool, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let count: i64 = dberror_return!(conn.query_row(
+      "SELECT count(*) FROM packs WHERE name = ?1", &[&name], |row| row.get(0)));
+    Ok(count > 0)
+  }
+
+  // The packs a blob can be found in along with where it is in each of them
+  pub fn packs_with(&self, hash: &BlobHash) -> Result<Vec<(String, PackEntry)>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT pack, offset, size FROM packed WHERE hash = ?1 ORDER BY rowid"));
+    let iter = dberror_return!(stmt.query_map(&[&(hash.to_string())], |row| {
+      let offset: i64 = row.get(1);
+      let size: i64 = row.get(2);
+      (row.get(0), PackEntry { hash: *hash, offset: offset as u64, size: size as u64 })
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val));
+    }
+    Ok(vals)
+  }
+
+  pub fn pack_entries(&self, name: &str) -> Result<Vec<PackEntry>, SyncerError> {
+    let conn = self.connection.lock().unwrap();
+    let mut stmt = dberror_return!(conn.prepare(
+      "SELECT hash, offset, size FROM packed WHERE pack = ?1 ORDER BY offset"));
+    let iter = dberror_return!(stmt.query_map(&[&name], |row| -> Result<PackEntry, SyncerError> {
+      let offset: i64 = row.get(1);
+      let size: i64 = row.get(2);
+      Ok(PackEntry { hash: Self::hash_from_string(row.get(0))?, offset: offset as u64, size: size as u64 })
+    }));
+    let mut vals = Vec::new();
+    for val in iter {
+      vals.push(dberror_return!(val)?);
     }
-    hashes
+    Ok(vals)
   }
 
   pub fn to_delete(&self) -> Vec<(BlobHash, u64)> {
@@ -301,16 +764,12 @@ impl MetadataDB {
     let mut stmt = conn.prepare(&format!(
       "SELECT hash, size FROM blobs WHERE synced = 1 AND present = 1 AND size > {}
        ORDER BY last_use ASC LIMIT {}", KEEP_UP_TO_SIZE, TO_DELETE)).unwrap();
-    let hash_iter = stmt.query_map(&[], |row| {
-      let hasharray = Self::hash_from_string(row.get(0));
+    let hash_iter = stmt.query_map(&[], |row| -> Result<(BlobHash, u64), SyncerError> {
+      let hasharray = Self::hash_from_string(row.get(0))?;
       let size: i64 = row.get(1);
-      (hasharray, size as u64)
+      Ok((hasharray, size as u64))
     }).unwrap();
-    let mut vec = Vec::new();
-    for hash in hash_iter {
-      vec.push(hash.unwrap());
-    }
-    vec
+    Self::skip_bad_rows(hash_iter)
   }
 
   pub fn localbytes(&self) -> u64 {
@@ -331,9 +790,9 @@ mod tests {
   #[test]
   fn set_and_get_node() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
+    let db = MetadataDB::new(conn).unwrap();
     assert_eq!(db.node_exists((0,0)).unwrap(), false);
-    let from_hash = [0;HASHSIZE];
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     db.set_node((0,0), &from_hash, timeval()).unwrap();
     assert_eq!(db.node_exists((0,0)).unwrap(), true);
     let hash = db.get_node((0,0)).unwrap();
@@ -343,10 +802,10 @@ mod tests {
   #[test]
   fn set_and_reset_node() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [0;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     db.set_node((0,0), &from_hash, timeval()).unwrap();
-    let from_hash = [1;HASHSIZE];
+    let from_hash = BlobHash::from([1; HASHSIZE]);
     db.set_node((0,0), &from_hash, timeval()).unwrap();
     let hash = db.get_node((0,0)).unwrap();
     assert_eq!(from_hash, hash);
@@ -355,9 +814,9 @@ mod tests {
   #[test]
   fn double_set_node() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
+    let db = MetadataDB::new(conn).unwrap();
     assert_eq!(db.node_exists((0,0)).unwrap(), false);
-    let from_hash = [0;HASHSIZE];
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     db.set_blob(&from_hash, 0);
     db.mark_synced_blob(&from_hash);
     let time = timeval();
@@ -369,10 +828,10 @@ mod tests {
   #[test]
   fn get_earlier_node() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
-    let from_hash3 = [3;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
+    let from_hash3 = BlobHash::from([3; HASHSIZE]);
     let time = timeval();
     db.set_node((0,0), &from_hash1, time).unwrap();
     db.set_node((0,0), &from_hash2, time).unwrap();
@@ -390,9 +849,9 @@ mod tests {
   #[test]
   fn node_exists_long() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
     let time1 = timeval();
     let time2 = time1+1;
     assert_eq!(db.node_exists_long((0,0), &from_hash1, time1).unwrap(), false);
@@ -406,10 +865,10 @@ mod tests {
   #[test]
   fn set_node_behind() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
+    let db = MetadataDB::new(conn).unwrap();
     assert_eq!(db.node_exists((0,0)).unwrap(), false);
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
     db.set_node((0,0), &from_hash1, timeval()).unwrap();
     db.set_node_behind((0,0), &from_hash2, timeval()).unwrap();
     assert_eq!(from_hash1, db.get_node((0,0)).unwrap());
@@ -418,18 +877,30 @@ mod tests {
   #[test]
   fn maxnode() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
+    let db = MetadataDB::new(conn).unwrap();
     assert_eq!(0, db.max_node(0).unwrap());
-    let from_hash = [0;HASHSIZE];
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     db.set_node((0,5), &from_hash, timeval()).unwrap();
     assert_eq!(5, db.max_node(0).unwrap());
   }
 
+  #[test]
+  fn fix_blob_rows() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    let hash = BlobHash::from([1; HASHSIZE]);
+    db.set_blob(&hash, 10);
+    assert_eq!(vec![(hash, true, 10)], db.all_blobs().unwrap().0);
+    db.fix_blob(&hash, false, 20).unwrap();
+    assert_eq!(vec![(hash, false, 20)], db.all_blobs().unwrap().0);
+    assert_eq!(0, db.localbytes());
+  }
+
   #[test]
   fn set_and_get_blob() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [0;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     let from_size = 10;
     db.set_blob(&from_hash, from_size);
     let from_time = timeval();
@@ -449,8 +920,8 @@ mod tests {
   #[test]
   fn set_and_reset_blob() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [0;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     db.set_blob(&from_hash, 0);
     db.mark_synced_blob(&from_hash);
     let (_, _, last_used) = db.get_blob(&from_hash).unwrap();
@@ -464,25 +935,25 @@ mod tests {
   #[test]
   fn to_upload() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
-    let from_hash3 = [3;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
+    let from_hash3 = BlobHash::from([3; HASHSIZE]);
     db.set_blob(&from_hash1, 0);
     db.set_blob(&from_hash2, 0);
     db.set_blob(&from_hash3, 0);
     db.mark_synced_blob(&from_hash2);
-    let to_upload: Vec<BlobHash> = db.to_upload().into();
+    let to_upload: Vec<BlobHash> = db.to_upload(TO_UPLOAD).into();
     assert_eq!(vec![from_hash1, from_hash3], to_upload);
   }
 
   #[test]
   fn to_delete() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
-    let from_hash3 = [3;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
+    let from_hash3 = BlobHash::from([3; HASHSIZE]);
     db.set_blob(&from_hash1, 100000);
     db.set_blob(&from_hash2, 200000);
     db.set_blob(&from_hash3, 300000);
@@ -498,10 +969,10 @@ mod tests {
   #[test]
   fn localbytes() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
+    let db = MetadataDB::new(conn).unwrap();
     assert_eq!(0, db.localbytes());
-    let from_hash1 = [1;HASHSIZE];
-    let from_hash2 = [2;HASHSIZE];
+    let from_hash1 = BlobHash::from([1; HASHSIZE]);
+    let from_hash2 = BlobHash::from([2; HASHSIZE]);
     db.set_blob(&from_hash1, 10);
     db.set_blob(&from_hash2, 20);
     assert_eq!(30, db.localbytes());
@@ -514,8 +985,8 @@ mod tests {
   #[test]
   fn touch_marks_local() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [0;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     let from_size = 10;
     assert_eq!(0, db.localbytes());
     db.set_blob(&from_hash, from_size);
@@ -530,8 +1001,8 @@ mod tests {
   #[test]
   fn to_upload_nodes() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [1;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([1; HASHSIZE]);
     db.set_blob(&from_hash, 0);
     db.set_node((0,0), &from_hash, timeval()).unwrap();
 
@@ -554,24 +1025,209 @@ mod tests {
   #[test]
   fn touch_creates() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    let from_hash = [0;HASHSIZE];
+    let db = MetadataDB::new(conn).unwrap();
+    let from_hash = BlobHash::from([0; HASHSIZE]);
     assert_eq!(0, db.localbytes());
     let mut vals = vec![(from_hash, (timeval(), 10))];
     db.touch_blobs(vals.drain(..));
     assert_eq!(10, db.localbytes());
   }
 
+  #[test]
+  fn add_and_resolve_conflicts() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    let mut conflict = ConflictInfo {
+      id: 0,
+      kind: ConflictKind::Content,
+      node: (0,1),
+      base: BlobHash::from([0; HASHSIZE]),
+      local: BlobHash::from([1; HASHSIZE]),
+      remote: BlobHash::from([2; HASHSIZE]),
+      merged: BlobHash::from([3; HASHSIZE]),
+      copy: Some((0,2)),
+      creation: timeval(),
+      resolved: false,
+    };
+    assert_eq!(0, db.get_conflicts(None, true).unwrap().len());
+    db.add_conflict(&conflict).unwrap();
+    conflict.kind = ConflictKind::Merged;
+    conflict.copy = None;
+    conflict.resolved = true;
+    db.add_conflict(&conflict).unwrap();
+
+    // Clean merges only show up when asking for everything
+    let conflicts = db.get_conflicts(None, false).unwrap();
+    assert_eq!(1, conflicts.len());
+    assert_eq!(ConflictKind::Content, conflicts[0].kind);
+    assert_eq!(Some((0,2)), conflicts[0].copy);
+    assert_eq!(BlobHash::from([2; HASHSIZE]), conflicts[0].remote);
+    assert_eq!(2, db.get_conflicts(None, true).unwrap().len());
+
+    // Can be found both from the node and its copy
+    assert_eq!(1, db.get_conflicts(Some((0,1)), false).unwrap().len());
+    assert_eq!(1, db.get_conflicts(Some((0,2)), false).unwrap().len());
+    assert_eq!(0, db.get_conflicts(Some((0,3)), true).unwrap().len());
+
+    db.mark_resolved(conflicts[0].id).unwrap();
+    assert_eq!(0, db.get_conflicts(None, false).unwrap().len());
+    assert_eq!(2, db.get_conflicts(None, true).unwrap().len());
+  }
+
   #[test]
   fn set_and_get_peer() {
     let conn = Connection::open_in_memory().unwrap();
-    let db = MetadataDB::new(conn);
-    assert_eq!(0, db.get_peer(0).unwrap());
-    db.set_peer(0, 0).unwrap();
-    assert_eq!(0, db.get_peer(0).unwrap());
-    db.set_peer(1, 10).unwrap();
-    assert_eq!(10, db.get_peer(1).unwrap());
-    db.set_peer(0, 10).unwrap();
-    assert_eq!(10, db.get_peer(0).unwrap());
+    let db = MetadataDB::new(conn).unwrap();
+    assert_eq!((0, 0), db.get_peer(0).unwrap());
+    db.set_peer(0, 0, 0).unwrap();
+    assert_eq!((0, 0), db.get_peer(0).unwrap());
+    db.set_peer(1, 0, 10).unwrap();
+    assert_eq!((0, 10), db.get_peer(1).unwrap());
+    db.set_peer(0, 2, 10).unwrap();
+    assert_eq!((2, 10), db.get_peer(0).unwrap());
+    assert_eq!(vec![(0, 2, 10), (1, 0, 10)], db.get_peers().unwrap());
+  }
+
+  #[test]
+  fn label_and_retire_peers() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    db.set_peer(1, 3, 10).unwrap();
+    db.set_peer_label(1, "old laptop").unwrap();
+    db.peer_seen(1, 20).unwrap();
+    db.peer_seen(1, 10).unwrap();
+    db.retire_peer(2).unwrap();
+    // Updating the position keeps everything else
+    db.set_peer(1, 3, 20).unwrap();
+
+    let peers = db.get_peer_infos().unwrap();
+    assert_eq!(2, peers.len());
+    assert_eq!(Some("old laptop".to_string()), peers[0].label);
+    assert_eq!((3, 20), (peers[0].segment, peers[0].offset));
+    assert_eq!(20, peers[0].lastseen);
+    assert!(!peers[0].retired);
+    assert_eq!(None, peers[1].label);
+    assert!(peers[1].retired);
+    assert_eq!(vec![2], db.retired_peers().unwrap());
+  }
+
+  #[test]
+  fn peer_keys() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    assert_eq!(None, db.get_peer_key(1).unwrap());
+    db.set_peer(1, 0, 10).unwrap();
+    assert_eq!(None, db.get_peer_key(1).unwrap());
+    db.set_peer_key(1, "abcd").unwrap();
+    assert_eq!(Some("abcd".to_string()), db.get_peer_key(1).unwrap());
+    assert_eq!((0, 10), db.get_peer(1).unwrap());
+
+    assert_eq!(0, db.get_peer_rejected(2).unwrap());
+    db.add_rejected(2).unwrap();
+    db.add_rejected(2).unwrap();
+    assert_eq!(2, db.get_peer_rejected(2).unwrap());
+    assert_eq!(None, db.get_peer_key(2).unwrap());
+  }
+
+  #[test]
+  fn quarantined() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    db.add_quarantined("logline", "0000000000000001.0@10", "invalid node").unwrap();
+    db.add_quarantined("logline", "0000000000000001.0@10", "invalid node").unwrap();
+    db.add_quarantined("blob", "aabb", "hash mismatch").unwrap();
+    let vals = db.get_quarantined().unwrap();
+    assert_eq!(2, vals.len());
+    assert_eq!(("logline", "invalid node"), (&vals[0].kind[..], &vals[0].reason[..]));
+    assert_eq!("aabb", vals[1].source);
+  }
+
+  #[test]
+  fn hash_rows() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    db.set_blob(&BlobHash::from([1; HASHSIZE]), 10);
+    db.connection.lock().unwrap().execute(
+      "INSERT INTO blobs (hash, synced, present, size, last_use) VALUES ('zz', 0, 1, 5, 0)", &[]).unwrap();
+    // The bad row is skipped by the background jobs and handed to fsck to report
+    assert_eq!(vec![BlobHash::from([1; HASHSIZE])], db.to_upload(TO_UPLOAD));
+    assert_eq!(vec!["zz".to_string()], db.all_blobs().unwrap().1);
+    db.remove_blob_row("zz").unwrap();
+    assert!(db.all_blobs().unwrap().1.is_empty());
+  }
+
+  #[test]
+  fn latest_nodes() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    let time = timeval();
+    db.set_node((0,1), &BlobHash::from([1; HASHSIZE]), time).unwrap();
+    db.set_node((0,1), &BlobHash::from([2; HASHSIZE]), time).unwrap();
+    db.set_node((0,2), &BlobHash::from([3; HASHSIZE]), time).unwrap();
+    db.set_node_behind((0,2), &BlobHash::from([4; HASHSIZE]), time).unwrap();
+    let hashes: Vec<(NodeId, BlobHash)> = db.latest_nodes().unwrap().iter().map(|n| (n.id, n.hash)).collect();
+    assert_eq!(vec![((0,1), BlobHash::from([2; HASHSIZE])), ((0,2), BlobHash::from([3; HASHSIZE]))], hashes);
+  }
+
+  #[test]
+  fn imported_nodes_are_synced() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    let hash = BlobHash::from([1; HASHSIZE]);
+    db.set_blob(&hash, 0);
+    db.mark_synced_blob(&hash);
+    assert!(!db.pending_node_uploads().unwrap());
+    db.import_node((0,1), &hash, timeval()).unwrap();
+    assert!(db.node_exists((0,1)).unwrap());
+    assert_eq!(0, db.to_upload_nodes().len());
+    assert!(!db.pending_node_uploads().unwrap());
+    db.set_node((0,2), &hash, timeval()).unwrap();
+    assert!(db.pending_node_uploads().unwrap());
+  }
+
+  #[test]
+  fn packs() {
+    let conn = Connection::open_in_memory().unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    let first = BlobHash::from([1; HASHSIZE]);
+    let second = BlobHash::from([2; HASHSIZE]);
+    let entries = vec![
+      PackEntry { hash: first, offset: 0, size: 10 },
+      PackEntry { hash: second, offset: 10, size: 5 },
+    ];
+    assert!(!db.has_pack("a").unwrap());
+    db.add_pack("a", &entries).unwrap();
+    db.add_pack("a", &entries).unwrap();
+    db.add_pack("b", &entries[1..]).unwrap();
+    assert!(db.has_pack("a").unwrap());
+    assert_eq!(entries, db.pack_entries("a").unwrap());
+    assert_eq!(vec![("a".to_string(), entries[0])], db.packs_with(&first).unwrap());
+    let packs: Vec<String> = db.packs_with(&second).unwrap().into_iter().map(|p| p.0).collect();
+    assert_eq!(vec!["a".to_string(), "b".to_string()], packs);
+    assert_eq!(0, db.packs_with(&BlobHash::from([3; HASHSIZE])).unwrap().len());
+  }
+
+  #[test]
+  fn schema_versions() {
+    // One from before the schema was versioned with only some of the peers columns
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute("CREATE TABLE peers (id INTEGER PRIMARY KEY, offset INTEGER NOT NULL, label TEXT)", &[]).unwrap();
+    conn.execute("INSERT INTO peers (id, offset, label) VALUES (5, 100, 'old')", &[]).unwrap();
+    let db = MetadataDB::new(conn).unwrap();
+    assert_eq!((0, 100), db.get_peer(5).unwrap());
+    assert_eq!(0, db.get_peer_rejected(5).unwrap());
+    let conn = db.connection.into_inner().unwrap();
+    let version: i64 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0)).unwrap();
+    assert_eq!(SCHEMA_VERSION, version);
+
+    // Opening it again has nothing left to do
+    let db = MetadataDB::new(conn).unwrap();
+    let conn = db.connection.into_inner().unwrap();
+    conn.execute(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1), &[]).unwrap();
+    assert!(MetadataDB::new(conn).is_err());
+
+    // Anything but a column that's already there still fails
+    let conn = Connection::open_in_memory().unwrap();
+    assert!(MetadataDB::add_column(&conn, "missing", "label TEXT").is_err());
   }
 }
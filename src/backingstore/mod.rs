@@ -1,21 +1,36 @@
 extern crate bincode;
 extern crate libc;
 extern crate crossbeam_utils;
+extern crate hex;
 
 mod blobstorage;
 mod metadatadb;
 mod rsync;
+mod notify;
+mod signing;
+mod journal;
+mod error;
+mod hash;
+mod versions;
+mod packs;
+mod localstore;
+#[cfg(test)]
+pub mod testutil;
 
 use self::blobstorage::*;
-pub use self::blobstorage::BlobHash;
-use super::filesystem::FSEntry;
+use self::journal::*;
+pub use self::blobstorage::{log_segments, fetch_repository, publish_repository, fetch_format, publish_format};
+pub use self::hash::*;
+pub use self::localstore::{LocalStore, LocalLayout};
+pub use self::versions::{decode_exact, decode_node_info};
+pub use self::error::SyncerError;
+use super::filesystem::{FSEntry, VectorClock, VectorOrdering};
 use crate::rwhashes::*;
 use crate::config::*;
 
-use self::libc::c_int;
-use std::sync::Mutex;
 use std::path::Path;
-use std::io::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::sync::RwLockWriteGuard;
 
 pub type NodeId = (i64, i64);
 
@@ -26,60 +41,193 @@ pub struct NodeInfo {
   pub creation: i64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckpointNode {
+  pub id: NodeId,
+  pub hash: BlobHash,
+  pub creation: i64,
+  pub vclock: VectorClock,
+}
+
+// Snapshot of the current version of every node and how far into each peer's log it
+// goes so new peers don't need to replay all the logs from the start
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+  pub peernum: i64,
+  pub creation: i64,
+  pub logs: Vec<(i64, u64, u64)>,
+  pub nodes: Vec<CheckpointNode>,
+}
+
+// What a scrub of the blobs found
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+  pub checked: u64,
+  // Bad local copies that can be fetched again
+  pub corrupted: Vec<BlobHash>,
+  // Bad local copies that never made it to the server
+  pub lost: Vec<BlobHash>,
+  pub remote_checked: u64,
+  pub remote_missing: Vec<BlobHash>,
+  pub remote_corrupted: Vec<BlobHash>,
+  // Bad or missing on the server and uploaded again from here
+  pub repaired: Vec<BlobHash>,
+}
+
+// A record that couldn't be used and was set aside
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantineInfo {
+  pub kind: String,
+  pub source: String,
+  pub reason: String,
+  pub creation: i64,
+}
+
+// What fsck found, with whether each problem was fixed
+#[derive(Debug, Default)]
+pub struct FsckReport {
+  pub nodes: u64,
+  pub blocks: u64,
+  pub blobs: u64,
+  pub loglines: u64,
+  pub issues: Vec<(String, bool)>,
+}
+
+impl FsckReport {
+  pub fn issue(&mut self, message: String, fixed: bool) {
+    self.issues.push((message, fixed));
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+  pub id: i64,
+  pub label: Option<String>,
+  // How far into the peer's log we've read
+  pub segment: u64,
+  pub offset: u64,
+  pub lastseen: i64,
+  pub retired: bool,
+  // The key its log entries need to be signed with
+  pub pubkey: Option<String>,
+  // How many of its log entries we didn't take
+  pub rejected: u64,
+  // The peer's entry in the vector clocks of the current nodes
+  pub counter: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+  // Concurrent changes that merged cleanly
+  Merged,
+  // Both sides changed the contents so the losing side was kept as a copy
+  Content,
+  // Same vector clock but different contents so the incoming version won
+  Equal,
+}
+
+impl ConflictKind {
+  pub fn name(self) -> &'static str {
+    match self {
+      ConflictKind::Merged => "merged",
+      ConflictKind::Content => "content",
+      ConflictKind::Equal => "equal",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "merged" => Some(ConflictKind::Merged),
+      "content" => Some(ConflictKind::Content),
+      "equal" => Some(ConflictKind::Equal),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictInfo {
+  pub id: i64,
+  pub kind: ConflictKind,
+  pub node: NodeId,
+  pub base: BlobHash,
+  pub local: BlobHash,
+  pub remote: BlobHash,
+  pub merged: BlobHash,
+  pub copy: Option<NodeId>,
+  pub creation: i64,
+  pub resolved: bool,
+}
+
+// Block contents rebuilt from the journal along with the hash they were last based on
+type ReplayBlocks = HashMap<(NodeId, usize), (BlobHash, Vec<u8>)>;
+
 pub struct BackingStore {
-  peernum: i64,
   blobs: BlobStorage,
-  node_counter: Mutex<i64>,
   node_cache: RwHashes<NodeId, FSEntry>,
-  zero: BlobHash,
+  // Held while a node is read, changed and put back so blocks saved in between by
+  // throttle_writes or a sync don't get overwritten with the hashes from before
+  node_locks: RwHashes<NodeId, ()>,
+  journal: Journal,
 }
 
 impl BackingStore {
-  pub fn new(path: &Path, config: &Config) -> Result<Self, c_int> {
-    let bs = BlobStorage::new(&config.peerid, path, &config.server, config.maxbytes)?;
-    let zero = BlobStorage::zero(1);
-    let nodecount = bs.max_node(config.peernum())? + 1;
+  pub fn new(path: &Path, config: &Config) -> Result<Self, SyncerError> {
+    let bs = BlobStorage::new(path, config)?;
 
-    let out = Self {
+    Ok(Self {
       blobs: bs,
-      peernum: config.peernum(),
-      node_counter: Mutex::new(nodecount),
       node_cache: RwHashes::new(8),
-      zero: zero,
-    };
-    out.add_blob(&[0])?;
-    Ok(out)
+      node_locks: RwHashes::new(8),
+      journal: Journal::new(path),
+    })
+  }
+
+  pub fn lock_node(&self, node: NodeId) -> RwLockWriteGuard<'_, HashMap<NodeId, ()>> {
+    self.node_locks.write(&node)
+  }
+
+  pub fn blksize(&self) -> usize {
+    self.blobs.blksize()
+  }
+
+  // A block reads as zeros without any I/O if it's a hole that hasn't been written to
+  pub fn is_hole(&self, node: NodeId, block: usize, hash: &BlobHash) -> bool {
+    *hash == HASHZERO && !self.blobs.is_cached(node, block)
   }
 
-  pub fn blob_zero(&self) -> BlobHash {
-    self.zero
+  pub fn is_cached(&self, node: NodeId, block: usize) -> bool {
+    self.blobs.is_cached(node, block)
   }
 
-  pub fn add_blob(&self, data: &[u8]) -> Result<BlobHash, c_int> {
+  pub fn drop_blocks(&self, node: NodeId, from: usize) {
+    self.blobs.drop_blocks(node, from)
+  }
+
+  pub fn add_blob(&self, data: &[u8]) -> Result<BlobHash, SyncerError> {
     self.blobs.add_blob(data)
   }
 
-  pub fn create_node(&self, entry: FSEntry) -> Result<NodeId, c_int> {
-    let node = {
-      let mut counter = self.node_counter.lock().unwrap();
-      *counter += 1;
-      (self.peernum, *counter)
-    };
+  pub fn create_node(&self, entry: FSEntry) -> Result<NodeId, SyncerError> {
+    let node = self.blobs.new_node_id();
     self.save_node(node, entry)?;
     Ok(node)
   }
 
-  pub fn save_node(&self, node: NodeId, entry: FSEntry) -> Result<(), c_int> {
+  pub fn save_node(&self, node: NodeId, entry: FSEntry) -> Result<(), SyncerError> {
     self.blobs.save_node(node, &entry)
   }
 
-  pub fn save_node_cached(&self, node: NodeId, entry: FSEntry) -> Result<(), c_int> {
-    let mut nodes = self.node_cache.write(&node);
-    nodes.insert(node, entry);
+  pub fn save_node_cached(&self, node: NodeId, entry: FSEntry) -> Result<(), SyncerError> {
+    {
+      let mut nodes = self.node_cache.write(&node);
+      nodes.insert(node, entry.clone());
+    }
+    self.journal.append(&JournalRecord::Node { node, entry: Box::new(entry) });
     Ok(())
   }
 
-  pub fn get_node(&self, node: NodeId) -> Result<FSEntry, c_int> {
+  pub fn get_node(&self, node: NodeId) -> Result<FSEntry, SyncerError> {
     let nodes = self.node_cache.read(&node);
     match nodes.get(&node) {
       Some(n) => Ok((*n).clone()),
@@ -91,12 +239,89 @@ impl BackingStore {
     }
   }
 
-  pub fn fetch_node(&self, node: NodeId) -> Result<(BlobHash, FSEntry), c_int> {
+  pub fn fetch_node(&self, node: NodeId) -> Result<(BlobHash, FSEntry), SyncerError> {
     let (hash, buffer) = self.blobs.read_node(node)?;
-    Ok((hash, bincode::deserialize(&buffer[..]).unwrap()))
+    Ok((hash, decode_entry(&hash, &buffer)?))
+  }
+
+  pub fn fetch_entry(&self, hash: &BlobHash) -> Result<FSEntry, SyncerError> {
+    let buffer = self.blobs.read_blob(hash)?;
+    decode_entry(hash, &buffer)
+  }
+
+  pub fn conflicts(&self, node: Option<NodeId>, all: bool) -> Result<Vec<ConflictInfo>, SyncerError> {
+    self.blobs.conflicts(node, all)
+  }
+
+  pub fn quarantined(&self) -> Result<Vec<QuarantineInfo>, SyncerError> {
+    self.blobs.quarantined()
+  }
+
+  pub fn mark_resolved(&self, conflict: &ConflictInfo) -> Result<(), SyncerError> {
+    self.blobs.mark_resolved(conflict)
+  }
+
+  pub fn peers(&self) -> Result<Vec<PeerInfo>, SyncerError> {
+    self.blobs.peers()
+  }
+
+  pub fn label_peer(&self, peernum: i64, label: &str) -> Result<(), SyncerError> {
+    self.blobs.label_peer(peernum, label)
+  }
+
+  pub fn verify(&self, remote: bool) -> Result<VerifyReport, SyncerError> {
+    // Make sure everything written so far is in the blobs table
+    self.sync_all()?;
+    let mut report = VerifyReport::default();
+    self.blobs.verify_local(&mut report)?;
+    if remote {
+      self.blobs.verify_remote(&mut report)?;
+    }
+    Ok(report)
+  }
+
+  // Bring the stored nodes up to the current layout, returning how many were changed
+  pub fn upgrade(&self) -> Result<usize, SyncerError> {
+    // Anything left in the journal needs to be in the nodes first
+    self.start_journal()?;
+    let count = self.blobs.upgrade_entries()?;
+    self.sync_all()?;
+    Ok(count)
   }
 
-  pub fn node_exists(&self, node: NodeId) -> Result<bool, c_int> {
+  pub fn fsck(&self, repair: bool, report: &mut FsckReport) -> Result<(), SyncerError> {
+    self.sync_all()?;
+    self.blobs.fsck_blobs(repair, report)?;
+    self.blobs.fsck_logs(report)
+  }
+
+  pub fn blob_available(&self, hash: &BlobHash) -> bool {
+    self.blobs.blob_available(hash)
+  }
+
+  pub fn latest_node_ids(&self) -> Result<Vec<NodeId>, SyncerError> {
+    self.blobs.latest_node_ids()
+  }
+
+  pub fn trust_peer(&self, peernum: i64, pubkey: &str) -> Result<(), SyncerError> {
+    self.blobs.trust_peer(peernum, pubkey)
+  }
+
+  pub fn untrusted_peers(&self) -> Result<Vec<(i64, String)>, SyncerError> {
+    self.blobs.untrusted_peers()
+  }
+
+  pub fn retire_peer(&self, peernum: i64) -> Result<(), SyncerError> {
+    // Bring in everything the peer did before we stop reading its log
+    self.blobs.do_downloads_nodes()?;
+    self.blobs.retire_peer(peernum)
+  }
+
+  pub fn create_conflict_copy(&self, node: NodeId, current: &FSEntry, loser: &FSEntry) -> Result<NodeId, SyncerError> {
+    self.blobs.create_conflict_copy(node, current, loser)
+  }
+
+  pub fn node_exists(&self, node: NodeId) -> Result<bool, SyncerError> {
     let nodes = self.node_cache.read(&node);
     Ok(match nodes.get(&node) {
       Some(_) => true,
@@ -104,15 +329,34 @@ impl BackingStore {
     })
   }
 
-  pub fn read(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, bytes: usize, readahead: &[BlobHash]) -> Result<Vec<u8>, c_int> {
+  pub fn read(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, bytes: usize, readahead: &[BlobHash]) -> Result<Vec<u8>, SyncerError> {
     self.blobs.read(node, block, hash, offset, bytes, readahead)
   }
 
-  pub fn write(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, data: &[u8], readahead: &[BlobHash]) -> Result<(), c_int> {
-    self.blobs.write(node, block, hash, offset, data, readahead)
+  pub fn write(&self, node: NodeId, block: usize, hash: &BlobHash, offset: usize, data: &[u8], readahead: &[BlobHash]) -> Result<(), SyncerError> {
+    self.blobs.write(node, block, hash, offset, data, readahead)?;
+    self.journal.append(&JournalRecord::Write {
+      node,
+      block,
+      hash: *hash,
+      offset,
+      data: data.to_vec(),
+    });
+    Ok(())
   }
 
-  fn sync_one_node(&self, node: NodeId, mut entry: FSEntry) -> Result<(), c_int> {
+  pub fn truncate_block(&self, node: NodeId, block: usize, hash: &BlobHash, len: usize) -> Result<(), SyncerError> {
+    self.blobs.truncate_block(node, block, hash, len)?;
+    self.journal.append(&JournalRecord::Truncate {
+      node,
+      block,
+      hash: *hash,
+      len,
+    });
+    Ok(())
+  }
+
+  fn sync_one_node(&self, node: NodeId, mut entry: FSEntry) -> Result<(), SyncerError> {
     for (i, hash) in self.blobs.sync_node(node)? {
       entry.set_block(i, hash);
     }
@@ -120,30 +364,156 @@ impl BackingStore {
     Ok(())
   }
 
-  pub fn sync_node(&self, node: NodeId) -> Result<(), c_int> {
+  fn sync_cached_node(&self, node: NodeId) -> Result<(), SyncerError> {
+    let _lock = self.lock_node(node);
     let mut nodes = self.node_cache.write(&node);
     if let Some(entry) = nodes.remove(&node) {
       self.sync_one_node(node, entry)?;
     }
+    Ok(())
+  }
+
+  pub fn sync_node(&self, node: NodeId) -> Result<(), SyncerError> {
+    self.sync_cached_node(node)?;
     self.blobs.do_save();
     Ok(())
   }
 
-  pub fn sync_all(&self) -> Result<(), Error> {
+  pub fn sync_all(&self) -> Result<(), SyncerError> {
     for i in 0..self.node_cache.len() {
-      let mut nodes = self.node_cache.write_pos(i);
-      for (node, entry) in nodes.drain() {
-        match self.sync_one_node(node, entry) {
-          Ok(_) => {},
-          Err(_) => return Err(Error::new(ErrorKind::Other, "sync failed")),
-        }
+      for node in self.node_cache.keys_pos(i) {
+        self.sync_cached_node(node)?;
       }
     }
     self.blobs.do_save();
+    self.journal.reset(|| self.node_cache.all_empty() && !self.blobs.has_cached_blocks(),
+                       || Ok(self.blobs.sync_saved()?))?;
+    Ok(())
+  }
+
+  // Keep the blocks that were written but not saved yet under the configured memory
+  // limit by saving the oldest ones. This runs in the writers so they get slowed down
+  // to the speed at which blocks can be saved.
+  pub fn throttle_writes(&self) -> Result<(), SyncerError> {
+    let mut tries = self.blobs.dirty_blocks();
+    while tries > 0 && self.blobs.over_dirty_limit() {
+      tries -= 1;
+      let (node, block) = match self.blobs.oldest_dirty() {
+        Some(d) => d,
+        None => break,
+      };
+      // Someone in the middle of changing the node would put back the old hash
+      let _lock = match self.node_locks.try_write(&node) {
+        Some(l) => l,
+        None => {
+          self.blobs.requeue_dirty(node, block);
+          continue
+        },
+      };
+      let mut nodes = self.node_cache.write(&node);
+      match nodes.get_mut(&node) {
+        Some(ref mut entry) if block < entry.get_blocks().len() => {
+          if let Some(hash) = self.blobs.flush_block(node, block)? {
+            entry.set_block(block, hash);
+          }
+        },
+        // The write that dirtied it hasn't saved the node yet so try again later
+        _ => self.blobs.requeue_dirty(node, block),
+      }
+    }
     Ok(())
   }
 
-  pub fn fsync_node(&self, node: NodeId) -> Result<(), c_int> {
+  pub fn sync_journal(&self) -> Result<(), SyncerError> {
+    Ok(self.journal.sync()?)
+  }
+
+  // Put back whatever was only in the caches when we last stopped and save it
+  pub fn start_journal(&self) -> Result<(), SyncerError> {
+    let records = self.journal.start()?;
+    if records.is_empty() { return Ok(()) }
+    eprintln!("WARNING: replaying {} journal records left by an unclean shutdown", records.len());
+
+    // Replay the writes on top of the block they started from. A block that was saved
+    // in between is already at the hash the later writes started from.
+    let mut blocks: ReplayBlocks = HashMap::new();
+    let mut entries = HashMap::new();
+    for record in records {
+      match record {
+        JournalRecord::Write { node, block, hash, offset, data } => {
+          let content = match self.replay_base(&mut blocks, node, block, hash) {
+            Some(c) => c,
+            None => continue,
+          };
+          let end = offset + data.len();
+          if end > content.len() { content.resize(end, 0) }
+          content[offset..end].copy_from_slice(&data);
+        },
+        JournalRecord::Truncate { node, block, hash, len } => {
+          if let Some(content) = self.replay_base(&mut blocks, node, block, hash) {
+            content.truncate(len);
+          }
+        },
+        JournalRecord::Node { node, entry } => {
+          entries.insert(node, *entry);
+        },
+      }
+    }
+
+    let mut nodes: Vec<NodeId> = entries.keys().cloned().chain(blocks.keys().map(|k| k.0)).collect();
+    nodes.sort();
+    nodes.dedup();
+    for node in nodes {
+      let saved = if self.blobs.node_exists(node)? {
+        self.fetch_node(node).ok().map(|n| n.1)
+      } else {
+        None
+      };
+      // Anything that was saved after the journaled version wins
+      let entry = match (entries.remove(&node), saved) {
+        (Some(journaled), Some(saved)) => {
+          if saved.cmp_vclock(&journaled) == VectorOrdering::Greater { saved } else { journaled }
+        },
+        (Some(journaled), None) => journaled,
+        (None, Some(saved)) => saved,
+        (None, None) => continue,
+      };
+      // Blocks that don't start from what the node has now were already saved and the
+      // ones past its end were truncated away
+      for (&(bnode, block), (base, content)) in blocks.iter() {
+        if bnode != node { continue }
+        if entry.get_blocks().get(block) == Some(base) {
+          self.blobs.cache_block(node, block, content.clone());
+        }
+      }
+      self.node_cache.write(&node).insert(node, entry);
+    }
+    self.sync_all()
+  }
+
+  // The contents a replayed record applies to, carrying on from the earlier records
+  // for the same block when they led to the hash it started from
+  fn replay_base<'a>(&self, blocks: &'a mut ReplayBlocks, node: NodeId, block: usize, hash: BlobHash) -> Option<&'a mut Vec<u8>> {
+    let key = (node, block);
+    let reuse = match blocks.get(&key) {
+      Some((base, content)) => *base == hash || hash.matches(content),
+      None => false,
+    };
+    if reuse {
+      blocks.get_mut(&key).unwrap().0 = hash;
+    } else {
+      match self.blobs.read_blob(&hash) {
+        Ok(content) => { blocks.insert(key, (hash, content)); },
+        Err(_) => {
+          eprintln!("WARNING: can't replay write to {:?}, block {} is missing", node, hash);
+          return None
+        },
+      }
+    }
+    Some(&mut blocks.get_mut(&key).unwrap().1)
+  }
+
+  pub fn fsync_node(&self, node: NodeId) -> Result<(), SyncerError> {
     let (hash, entry) = self.fetch_node(node)?;
     self.blobs.fsync_file(&hash)?;
     for hash in entry.get_blocks() {
@@ -152,26 +522,65 @@ impl BackingStore {
     Ok(())
   }
 
-  pub fn do_uploads(&self) -> Result<(), Error> {
-    match self.blobs.do_uploads() {
-      Ok(_) => Ok(()),
-      Err(_) => Err(Error::new(ErrorKind::Other, "upload failed")),
-    }
+  pub fn do_uploads(&self) -> Result<(), SyncerError> {
+    self.blobs.do_uploads()
   }
 
-  pub fn do_uploads_nodes(&self) -> Result<(), Error> {
+  pub fn do_uploads_nodes(&self) -> Result<(), SyncerError> {
     self.blobs.do_uploads_nodes()
   }
 
-  pub fn do_downloads_nodes(&self) -> Result<(), Error> {
+  pub fn do_downloads_nodes(&self) -> Result<(), SyncerError> {
     self.blobs.do_downloads_nodes()
   }
 
-  pub fn do_removals(&self) -> Result<(), Error> {
+  pub fn wait_downloads_nodes(&self) -> Result<(), SyncerError> {
+    self.blobs.wait_downloads_nodes()
+  }
+
+  // Wait for local changes to be saved and send them to the server straight away.
+  // Draining the caches is left to the regular sync so a busy writer doesn't turn
+  // every short burst into a full flush.
+  pub fn push_changes(&self) -> Result<(), SyncerError> {
+    if !self.blobs.wait_local_changes() {
+      return Ok(())
+    }
+    self.do_uploads()?;
+    self.do_uploads_nodes()
+  }
+
+  pub fn take_remote_change(&self, node: NodeId) -> bool {
+    self.blobs.take_remote_change(node)
+  }
+
+  pub fn stop_notifications(&self) {
+    self.blobs.stop_notifications();
+  }
+
+  pub fn do_downloads_nodes_latest(&self) -> Result<(), SyncerError> {
+    self.blobs.do_downloads_nodes_latest()
+  }
+
+  pub fn do_removals(&self) -> Result<(), SyncerError> {
     self.blobs.do_removals()
   }
 
-  pub fn init_server(&self) -> Result<(), Error> {
+  pub fn do_checkpoint(&self) -> Result<(), SyncerError> {
+    self.blobs.do_checkpoint(false)
+  }
+
+  pub fn load_checkpoint(&self) -> Result<bool, SyncerError> {
+    self.blobs.load_checkpoint(true)
+  }
+
+  pub fn compact(&self) -> Result<(), SyncerError> {
+    self.sync_all()?;
+    self.do_uploads()?;
+    self.do_uploads_nodes()?;
+    self.blobs.compact()
+  }
+
+  pub fn init_server(&self) -> Result<(), SyncerError> {
     self.blobs.init_server()?;
     self.sync_all()?;
     self.do_uploads()?;
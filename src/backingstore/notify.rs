@@ -0,0 +1,185 @@
+use crate::settings::*;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+use std::io::{Error, ErrorKind};
+
+struct NotifierState {
+  changed: bool,
+  stopped: bool,
+  watchable: bool,
+}
+
+// Wakes up the sync threads as soon as there's something for them to do instead of
+// having them poll on a timer. Local changes are signaled directly and changes from
+// other peers are picked up by watching the node logs on the server with inotifywait.
+pub struct Notifier {
+  host: Option<String>,
+  nodesdir: String,
+  peerid: String,
+  state: Mutex<NotifierState>,
+  cond: Condvar,
+}
+
+// Single quote for the remote shell
+fn quote(arg: &str) -> String {
+  format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+impl Notifier {
+  pub fn new(server: &str, peerid: &str) -> Self {
+    // Same rules as rsync, host:path goes over ssh and anything else is local
+    let (host, dir) = match (server.find(':'), server.find('/')) {
+      (Some(colon), slash) if slash.map(|s| colon < s).unwrap_or(true) && !server.contains("::") => {
+        (Some(server[..colon].to_string()), &server[colon+1..])
+      },
+      _ => (None, server),
+    };
+    let nodesdir = if dir.is_empty() { "data/nodes".to_string() } else { format!("{}/data/nodes", dir) };
+
+    Self {
+      host,
+      nodesdir,
+      peerid: peerid.to_string(),
+      state: Mutex::new(NotifierState {
+        changed: false,
+        stopped: false,
+        watchable: true,
+      }),
+      cond: Condvar::new(),
+    }
+  }
+
+  pub fn local_change(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.changed = true;
+    self.cond.notify_all();
+  }
+
+  pub fn stop(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.stopped = true;
+    self.cond.notify_all();
+  }
+
+  // Sleep unless we get stopped, returns false if we were
+  pub fn sleep(&self, dur: Duration) -> bool {
+    let deadline = Instant::now() + dur;
+    let mut state = self.state.lock().unwrap();
+    while !state.stopped {
+      let now = Instant::now();
+      if now >= deadline { break }
+      state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+    !state.stopped
+  }
+
+  // Wait for local changes for at most timeout and then give them a moment to settle
+  // so a burst of writes gets pushed together. Returns false if we were stopped.
+  pub fn wait_local(&self, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    {
+      let mut state = self.state.lock().unwrap();
+      while !state.changed && !state.stopped {
+        let now = Instant::now();
+        if now >= deadline { break }
+        state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+      }
+      state.changed = false;
+    }
+    self.sleep(Duration::from_millis(LIVE_PUSH_DELAY))
+  }
+
+  // Wait until other peers may have changed something on the server. Falls back to
+  // plain polling if the server can't be watched. Returns false if we were stopped.
+  pub fn wait_remote(&self) -> bool {
+    if self.state.lock().unwrap().watchable {
+      match self.watch(LIVE_WATCH_TIMEOUT) {
+        Ok(r) => return r,
+        Err(e) => {
+          eprintln!("WARNING: can't watch the server for changes, polling instead: {}", e);
+          self.state.lock().unwrap().watchable = false;
+        },
+      }
+    }
+    self.sleep(Duration::from_secs(LIVE_POLL_INTERVAL))
+  }
+
+  fn watch_command(&self, secs: u64) -> Command {
+    // Our own uploads don't need to wake us up
+    let exclude = format!("/\\.?{}[^/]*$", self.peerid);
+    let secs = secs.to_string();
+    let args = ["inotifywait", "-qq", "-t", &secs, "-e", "close_write", "-e", "moved_to",
+                "--exclude", &exclude, &self.nodesdir];
+    match self.host {
+      Some(ref host) => {
+        let mut cmd = Command::new("ssh");
+        cmd.arg(host);
+        cmd.arg(args.iter().map(|a| quote(a)).collect::<Vec<String>>().join(" "));
+        cmd
+      },
+      None => {
+        let mut cmd = Command::new(args[0]);
+        cmd.args(&args[1..]);
+        cmd
+      },
+    }
+  }
+
+  fn watch(&self, secs: u64) -> Result<bool, Error> {
+    let mut child = self.watch_command(secs)
+      .stdin(Stdio::null())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()?;
+    loop {
+      if let Some(status) = child.try_wait()? {
+        return match status.code() {
+          // Something changed or we timed out, either way it's time to check
+          Some(0) | Some(2) => Ok(true),
+          _ => Err(Error::new(ErrorKind::Other, format!("inotifywait failed with {}", status))),
+        }
+      }
+      if !self.sleep(Duration::from_millis(100)) {
+        child.kill().ok();
+        child.wait().ok();
+        return Ok(false)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn server_paths() {
+    let n = Notifier::new("host:/srv/syncer", "0011223344556677");
+    assert_eq!(Some("host".to_string()), n.host);
+    assert_eq!("/srv/syncer/data/nodes", n.nodesdir);
+    let n = Notifier::new("/srv/syncer", "0011223344556677");
+    assert_eq!(None, n.host);
+    assert_eq!("/srv/syncer/data/nodes", n.nodesdir);
+    let n = Notifier::new("./with:colon", "0011223344556677");
+    assert_eq!(None, n.host);
+    let n = Notifier::new("host:", "0011223344556677");
+    assert_eq!("data/nodes", n.nodesdir);
+  }
+
+  #[test]
+  fn quoting() {
+    assert_eq!("'a b'", quote("a b"));
+    assert_eq!("'it'\\''s'", quote("it's"));
+  }
+
+  #[test]
+  fn stop_wakes_waiters() {
+    let n = Notifier::new("/nonexistent", "0011223344556677");
+    n.local_change();
+    assert!(n.wait_local(Duration::from_secs(10)));
+    n.stop();
+    assert!(!n.wait_local(Duration::from_secs(10)));
+    assert!(!n.sleep(Duration::from_secs(10)));
+  }
+}
@@ -0,0 +1,130 @@
+extern crate bincode;
+
+use super::{BlobHash, HashKind};
+
+// Small blobs get uploaded together in packs so the server doesn't end up with a file
+// for every small file and directory. Each pack is stored as <name>.pack along with
+// <name>.idx that lists where each blob is in it.
+const INDEX_MAGIC: &[u8; 4] = b"SYNI";
+const INDEX_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PackEntry {
+  pub hash: BlobHash,
+  pub offset: u64,
+  pub size: u64,
+}
+
+pub struct PackWriter {
+  data: Vec<u8>,
+  entries: Vec<PackEntry>,
+}
+
+impl PackWriter {
+  pub fn new() -> Self {
+    Self {
+      data: Vec::new(),
+      entries: Vec::new(),
+    }
+  }
+
+  pub fn add(&mut self, hash: BlobHash, blob: &[u8]) {
+    self.entries.push(PackEntry {
+      hash,
+      offset: self.data.len() as u64,
+      size: blob.len() as u64,
+    });
+    self.data.extend_from_slice(blob);
+  }
+
+  pub fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  // The pack's name is the hash of its contents so packs made by different peers never
+  // clash. Returns the name, the pack and what's in it.
+  pub fn finish(self, kind: HashKind) -> (String, Vec<u8>, Vec<PackEntry>) {
+    let name = BlobHash::compute(kind, &self.data).to_string();
+    (name, self.data, self.entries)
+  }
+}
+
+pub fn encode_index(entries: &[PackEntry]) -> Vec<u8> {
+  let mut encoded = INDEX_MAGIC.to_vec();
+  encoded.push(INDEX_VERSION);
+  encoded.append(&mut bincode::serialize(entries).unwrap());
+  encoded
+}
+
+pub fn decode_index(buffer: &[u8]) -> Result<Vec<PackEntry>, String> {
+  if buffer.len() <= INDEX_MAGIC.len() || !buffer.starts_with(INDEX_MAGIC) {
+    return Err("not a pack index".to_string())
+  }
+  let version = buffer[INDEX_MAGIC.len()];
+  if version != INDEX_VERSION {
+    return Err(format!("pack index in unknown version {}", version))
+  }
+  bincode::deserialize(&buffer[INDEX_MAGIC.len()+1..]).map_err(|e| e.to_string())
+}
+
+// Get a single blob out of a pack, if the pack is long enough to have it
+pub fn extract<'a>(pack: &'a [u8], entry: &PackEntry) -> Option<&'a [u8]> {
+  let start = entry.offset as usize;
+  let end = start.checked_add(entry.size as usize)?;
+  pack.get(start..end)
+}
+
+// Pack names are the hash of their contents, anything else in the packs dir is ignored
+pub fn parse_pack_name(filename: &str) -> Option<(&str, &str)> {
+  let dot = filename.rfind('.')?;
+  let (name, ext) = (&filename[..dot], &filename[dot+1..]);
+  BlobHash::parse(name)?;
+  match ext {
+    "pack" | "idx" => Some((name, ext)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn packs_roundtrip() {
+    let kind = HashKind::default();
+    let mut writer = PackWriter::new();
+    assert!(writer.is_empty());
+    let first = BlobHash::compute(kind, b"first");
+    let second = BlobHash::compute(kind, b"second blob");
+    writer.add(first, b"first");
+    writer.add(second, b"second blob");
+    assert_eq!(16, writer.len());
+
+    let (name, pack, entries) = writer.finish(kind);
+    assert!(BlobHash::parse(&name).unwrap().matches(&pack));
+    let index = encode_index(&entries);
+    assert_eq!(entries, decode_index(&index).unwrap());
+    assert_eq!(vec![first, second], entries.iter().map(|e| e.hash).collect::<Vec<_>>());
+    assert_eq!(Some(&b"second blob"[..]), extract(&pack, &entries[1]));
+    assert!(entries[0].hash.matches(extract(&pack, &entries[0]).unwrap()));
+    assert_eq!(None, extract(&pack[..10], &entries[1]));
+
+    assert!(decode_index(&index[1..]).is_err());
+    let mut newer = index.clone();
+    newer[INDEX_MAGIC.len()] = INDEX_VERSION + 1;
+    assert!(decode_index(&newer).is_err());
+  }
+
+  #[test]
+  fn pack_names() {
+    let name = BlobHash::compute(HashKind::default(), b"pack").to_string();
+    assert_eq!(Some((&name[..], "idx")), parse_pack_name(&format!("{}.idx", name)));
+    assert_eq!(Some((&name[..], "pack")), parse_pack_name(&format!("{}.pack", name)));
+    assert_eq!(None, parse_pack_name(&format!("{}.tmp", name)));
+    assert_eq!(None, parse_pack_name("something.idx"));
+  }
+}
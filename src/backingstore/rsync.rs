@@ -19,6 +19,16 @@ impl RsyncCommand {
   }
 
   pub fn run(&self) -> Result<(), Error> {
+    self.exec(false).map(|_| ())
+  }
+
+  // Like run() but a source that isn't there is Ok(false) instead of an error. rsync
+  // exits with 23 for that and retrying won't change it.
+  pub fn run_optional(&self) -> Result<bool, Error> {
+    self.exec(true)
+  }
+
+  fn exec(&self, optional: bool) -> Result<bool, Error> {
     for _ in 0..10 {
       let mut cmd = Command::new("rsync");
       cmd.arg("--quiet");
@@ -30,7 +40,9 @@ impl RsyncCommand {
       match cmd.status() {
         Ok(v) => {
           if v.success() {
-            return Ok(())
+            return Ok(true)
+          } else if optional && v.code() == Some(23) {
+            return Ok(false)
           } else {
             continue
           }
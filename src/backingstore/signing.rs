@@ -0,0 +1,124 @@
+extern crate ed25519_compact;
+extern crate base64;
+extern crate bincode;
+extern crate hex;
+
+use super::NodeInfo;
+use super::versions::decode_node_info;
+use self::ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+
+// Every line in the node logs and every pointer to a checkpoint is signed with the
+// key of the peer that wrote it so others only take changes from peers they trust
+pub struct Signer {
+  keypair: KeyPair,
+}
+
+impl Signer {
+  pub fn new(secretkey: &str) -> Option<Self> {
+    let bytes = hex::decode(secretkey).ok()?;
+    let seed = Seed::from_slice(&bytes).ok()?;
+    Some(Self {
+      keypair: KeyPair::from_seed(seed),
+    })
+  }
+
+  pub fn public_key(&self) -> String {
+    hex::encode(&self.keypair.pk[..])
+  }
+
+  pub fn sign(&self, message: &[u8]) -> String {
+    base64::encode(&self.keypair.sk.sign(message, None)[..])
+  }
+
+  pub fn sign_node(&self, node: &NodeInfo) -> String {
+    let encoded = bincode::serialize(node).unwrap();
+    format!("{} {}", base64::encode(&encoded), self.sign(&encoded))
+  }
+}
+
+pub fn valid_public_key(pubkey: &str) -> bool {
+  match hex::decode(pubkey) {
+    Ok(bytes) => PublicKey::from_slice(&bytes).is_ok(),
+    Err(_) => false,
+  }
+}
+
+pub fn verify(pubkey: &str, message: &[u8], signature: &str) -> bool {
+  let pk = match hex::decode(pubkey).ok().and_then(|b| PublicKey::from_slice(&b).ok()) {
+    Some(pk) => pk,
+    None => return false,
+  };
+  let sig = match base64::decode(signature).ok().and_then(|b| Signature::from_slice(&b).ok()) {
+    Some(sig) => sig,
+    None => return false,
+  };
+  pk.verify(message, &sig).is_ok()
+}
+
+// Split a node log line into the node and whether it was signed by the given key
+pub fn parse_node_line(line: &str, pubkey: Option<&str>) -> Result<NodeInfo, &'static str> {
+  let mut parts = line.splitn(2, ' ');
+  let encoded = match base64::decode(parts.next().unwrap_or("")) {
+    Ok(e) => e,
+    Err(_) => return Err("invalid encoding"),
+  };
+  let signature = match parts.next() {
+    Some(s) => s,
+    None => return Err("no signature"),
+  };
+  let pubkey = match pubkey {
+    Some(p) => p,
+    None => return Err("untrusted peer"),
+  };
+  if !verify(pubkey, &encoded, signature) {
+    return Err("bad signature")
+  }
+  match decode_node_info(&encoded) {
+    Some(node) => Ok(node),
+    None => Err("invalid node"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn signer(byte: u8) -> Signer {
+    Signer::new(&hex::encode([byte; 32])).unwrap()
+  }
+
+  #[test]
+  fn signed_lines() {
+    let s1 = signer(1);
+    let s2 = signer(2);
+    let node = NodeInfo {
+      id: (1, 2),
+      hash: [3; 20].into(),
+      creation: 4,
+    };
+    let line = s1.sign_node(&node);
+    let parsed = parse_node_line(&line, Some(&s1.public_key())).unwrap();
+    assert_eq!(node.id, parsed.id);
+    assert_eq!(node.hash, parsed.hash);
+    assert_eq!(Err("bad signature"), parse_node_line(&line, Some(&s2.public_key())).map(|n| n.id));
+    assert_eq!(Err("untrusted peer"), parse_node_line(&line, None).map(|n| n.id));
+    let unsigned = line.split(' ').next().unwrap();
+    assert_eq!(Err("no signature"), parse_node_line(unsigned, Some(&s1.public_key())).map(|n| n.id));
+
+    // Changing the node breaks the signature
+    let mut tampered = node;
+    tampered.creation = 5;
+    let forged = format!("{} {}", base64::encode(&bincode::serialize(&tampered).unwrap()),
+                         line.split(' ').nth(1).unwrap());
+    assert_eq!(Err("bad signature"), parse_node_line(&forged, Some(&s1.public_key())).map(|n| n.id));
+  }
+
+  #[test]
+  fn keys() {
+    assert!(Signer::new("00").is_none());
+    let s = signer(1);
+    assert!(valid_public_key(&s.public_key()));
+    assert!(!valid_public_key("zz"));
+    assert_eq!(s.public_key(), signer(1).public_key());
+  }
+}
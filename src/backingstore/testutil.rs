@@ -0,0 +1,43 @@
+// Fixtures shared by the tests that need a real data dir
+
+use super::BackingStore;
+use crate::config::Config;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+// A scratch dir that's gone once the test is done with it, whether it passed or not
+pub struct TempDir {
+  path: PathBuf,
+}
+
+impl TempDir {
+  pub fn new(name: &str) -> Self {
+    let path = std::env::temp_dir().join(format!("syncer-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&path).ok();
+    fs::create_dir_all(&path).unwrap();
+    Self { path }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    fs::remove_dir_all(&self.path).ok();
+  }
+}
+
+// A config for a peer whose server is never reached
+pub fn test_config() -> Config {
+  let mut config = Config::new("/nonexistent".to_string(), 0);
+  config.peerid = "0011223344556677".to_string();
+  config
+}
+
+pub fn test_store(name: &str) -> (BackingStore, TempDir) {
+  let dir = TempDir::new(name);
+  let bs = BackingStore::new(dir.path(), &test_config()).unwrap();
+  (bs, dir)
+}
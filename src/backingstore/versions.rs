@@ -0,0 +1,108 @@
+extern crate bincode;
+extern crate serde;
+
+use super::{NodeId, NodeInfo, Checkpoint, CheckpointNode, LegacyHash};
+use crate::filesystem::VectorClock;
+
+// The structures that end up in node logs and checkpoints as they were before hashes
+// carried their algorithm. Those can't be told apart from the current ones by anything
+// other than their size so each layout is tried in turn.
+
+#[derive(Serialize, Deserialize)]
+pub struct NodeInfoV1 {
+  id: NodeId,
+  hash: LegacyHash,
+  creation: i64,
+}
+
+impl From<NodeInfoV1> for NodeInfo {
+  fn from(old: NodeInfoV1) -> Self {
+    NodeInfo {
+      id: old.id,
+      hash: old.hash.into(),
+      creation: old.creation,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointNodeV1 {
+  id: NodeId,
+  hash: LegacyHash,
+  creation: i64,
+  vclock: VectorClock,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointV1 {
+  peernum: i64,
+  creation: i64,
+  logs: Vec<(i64, u64, u64)>,
+  nodes: Vec<CheckpointNodeV1>,
+}
+
+impl From<CheckpointV1> for Checkpoint {
+  fn from(old: CheckpointV1) -> Self {
+    Checkpoint {
+      peernum: old.peernum,
+      creation: old.creation,
+      logs: old.logs,
+      nodes: old.nodes.into_iter().map(|n| CheckpointNode {
+        id: n.id,
+        hash: n.hash.into(),
+        creation: n.creation,
+        vclock: n.vclock,
+      }).collect(),
+    }
+  }
+}
+
+// Only take the decoded value if it used up the whole buffer, otherwise it was in some
+// other layout that happened to decode
+pub fn decode_exact<T>(buffer: &[u8]) -> Option<T>
+  where T: serde::de::DeserializeOwned + serde::Serialize {
+  let value: T = bincode::deserialize(buffer).ok()?;
+  if bincode::serialized_size(&value).ok()? != buffer.len() as u64 {
+    return None
+  }
+  Some(value)
+}
+
+pub fn decode_node_info(buffer: &[u8]) -> Option<NodeInfo> {
+  decode_exact::<NodeInfo>(buffer)
+    .or_else(|| decode_exact::<NodeInfoV1>(buffer).map(NodeInfo::from))
+}
+
+pub fn decode_checkpoint(buffer: &[u8]) -> Option<Checkpoint> {
+  decode_exact::<Checkpoint>(buffer)
+    .or_else(|| decode_exact::<CheckpointV1>(buffer).map(Checkpoint::from))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backingstore::BlobHash;
+  use crate::settings::*;
+
+  #[test]
+  fn reads_old_layouts() {
+    let old = NodeInfoV1 { id: (1, 2), hash: [3; HASHSIZE], creation: 4 };
+    let node = decode_node_info(&bincode::serialize(&old).unwrap()).unwrap();
+    assert_eq!(((1, 2), BlobHash::from([3; HASHSIZE]), 4), (node.id, node.hash, node.creation));
+    let encoded = bincode::serialize(&node).unwrap();
+    assert_eq!(node.hash, decode_node_info(&encoded).unwrap().hash);
+    assert!(decode_node_info(&encoded[1..]).is_none());
+
+    let old = CheckpointV1 {
+      peernum: 1,
+      creation: 2,
+      logs: vec![(1, 0, 10)],
+      nodes: vec![CheckpointNodeV1 { id: (1, 2), hash: [5; HASHSIZE], creation: 3, vclock: VectorClock::new() }],
+    };
+    let checkpoint = decode_checkpoint(&bincode::serialize(&old).unwrap()).unwrap();
+    assert_eq!(vec![(1, 0, 10)], checkpoint.logs);
+    assert_eq!(BlobHash::from([5; HASHSIZE]), checkpoint.nodes[0].hash);
+    let encoded = bincode::serialize(&checkpoint).unwrap();
+    assert_eq!(1, decode_checkpoint(&encoded).unwrap().nodes.len());
+  }
+}
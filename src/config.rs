@@ -5,11 +5,12 @@ extern crate hex;
 use self::rand::RngCore;
 use self::rand::os::OsRng;
 
-use std::path::Path;
-use std::fs::File;
-use std::io::{Read,Write};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{Read, Write, ErrorKind};
 
 use crate::settings::*;
+use crate::backingstore::{HashAlgo, HashKind, LocalLayout};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -17,8 +18,65 @@ pub struct Config {
   pub formatversion: u64,
   pub server: String,
   pub maxbytes: u64,
+  // How many bytes of written blocks to keep in memory before saving them
+  #[serde(default = "default_maxdirty")]
+  pub maxdirty: u64,
   #[serde(default)]
   pub peerid: String,
+  // Seed of the key used to sign our node logs
+  #[serde(default)]
+  pub secretkey: String,
+  // Whether to trust peers we haven't seen before with the key they publish
+  #[serde(default = "default_trustnewpeers")]
+  pub trustnewpeers: bool,
+  // Size of the blocks files are split into, fixed when the repository is created
+  #[serde(default = "default_blksize")]
+  pub blksize: usize,
+  // Algorithm and size of the hashes that name new blobs, fixed when the repository is
+  // created. Blobs named by other kinds of hashes can still be read.
+  #[serde(default = "default_hashalgo")]
+  pub hashalgo: HashAlgo,
+  #[serde(default = "default_hashsize")]
+  pub hashsize: usize,
+  // How blobs are kept in the local dir, picked when it's created
+  #[serde(default = "default_localstore")]
+  pub localstore: LocalLayout,
+}
+
+// The settings every peer of a repository needs to agree on. They're written to the data
+// dir when it's created so they end up on the server for clones to pick up.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RepoSettings {
+  pub blksize: usize,
+  #[serde(default = "default_hashalgo")]
+  pub hashalgo: HashAlgo,
+  pub hashsize: usize,
+}
+
+// Peers have to be trusted with peers trust unless the config says otherwise
+fn default_trustnewpeers() -> bool {
+  false
+}
+
+fn default_maxdirty() -> u64 {
+  MAXDIRTY
+}
+
+// Repositories from before these were in the config all used the defaults
+fn default_blksize() -> usize {
+  BLKSIZE
+}
+
+fn default_hashalgo() -> HashAlgo {
+  HashAlgo::Blake2b
+}
+
+fn default_hashsize() -> usize {
+  HASHSIZE
+}
+
+fn default_localstore() -> LocalLayout {
+  LocalLayout::Flat
 }
 
 pub fn convert_peerid(peerid: &str) -> i64 {
@@ -41,7 +99,14 @@ impl Config {
       formatversion: FORMATVERSION,
       server,
       maxbytes,
+      maxdirty: MAXDIRTY,
       peerid: hex::encode(&bytes),
+      secretkey: Self::new_secretkey(),
+      trustnewpeers: false,
+      blksize: BLKSIZE,
+      hashalgo: HashAlgo::Blake2b,
+      hashsize: HASHSIZE,
+      localstore: LocalLayout::Flat,
     }
   }
 
@@ -66,9 +131,24 @@ impl Config {
     if !hex::decode(&config.peerid).is_ok() {
       return Err(format!("invalid peer: {:?}", config.peerid));
     }
+    // Repositories from before signed logs don't have a key but can't be opened anyway
+    if config.formatversion >= FORMATVERSION && !config.valid_secretkey() {
+      return Err("invalid secret key".to_string());
+    }
     Ok(config)
   }
 
+  pub fn new_secretkey() -> String {
+    let mut rng = OsRng::new().unwrap();
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed);
+    hex::encode(seed)
+  }
+
+  pub fn valid_secretkey(&self) -> bool {
+    self.secretkey.len() == 64 && hex::decode(&self.secretkey).is_ok()
+  }
+
   pub fn save_config(&self, path: &Path) -> Result<(), String> {
     let serial = match toml::to_string(self) {
       Ok(c) => c,
@@ -88,11 +168,96 @@ impl Config {
   pub fn peernum(&self) -> i64 {
     convert_peerid(&self.peerid)
   }
+
+  pub fn repo_settings(&self) -> RepoSettings {
+    RepoSettings {
+      blksize: self.blksize,
+      hashalgo: self.hashalgo,
+      hashsize: self.hashsize,
+    }
+  }
+
+  // Pick the hash for new blobs from "<algorithm>[:<bytes>]"
+  pub fn set_hash(&mut self, spec: &str) -> Result<(), String> {
+    let mut parts = spec.splitn(2, ':');
+    let algo = match parts.next().and_then(HashAlgo::from_name) {
+      Some(algo) => algo,
+      None => return Err("unknown algorithm".to_string()),
+    };
+    self.hashsize = match parts.next() {
+      Some(size) => size.parse::<usize>().map_err(|e| e.to_string())?,
+      None if algo == HashAlgo::Blake3 => MAXHASHSIZE,
+      None => HASHSIZE,
+    };
+    self.hashalgo = algo;
+    Ok(())
+  }
+
+  pub fn set_localstore(&mut self, name: &str) -> Result<(), String> {
+    match LocalLayout::from_name(name) {
+      Some(layout) => {
+        self.localstore = layout;
+        Ok(())
+      },
+      None => Err("unknown local store, use flat, fanout or sqlite".to_string()),
+    }
+  }
+
+  pub fn hashkind(&self) -> HashKind {
+    HashKind {
+      algo: self.hashalgo,
+      len: self.hashsize,
+    }
+  }
+}
+
+impl RepoSettings {
+  fn path(source: &Path) -> PathBuf {
+    let mut path = PathBuf::from(source);
+    path.push("repository");
+    path
+  }
+
+  // Repositories created before the settings were stored don't have them
+  pub fn load(source: &Path) -> Result<Option<RepoSettings>, String> {
+    let text = match fs::read_to_string(Self::path(source)) {
+      Ok(t) => t,
+      Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(format!("couldn't read repository settings: {}", e)),
+    };
+    match toml::from_str(&text) {
+      Ok(s) => Ok(Some(s)),
+      Err(e) => Err(format!("couldn't parse repository settings: {}", e)),
+    }
+  }
+
+  pub fn save(&self, source: &Path) -> Result<(), String> {
+    let serial = match toml::to_string(self) {
+      Ok(s) => s,
+      Err(e) => return Err(format!("couldn't write repository settings: {}", e)),
+    };
+    match fs::write(Self::path(source), serial) {
+      Ok(_) => Ok(()),
+      Err(e) => Err(format!("couldn't write repository settings: {}", e)),
+    }
+  }
+
+  // Whether this build can work with a repository with these settings
+  pub fn check(&self) -> Result<(), String> {
+    if self.hashsize < MINHASHSIZE || self.hashsize > MAXHASHSIZE {
+      return Err(format!("hash size {} isn't between {} and {}", self.hashsize, MINHASHSIZE, MAXHASHSIZE));
+    }
+    if self.blksize < MINBLKSIZE || self.blksize > MAXBLKSIZE {
+      return Err(format!("block size {} isn't between {} and {}", self.blksize, MINBLKSIZE, MAXBLKSIZE));
+    }
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::backingstore::testutil::TempDir;
 
   #[test]
   fn peernum_roundtrips() {
@@ -100,4 +265,25 @@ mod tests {
     let text = hex::encode(&vals);
     assert_eq!(16843009, convert_peerid(&text));
   }
+
+  #[test]
+  fn repo_settings() {
+    let mut conf = Config::new("/nonexistent".to_string(), 0);
+    assert_eq!(Ok(()), conf.repo_settings().check());
+    conf.blksize = 100;
+    assert!(conf.repo_settings().check().is_err());
+    conf.blksize = 4000000;
+    conf.hashsize = 64;
+    assert!(conf.repo_settings().check().is_err());
+    conf.set_hash("blake3").unwrap();
+    assert_eq!(HashKind { algo: HashAlgo::Blake3, len: 32 }, conf.hashkind());
+    assert_eq!(Ok(()), conf.repo_settings().check());
+    assert!(conf.set_hash("sha1").is_err());
+    assert!(conf.set_hash("blake2b:x").is_err());
+
+    let dir = TempDir::new("repo");
+    assert_eq!(None, RepoSettings::load(dir.path()).unwrap());
+    conf.repo_settings().save(dir.path()).unwrap();
+    assert_eq!(Some(conf.repo_settings()), RepoSettings::load(dir.path()).unwrap());
+  }
 }
@@ -4,6 +4,9 @@ extern crate libc;
 use self::libc::c_int;
 extern crate time;
 use self::time::Timespec;
+extern crate blake2;
+use self::blake2::Blake2b;
+use self::blake2::digest::{Input, VariableOutput};
 
 use std::ffi::{OsStr, OsString};
 use std::cmp;
@@ -17,7 +20,7 @@ use crate::settings::*;
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Timespec")]
 #[allow(dead_code)]
-struct TimespecDef {
+pub(super) struct TimespecDef {
   sec: i64,
   nsec: i32,
 }
@@ -57,6 +60,24 @@ macro_rules! merge_3way {
   }
 }
 
+// Three way merge of a single value that fails if both sides changed it differently
+fn merge_3way_scalar<T: PartialEq>(base: T, first: T, second: T) -> Option<T> {
+  if first == base {
+    Some(second)
+  } else if second == base || first == second {
+    Some(first)
+  } else {
+    None
+  }
+}
+
+// Three way merge of a counter that keeps the changes made on both sides, so two
+// peers adding a link each end up with both
+fn merge_3way_count(base: u32, first: u32, second: u32) -> u32 {
+  let merged = first as i64 + second as i64 - base as i64;
+  cmp::max(1, merged) as u32
+}
+
 macro_rules! merge_3way_hash {
   ($base:expr, $left:expr, $right:expr) => {
     {
@@ -89,6 +110,7 @@ pub struct FSEntry {
 
   pub filetype: FileTypeDef,
   pub perm: u32,
+  pub nlink: u32,
   pub uid: u32,
   pub gid: u32,
   pub flags: u32,
@@ -107,6 +129,7 @@ pub struct FSEntry {
   pub bkuptime: Timespec,
   pub size: u64,
   pub blocks: Vec<BlobHash>,
+  pub parent: NodeId,
   pub children: BTreeMap<String, (NodeId, FileTypeDef)>,
   pub xattrs: BTreeMap<String, Vec<u8>>,
 }
@@ -115,6 +138,23 @@ pub fn from_os_str(ostr: &OsStr) -> Result<String, c_int> {
   ostr.to_os_string().into_string().or_else(|_| Err(libc::EIO))
 }
 
+// Stable inode number for a node that's the same for every path that links to it and
+// across mounts and peers. The root needs to be 1 as that's what FUSE expects.
+pub fn inode(node: NodeId) -> u64 {
+  if node == (0,0) {
+    return 1
+  }
+  let mut bytes = [0u8; 16];
+  bytes[0..8].copy_from_slice(&node.0.to_le_bytes());
+  bytes[8..16].copy_from_slice(&node.1.to_le_bytes());
+  let mut hasher = Blake2b::new(8).unwrap();
+  hasher.process(&bytes);
+  let mut buf = [0u8; 8];
+  hasher.variable_result(&mut buf).unwrap();
+  // Keep clear of 0 (invalid) and 1 (root)
+  cmp::max(u64::from_le_bytes(buf), 2)
+}
+
 impl FSEntry {
   pub fn new(filetype: FileTypeDef, peernum: i64) -> FSEntry {
     let time = self::time::get_time();
@@ -125,6 +165,7 @@ impl FSEntry {
       peernum: peernum,
       filetype: filetype,
       perm: 0,
+      nlink: 1,
       uid: 0,
       gid: 0,
       flags: 0,
@@ -137,15 +178,24 @@ impl FSEntry {
       bkuptime: time,
       size: 0,
       blocks: Vec::new(),
+      parent: (0,0),
       children: BTreeMap::new(),
       xattrs: BTreeMap::new(),
     }
   }
 
-  pub fn attrs(&self) -> FileAttr {
+  pub fn attrs(&self, node: NodeId) -> FileAttr {
     let blocks = (self.size + 512 -1)/ 512;
+    let nlink = if self.filetype == FileTypeDef::Directory {
+      // Directories are linked from their parent, their own "." and every subdir's ".."
+      let subdirs = self.children.values().filter(|c| c.1 == FileTypeDef::Directory).count();
+      2 + subdirs as u32
+    } else {
+      self.nlink
+    };
 
     FileAttr {
+      ino: inode(node),
       size: self.size,
       blocks,
       atime: self.atime,
@@ -154,7 +204,7 @@ impl FSEntry {
       crtime: self.crtime,
       kind: self.filetype.to_filetype(),
       perm: self.perm as u16,
-      nlink: 1,
+      nlink,
       uid: self.uid,
       gid: self.gid,
       rdev: self.rdev,
@@ -165,10 +215,12 @@ impl FSEntry {
   pub fn children(&self) -> Vec<DirectoryEntry> {
     assert!(self.filetype == FileTypeDef::Directory);
     let mut out = Vec::new();
-    out.push(DirectoryEntry{name: OsString::from("."), kind: FileType::Directory});
-    out.push(DirectoryEntry{name: OsString::from(".."), kind: FileType::Directory});
+    // fuse_mt fills in the inodes of . and ..
+    out.push(DirectoryEntry{ino: 0, name: OsString::from("."), kind: FileType::Directory});
+    out.push(DirectoryEntry{ino: 0, name: OsString::from(".."), kind: FileType::Directory});
     for (key, val) in self.children.iter() {
       out.push(DirectoryEntry{
+        ino: inode(val.0),
         name: key.clone().into(),
         kind: val.1.to_filetype(),
       });
@@ -176,9 +228,8 @@ impl FSEntry {
     out
   }
 
-  pub fn add_child(&mut self, name: &OsStr, node: (NodeId, FileTypeDef)) -> Result<(), c_int> {
-    self.children.insert(from_os_str(name)?, node);
-    Ok(())
+  pub fn add_child(&mut self, name: &OsStr, node: (NodeId, FileTypeDef)) -> Result<Option<(NodeId, FileTypeDef)>, c_int> {
+    Ok(self.children.insert(from_os_str(name)?, node))
   }
 
   pub fn remove_child(&mut self, name: &OsStr) -> Result<(NodeId, FileTypeDef), c_int> {
@@ -189,24 +240,25 @@ impl FSEntry {
   }
 
   pub fn write(&mut self, node: NodeId, bs: &BackingStore, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+    let blksize = bs.blksize();
     self.size = cmp::max(self.size, offset + data.len() as u64);
-    let total_needed_blocks = (self.size as usize + BLKSIZE - 1) / BLKSIZE;
+    let total_needed_blocks = (self.size as usize).div_ceil(blksize);
     if total_needed_blocks > self.blocks.len() {
-      self.blocks.resize(total_needed_blocks, bs.blob_zero());
+      self.blocks.resize(total_needed_blocks, HASHZERO);
     }
 
     let start = offset as usize;
     let end = start + data.len();
     let mut written = 0;
-    let startblock = start/BLKSIZE;
-    let endblock = (end + BLKSIZE - 1)/BLKSIZE;
+    let startblock = start/blksize;
+    let endblock = end.div_ceil(blksize);
     for i in startblock..endblock {
       let block = &self.blocks[i];
       let readahead = &self.blocks[i+1..cmp::min(i+1+READAHEAD, self.blocks.len())];
-      let bstart = cmp::max(start, i*BLKSIZE);
-      let bend = cmp::min(end, (i+1)*BLKSIZE);
+      let bstart = cmp::max(start, i*blksize);
+      let bend = cmp::min(end, (i+1)*blksize);
       let bsize = bend - bstart;
-      let boffset = bstart - i*BLKSIZE;
+      let boffset = bstart - i*blksize;
       bs.write(node, i, block, boffset, &data[written..written+bsize], readahead)?;
       written += bsize;
     }
@@ -221,28 +273,95 @@ impl FSEntry {
       return Ok(Vec::new())
     }
 
+    let blksize = bs.blksize();
     let start = offset as usize;
     let end = cmp::min(start + (size as usize), self.size as usize);
     let mut data = vec![0; end - start];
     let mut written = 0;
-    let startblock = start/BLKSIZE;
-    let endblock = (end + BLKSIZE - 1)/BLKSIZE;
+    let startblock = start/blksize;
+    let endblock = end.div_ceil(blksize);
     for i in startblock..endblock {
-      let block = &self.blocks[i];
-      let readahead = &self.blocks[i+1..cmp::min(i+1+READAHEAD, self.blocks.len())];
-      let bstart = cmp::max(start, i*BLKSIZE);
-      let bend = cmp::min(end, (i+1)*BLKSIZE);
+      let block = self.blocks.get(i).unwrap_or(&HASHZERO);
+      let readahead = self.blocks.get(i+1..cmp::min(i+1+READAHEAD, self.blocks.len())).unwrap_or(&[]);
+      let bstart = cmp::max(start, i*blksize);
+      let bend = cmp::min(end, (i+1)*blksize);
       let bsize = bend - bstart;
-      let boffset = bstart - i*BLKSIZE;
-      data[written..written+bsize].copy_from_slice(&bs.read(node, i, block, boffset, bsize, readahead)?);
+      let boffset = bstart - i*blksize;
+      // Holes and blocks that end early leave the rest as zeros
+      let content = bs.read(node, i, block, boffset, bsize, readahead)?;
+      data[written..written+content.len()].copy_from_slice(&content);
       written += bsize;
     }
     assert!(written == data.len());
     Ok(data)
   }
 
+  // Change the size, dropping the blocks past the new end and cutting the last one short
+  // so growing the file again reads zeros instead of what was there before. Growing
+  // leaves the blocks alone since anything past the end of one reads as zeros.
+  pub fn truncate(&mut self, node: NodeId, bs: &BackingStore, size: u64) -> Result<(), c_int> {
+    let blksize = bs.blksize() as u64;
+    let partial = size % blksize;
+    if size < self.size && partial != 0 {
+      let i = (size / blksize) as usize;
+      let block = self.blocks.get(i).cloned().unwrap_or(HASHZERO);
+      if !bs.is_hole(node, i, &block) {
+        bs.truncate_block(node, i, &block, partial as usize)?;
+      }
+    }
+    let needed = size.div_ceil(blksize) as usize;
+    bs.drop_blocks(node, needed);
+    self.blocks.resize(needed, HASHZERO);
+    self.size = size;
+    self.mtime = self::time::get_time();
+    Ok(())
+  }
+
+  // Where the data or the hole that's next at or after offset starts, like lseek with
+  // SEEK_DATA and SEEK_HOLE. There's always a hole at the end of the file.
+  pub fn seek(&self, node: NodeId, bs: &BackingStore, offset: u64, data: bool) -> Result<u64, c_int> {
+    if offset >= self.size {
+      return Err(libc::ENXIO)
+    }
+    let blksize = bs.blksize() as u64;
+    for i in offset/blksize..self.size.div_ceil(blksize) {
+      let block = self.blocks.get(i as usize).unwrap_or(&HASHZERO);
+      if bs.is_hole(node, i as usize, block) != data {
+        return Ok(cmp::max(offset, i*blksize))
+      }
+    }
+    if data { Err(libc::ENXIO) } else { Ok(self.size) }
+  }
+
+  // Zero out a range without changing the size. Whole blocks become holes that take no
+  // space and only the partial ones at the edges get written.
+  pub fn punch_hole(&mut self, node: NodeId, bs: &BackingStore, offset: u64, len: u64) -> Result<(), c_int> {
+    let blksize = bs.blksize() as u64;
+    let end = cmp::min(offset.saturating_add(len), self.size);
+    let mut pos = offset;
+    while pos < end {
+      let i = pos / blksize;
+      let bstart = i*blksize;
+      let bend = cmp::min(end, bstart+blksize);
+      let block = (i as usize, self.blocks.get(i as usize).cloned().unwrap_or(HASHZERO));
+      // Past the end of the file doesn't need zeroing so the last block can go whole
+      let whole = pos == bstart && (bend == bstart+blksize || bend == self.size);
+      if whole && !bs.is_cached(node, block.0) {
+        if block.0 < self.blocks.len() {
+          self.blocks[block.0] = HASHZERO;
+        }
+      } else if !bs.is_hole(node, block.0, &block.1) {
+        let zeros = vec![0; (bend - pos) as usize];
+        bs.write(node, block.0, &block.1, (pos - bstart) as usize, &zeros, &[])?;
+      }
+      pos = bend;
+    }
+    self.mtime = self::time::get_time();
+    Ok(())
+  }
+
   pub fn set_block(&mut self, i: usize, hash: BlobHash) {
-    self.blocks[i].copy_from_slice(&hash);
+    self.blocks[i] = hash;
   }
 
   pub fn get_blocks(&self) -> &Vec<BlobHash> {
@@ -257,11 +376,89 @@ impl FSEntry {
     self.clock.sec * 1000 + (self.clock.nsec as i64)/1000000
   }
 
-  pub fn merge_3way(&self, first: &FSEntry, second: &FSEntry) -> FSEntry {
+  // Total order used to pick a winner between conflicting versions so that every peer
+  // picks the same one: latest clock first and the highest peer if that's a tie
+  fn wins_over(&self, other: &FSEntry) -> bool {
+    (self.clock, self.peernum) > (other.clock, other.peernum)
+  }
+
+  // If both sides changed the contents of the file in different ways there's no way to
+  // merge them so return the version that loses and needs to be kept as a copy
+  pub fn content_conflict<'b>(&self, first: &'b FSEntry, second: &'b FSEntry, blksize: usize) -> Option<&'b FSEntry> {
+    let base = (self.size, &self.blocks);
+    let firstc = (first.size, &first.blocks);
+    let secondc = (second.size, &second.blocks);
+    if firstc == base || secondc == base || firstc == secondc {
+      return None
+    }
+    if self.merge_blocks(first, second, blksize).is_some() {
+      return None
+    }
+    Some(if first.wins_over(second) { second } else { first })
+  }
+
+  // Merge the contents block by block so that changes to different parts of the same
+  // file on different peers are both kept. Returns None if both sides changed the same
+  // block or the size in different ways.
+  fn merge_blocks(&self, first: &FSEntry, second: &FSEntry, blksize: usize) -> Option<(u64, Vec<BlobHash>)> {
+    let size = merge_3way_scalar(self.size, first.size, second.size)?;
+
+    let len = cmp::max(self.blocks.len(), cmp::max(first.blocks.len(), second.blocks.len()));
+    let mut blocks = Vec::new();
+    let mut ended = false;
+    for i in 0..len {
+      let merged = merge_3way_scalar(self.blocks.get(i), first.blocks.get(i), second.blocks.get(i))?;
+      match merged {
+        Some(hash) if !ended => blocks.push(*hash),
+        // A side truncated the file but the other one changed a block past its end
+        Some(_) => return None,
+        None => ended = true,
+      }
+    }
+
+    let needed_blocks = (size as usize).div_ceil(blksize);
+    if blocks.len() < needed_blocks {
+      return None
+    }
+    Some((size, blocks))
+  }
+
+  // Name for the sibling that keeps the losing side of a content conflict
+  pub fn conflict_name(&self, name: &str) -> String {
+    let date = time::at_utc(self.clock);
+    let date = date.strftime("%Y-%m-%d %H.%M.%S").unwrap();
+    format!("{} (conflict from {:016x} {})", name, self.peernum as u64, date)
+  }
+
+  // Bring back the contents and attributes of another version while keeping the
+  // node's history and place in the tree
+  pub fn restore(&mut self, version: &FSEntry) {
+    self.perm = version.perm;
+    self.uid = version.uid;
+    self.gid = version.gid;
+    self.flags = version.flags;
+    self.rdev = version.rdev;
+    self.atime = version.atime;
+    self.mtime = version.mtime;
+    self.ctime = version.ctime;
+    self.crtime = version.crtime;
+    self.chgtime = version.chgtime;
+    self.bkuptime = version.bkuptime;
+    self.size = version.size;
+    self.blocks = version.blocks.clone();
+    self.xattrs = version.xattrs.clone();
+  }
+
+  pub fn merge_3way(&self, first: &FSEntry, second: &FSEntry, blksize: usize) -> FSEntry {
     assert!(first.filetype == second.filetype);
 
-    let first_large = first.clock > second.clock || first.peernum > second.peernum;
-    let (left, right) = if first_large { (first, second) } else { (second, first) };
+    let (left, right) = if first.wins_over(second) { (first, second) } else { (second, first) };
+    // Size and blocks only make sense together so if they can't be merged the winner
+    // gets both
+    let (size, blocks) = match self.merge_blocks(left, right, blksize) {
+      Some(merged) => merged,
+      None => (left.size, left.blocks.clone()),
+    };
 
     FSEntry {
       clock: cmp::max(left.clock, right.clock),
@@ -269,6 +466,7 @@ impl FSEntry {
       peernum: cmp::max(left.peernum, right.peernum),
       filetype: left.filetype,
       perm: merge_3way!(self.perm, left.perm, right.perm),
+      nlink: merge_3way_count(self.nlink, left.nlink, right.nlink),
       uid: merge_3way!(self.uid, left.uid, right.uid),
       gid: merge_3way!(self.gid, left.gid, right.gid),
       flags: merge_3way!(self.flags, left.flags, right.flags),
@@ -279,8 +477,9 @@ impl FSEntry {
       crtime: cmp::max(left.crtime, right.crtime),
       chgtime: cmp::max(left.chgtime, right.chgtime),
       bkuptime: cmp::max(left.bkuptime, right.bkuptime),
-      size: merge_3way!(self.size, left.size, right.size),
-      blocks: merge_3way!(self.blocks, left.blocks, right.blocks),
+      size,
+      blocks,
+      parent: merge_3way!(self.parent, left.parent, right.parent),
       children: merge_3way_hash!(self.children, left.children, right.children),
       xattrs: merge_3way_hash!(self.xattrs, left.xattrs, right.xattrs),
     }
@@ -290,8 +489,16 @@ impl FSEntry {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::backingstore::testutil::*;
   extern crate bincode;
 
+  // Save a file's cached blocks and get back what ended up stored
+  fn sync_file(bs: &BackingStore, node: NodeId, file: FSEntry) -> FSEntry {
+    bs.save_node_cached(node, file).unwrap();
+    bs.sync_node(node).unwrap();
+    bs.get_node(node).unwrap()
+  }
+
   #[test]
   fn serialization_roundtrips() {
     let mut entry = FSEntry::new(FileTypeDef::Directory, 0);
@@ -305,6 +512,55 @@ mod tests {
     assert_eq!(encoded, encoded2);
   }
 
+  #[test]
+  fn nlink_counts() {
+    let mut file = FSEntry::new(FileTypeDef::RegularFile, 0);
+    assert_eq!(1, file.attrs((0,1)).nlink);
+    file.nlink += 1;
+    assert_eq!(2, file.attrs((0,1)).nlink);
+
+    let mut dir = FSEntry::new(FileTypeDef::Directory, 0);
+    assert_eq!(2, dir.attrs((0,2)).nlink);
+    dir.children.insert("file".to_string(), ((0,1), FileTypeDef::RegularFile));
+    dir.children.insert("dir1".to_string(), ((0,2), FileTypeDef::Directory));
+    dir.children.insert("dir2".to_string(), ((0,3), FileTypeDef::Directory));
+    assert_eq!(4, dir.attrs((0,2)).nlink);
+  }
+
+  #[test]
+  fn nlink_merges() {
+    let base = FSEntry::new(FileTypeDef::RegularFile, 0);
+    let mut first = base.clone();
+    first.peernum = 1;
+    first.nlink = 2;
+    let mut second = base.clone();
+    second.peernum = 2;
+    second.nlink = 2;
+    assert_eq!(3, base.merge_3way(&first, &second, BLKSIZE).nlink);
+    assert_eq!(2, base.merge_3way(&first, &base, BLKSIZE).nlink);
+
+    let mut base = base;
+    base.nlink = 3;
+    first.nlink = 2;
+    second.nlink = 4;
+    assert_eq!(3, base.merge_3way(&first, &second, BLKSIZE).nlink);
+    second.nlink = 2;
+    assert_eq!(1, base.merge_3way(&first, &second, BLKSIZE).nlink);
+  }
+
+  #[test]
+  fn stable_inodes() {
+    assert_eq!(1, inode((0,0)));
+    assert_eq!(inode((5,10)), inode((5,10)));
+    assert!(inode((5,10)) != inode((10,5)));
+    assert!(inode((0,1)) > 1);
+    let file = FSEntry::new(FileTypeDef::RegularFile, 0);
+    assert_eq!(inode((5,10)), file.attrs((5,10)).ino);
+    let mut dir = FSEntry::new(FileTypeDef::Directory, 0);
+    dir.children.insert("file".to_string(), ((5,10), FileTypeDef::RegularFile));
+    assert_eq!(inode((5,10)), dir.children()[2].ino);
+  }
+
   #[test]
   fn three_way_merge() {
     let base   = FSEntry::new(FileTypeDef::RegularFile, 0);
@@ -315,12 +571,12 @@ mod tests {
     first.perm = 10;
     first.vclock.increment(1);
     second.peernum = 2;
-    second.blocks = vec![[0;HASHSIZE]];
+    second.blocks = vec![BlobHash::from([0; HASHSIZE])];
     second.vclock.increment(2);
     second.children.insert("test".to_string(), ((0,0), FileTypeDef::RegularFile));
 
-    let merge1 = base.merge_3way(&first, &second);
-    let merge2 = base.merge_3way(&second, &first);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
 
     assert_eq!(merge1, merge2);
     assert_eq!(first.perm, merge1.perm);
@@ -335,6 +591,154 @@ mod tests {
     assert_eq!(newvclock, merge1.vclock);
   }
 
+  #[test]
+  fn content_conflict() {
+    let mut base = FSEntry::new(FileTypeDef::RegularFile, 0);
+    base.blocks = vec![BlobHash::from([0; HASHSIZE])];
+    base.size = 1;
+    let mut first = base.clone();
+    let mut second = base.clone();
+    first.peernum = 1;
+    second.peernum = 2;
+
+    // Only one side changed the contents so it's a clean merge
+    first.blocks = vec![BlobHash::from([1; HASHSIZE])];
+    assert_eq!(None, base.content_conflict(&first, &second, BLKSIZE));
+    assert_eq!(first.blocks, base.merge_3way(&first, &second, BLKSIZE).blocks);
+
+    // Both sides changed it the same way
+    second.blocks = vec![BlobHash::from([1; HASHSIZE])];
+    assert_eq!(None, base.content_conflict(&first, &second, BLKSIZE));
+
+    // Both changed it differently so the loser needs to be kept around
+    second.blocks = vec![BlobHash::from([2; HASHSIZE])];
+    second.size = 2;
+    assert_eq!(Some(&first), base.content_conflict(&first, &second, BLKSIZE));
+    assert_eq!(Some(&first), base.content_conflict(&second, &first, BLKSIZE));
+    let merged = base.merge_3way(&first, &second, BLKSIZE);
+    assert_eq!(second.blocks, merged.blocks);
+    assert_eq!(second.size, merged.size);
+  }
+
+  #[test]
+  fn block_merge() {
+    let mut base = FSEntry::new(FileTypeDef::RegularFile, 0);
+    base.blocks = vec![BlobHash::from([0; HASHSIZE]); 4];
+    base.size = (BLKSIZE * 4) as u64;
+    let mut first = base.clone();
+    let mut second = base.clone();
+    first.peernum = 1;
+    second.peernum = 2;
+
+    // Different blocks changed on each side get merged
+    first.blocks[1] = BlobHash::from([1; HASHSIZE]);
+    second.blocks[3] = BlobHash::from([2; HASHSIZE]);
+    assert_eq!(None, base.content_conflict(&first, &second, BLKSIZE));
+    let merged = base.merge_3way(&first, &second, BLKSIZE);
+    assert_eq!(merged, base.merge_3way(&second, &first, BLKSIZE));
+    assert_eq!(vec![BlobHash::from([0; HASHSIZE]), BlobHash::from([1; HASHSIZE]), BlobHash::from([0; HASHSIZE]), BlobHash::from([2; HASHSIZE])], merged.blocks);
+    assert_eq!(base.size, merged.size);
+
+    // Appending on one side and changing an existing block on the other merges too
+    first.blocks.push(BlobHash::from([3; HASHSIZE]));
+    first.size += 10;
+    assert_eq!(None, base.content_conflict(&first, &second, BLKSIZE));
+    let merged = base.merge_3way(&first, &second, BLKSIZE);
+    assert_eq!(vec![BlobHash::from([0; HASHSIZE]), BlobHash::from([1; HASHSIZE]), BlobHash::from([0; HASHSIZE]), BlobHash::from([2; HASHSIZE]), BlobHash::from([3; HASHSIZE])], merged.blocks);
+    assert_eq!(first.size, merged.size);
+
+    // Both changing the same block is a conflict
+    second.blocks[1] = BlobHash::from([4; HASHSIZE]);
+    assert!(base.content_conflict(&first, &second, BLKSIZE).is_some());
+
+    // And so are diverging sizes
+    let mut second = base.clone();
+    second.peernum = 2;
+    second.blocks[3] = BlobHash::from([2; HASHSIZE]);
+    second.size -= 10;
+    assert!(base.content_conflict(&first, &second, BLKSIZE).is_some());
+
+    // Truncating on one side while changing a block past the end on the other
+    let mut first = base.clone();
+    first.peernum = 1;
+    first.blocks.truncate(2);
+    first.size = (BLKSIZE * 2) as u64;
+    let mut second = base.clone();
+    second.peernum = 2;
+    second.blocks[3] = BlobHash::from([2; HASHSIZE]);
+    assert!(base.content_conflict(&first, &second, BLKSIZE).is_some());
+  }
+
+  #[test]
+  fn merge_winner_is_symmetric() {
+    let base = FSEntry::new(FileTypeDef::RegularFile, 0);
+    let mut first = base.clone();
+    let mut second = base.clone();
+    first.perm = 1;
+    first.peernum = 2;
+    second.perm = 2;
+    second.peernum = 1;
+    second.clock.sec += 1;
+
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
+    assert_eq!(merge1, merge2);
+    assert_eq!(second.perm, merge1.perm);
+  }
+
+  #[test]
+  fn restore_keeps_place() {
+    let mut entry = FSEntry::new(FileTypeDef::RegularFile, 0);
+    entry.parent = (1,1);
+    entry.vclock.increment(1);
+    let mut version = FSEntry::new(FileTypeDef::RegularFile, 2);
+    version.size = 10;
+    version.perm = 0o600;
+    version.blocks = vec![BlobHash::from([1; HASHSIZE])];
+    version.xattrs.insert("foo".to_string(), vec![0]);
+
+    let vclock = entry.vclock.clone();
+    entry.restore(&version);
+    assert_eq!(version.size, entry.size);
+    assert_eq!(version.perm, entry.perm);
+    assert_eq!(version.blocks, entry.blocks);
+    assert_eq!(version.xattrs, entry.xattrs);
+    assert_eq!((1,1), entry.parent);
+    assert_eq!(0, entry.peernum);
+    assert_eq!(vclock, entry.vclock);
+  }
+
+  #[test]
+  fn conflict_names() {
+    let mut entry = FSEntry::new(FileTypeDef::RegularFile, 0);
+    entry.peernum = 255;
+    entry.clock = Timespec::new(0, 0);
+    assert_eq!("foo.txt (conflict from 00000000000000ff 1970-01-01 00.00.00)", entry.conflict_name("foo.txt"));
+  }
+
+  #[test]
+  fn parent_merge() {
+    let base   = FSEntry::new(FileTypeDef::RegularFile, 0);
+    let mut first  = FSEntry::new(FileTypeDef::RegularFile, 0);
+    let mut second = FSEntry::new(FileTypeDef::RegularFile, 0);
+
+    // Only one side moved the node so that's where it ends up
+    first.parent = (1,1);
+    first.peernum = 1;
+    second.peernum = 2;
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
+    assert_eq!(merge1, merge2);
+    assert_eq!((1,1), merge1.parent);
+
+    // Both sides moved it so the same one needs to win on all peers
+    second.parent = (2,2);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
+    assert_eq!(merge1, merge2);
+    assert_eq!((2,2), merge1.parent);
+  }
+
   #[test]
   fn children_merge() {
     let base   = FSEntry::new(FileTypeDef::RegularFile, 0);
@@ -344,8 +748,8 @@ mod tests {
     first.children.insert("foo".to_string(), ((1,1), FileTypeDef::RegularFile));
     second.children.insert("bar".to_string(), ((2,2), FileTypeDef::RegularFile));
 
-    let merge1 = base.merge_3way(&first, &second);
-    let merge2 = base.merge_3way(&second, &first);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
     assert_eq!(merge1, merge2);
 
     let mut result = base.clone();
@@ -368,8 +772,8 @@ mod tests {
     let mut second = FSEntry::new(FileTypeDef::RegularFile, 0);
     second.children.insert("foo".to_string(), ((1,1), FileTypeDef::RegularFile));
 
-    let merge1 = base.merge_3way(&first, &second);
-    let merge2 = base.merge_3way(&second, &first);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
     assert_eq!(merge1, merge2);
 
     assert_eq!(second.children, merge1.children);
@@ -384,8 +788,8 @@ mod tests {
     first.xattrs.insert("foo".to_string(), vec![0]);
     second.xattrs.insert("bar".to_string(), vec![0]);
 
-    let merge1 = base.merge_3way(&first, &second);
-    let merge2 = base.merge_3way(&second, &first);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
     assert_eq!(merge1, merge2);
 
     let mut result = base.clone();
@@ -408,10 +812,125 @@ mod tests {
     let mut second = FSEntry::new(FileTypeDef::RegularFile, 0);
     second.xattrs.insert("foo".to_string(), vec![0]);
 
-    let merge1 = base.merge_3way(&first, &second);
-    let merge2 = base.merge_3way(&second, &first);
+    let merge1 = base.merge_3way(&first, &second, BLKSIZE);
+    let merge2 = base.merge_3way(&second, &first, BLKSIZE);
     assert_eq!(merge1, merge2);
 
     assert_eq!(second.xattrs, merge1.xattrs);
   }
+
+  #[test]
+  fn holes() {
+    let (bs, _dir) = test_store("holes");
+    let node = (0, 1);
+    let blksize = bs.blksize() as u64;
+    let sync = |file| sync_file(&bs, node, file);
+
+    // Growing a file leaves holes that read as zeros
+    let mut file = FSEntry::new(FileTypeDef::RegularFile, 0);
+    file.write(node, &bs, 2*blksize+10, b"data").unwrap();
+    assert_eq!(vec![HASHZERO; 3], file.blocks);
+    assert_eq!(vec![0; 20], file.read(node, &bs, blksize-10, 20).unwrap());
+    assert_eq!(Ok(0), file.seek(node, &bs, 0, false));
+    assert_eq!(Ok(2*blksize), file.seek(node, &bs, 0, true));
+    let mut file = sync(file);
+    assert_eq!(vec![HASHZERO; 2], file.blocks[..2].to_vec());
+    assert!(file.blocks[2] != HASHZERO);
+    assert_eq!(b"\0data".to_vec(), file.read(node, &bs, 2*blksize+9, 10).unwrap());
+    assert_eq!(Ok(2*blksize+5), file.seek(node, &bs, 2*blksize+5, true));
+    assert_eq!(Ok(2*blksize+14), file.seek(node, &bs, 2*blksize, false));
+    assert_eq!(Err(libc::ENXIO), file.seek(node, &bs, 2*blksize+14, true));
+
+    // Writing zeros over a whole block also makes it a hole
+    file.write(node, &bs, 0, &vec![0; blksize as usize]).unwrap();
+    file.write(node, &bs, blksize, b"more").unwrap();
+    let mut file = sync(file);
+    assert_eq!(HASHZERO, file.blocks[0]);
+    assert!(file.blocks[1] != HASHZERO);
+
+    file.punch_hole(node, &bs, 2*blksize+11, 2).unwrap();
+    assert_eq!(b"d\0\0a".to_vec(), file.read(node, &bs, 2*blksize+10, 4).unwrap());
+    file.punch_hole(node, &bs, blksize, blksize).unwrap();
+    assert_eq!(HASHZERO, file.blocks[1]);
+    file.punch_hole(node, &bs, 0, u64::MAX).unwrap();
+    let file = sync(file);
+    assert_eq!(2*blksize+14, file.size);
+    assert_eq!(vec![HASHZERO; 3], file.blocks);
+    assert_eq!(Err(libc::ENXIO), file.seek(node, &bs, 0, true));
+    assert_eq!(vec![0; 14], file.read(node, &bs, 2*blksize, 100).unwrap());
+  }
+
+  #[test]
+  fn truncation() {
+    let (bs, _dir) = test_store("truncate");
+    let node = (0, 1);
+    let blksize = bs.blksize() as u64;
+    let sync = |file| sync_file(&bs, node, file);
+
+    // Shrinking and growing again within a block reads back zeros
+    let mut file = FSEntry::new(FileTypeDef::RegularFile, 0);
+    file.write(node, &bs, 0, b"hello world").unwrap();
+    file.truncate(node, &bs, 5).unwrap();
+    assert_eq!(b"hello".to_vec(), file.read(node, &bs, 0, 100).unwrap());
+    file.truncate(node, &bs, 11).unwrap();
+    assert_eq!(b"hello\0\0\0\0\0\0".to_vec(), file.read(node, &bs, 0, 100).unwrap());
+    let mut file = sync(file);
+    assert_eq!(b"hello\0\0\0\0\0\0".to_vec(), file.read(node, &bs, 0, 100).unwrap());
+
+    // Shrinking drops the blocks past the end, including ones not saved yet
+    file.write(node, &bs, 0, &vec![1; 2*blksize as usize + 10]).unwrap();
+    let mut file = sync(file);
+    file.write(node, &bs, 2*blksize, b"dirty").unwrap();
+    file.truncate(node, &bs, blksize+3).unwrap();
+    assert_eq!(2, file.blocks.len());
+    assert!(!bs.is_cached(node, 2));
+    let mut file = sync(file);
+    assert_eq!(blksize+3, file.size);
+    assert_eq!(vec![1; 3], file.read(node, &bs, blksize, 100).unwrap());
+    // The last block is cut short rather than padded with zeros
+    let last = file.blocks[1];
+    assert_eq!(3, bs.read(node, 1, &last, 0, blksize as usize, &[]).unwrap().len());
+
+    // Growing adds holes and leaves the existing blocks alone
+    file.truncate(node, &bs, 3*blksize).unwrap();
+    assert_eq!(vec![HASHZERO; 1], file.blocks[2..].to_vec());
+    assert_eq!(last, file.blocks[1]);
+    assert!(bs.is_hole(node, 1, &HASHZERO));
+    let file = sync(file);
+    let mut expected = vec![1; 3];
+    expected.resize(2*blksize as usize, 0);
+    assert_eq!(expected, file.read(node, &bs, blksize, 2*blksize as u32).unwrap());
+    assert_eq!(Ok(2*blksize), file.seek(node, &bs, blksize, false));
+
+    let mut file = file;
+    file.truncate(node, &bs, 0).unwrap();
+    let file = sync(file);
+    assert_eq!(0, file.size);
+    assert!(file.blocks.is_empty());
+    assert_eq!(Vec::<u8>::new(), file.read(node, &bs, 0, 100).unwrap());
+  }
+
+  #[test]
+  fn truncate_replays() {
+    let (bs, dir) = test_store("truncate-replay");
+    let node = (0, 1);
+    bs.start_journal().unwrap();
+    let mut file = FSEntry::new(FileTypeDef::RegularFile, 0);
+    file.write(node, &bs, 0, b"hello world").unwrap();
+    let mut file = sync_file(&bs, node, file);
+
+    // A truncate that was only journaled comes back after a crash
+    file.truncate(node, &bs, 5).unwrap();
+    bs.save_node_cached(node, file).unwrap();
+    bs.sync_journal().unwrap();
+    drop(bs);
+    let bs = BackingStore::new(dir.path(), &test_config()).unwrap();
+    bs.start_journal().unwrap();
+    let file = bs.get_node(node).unwrap();
+    assert_eq!(5, file.size);
+    assert_eq!(5, bs.read(node, 0, &file.blocks[0], 0, 100, &[]).unwrap().len());
+    let mut file = file;
+    file.truncate(node, &bs, 11).unwrap();
+    assert_eq!(b"hello\0\0\0\0\0\0".to_vec(), file.read(node, &bs, 0, 100).unwrap());
+  }
 }
@@ -0,0 +1,183 @@
+extern crate libc;
+use self::libc::c_int;
+
+use std::collections::HashMap;
+use std::sync::{Mutex, Condvar};
+
+use crate::backingstore::NodeId;
+
+// A byte range lock on a file as FUSE hands them to us. The end is inclusive and
+// u64::MAX means up to the end of the file whatever its size. flock() locks arrive as
+// locks over the whole file owned by the open file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileLock {
+  pub owner: u64,
+  pub start: u64,
+  pub end: u64,
+  pub write: bool,
+  pub pid: u32,
+}
+
+impl FileLock {
+  fn overlaps(&self, start: u64, end: u64) -> bool {
+    self.start <= end && start <= self.end
+  }
+
+  // Other owners can share a range as long as none of them want to write to it
+  fn conflicts(&self, other: &FileLock) -> bool {
+    self.owner != other.owner && (self.write || other.write) && self.overlaps(other.start, other.end)
+  }
+}
+
+// The advisory locks taken on each node. These only cover this mount, other peers
+// don't see them.
+pub struct LockTable {
+  locks: Mutex<HashMap<NodeId, Vec<FileLock>>>,
+  unlocked: Condvar,
+}
+
+impl LockTable {
+  pub fn new() -> Self {
+    Self {
+      locks: Mutex::new(HashMap::new()),
+      unlocked: Condvar::new(),
+    }
+  }
+
+  // The first lock held by someone else that would keep this one from being taken
+  pub fn conflict(&self, node: NodeId, lock: &FileLock) -> Option<FileLock> {
+    let locks = self.locks.lock().unwrap();
+    locks.get(&node).and_then(|l| l.iter().find(|l| l.conflicts(lock)).cloned())
+  }
+
+  // Take a lock, replacing whatever the owner already had over that range. Waits for
+  // the conflicting locks to go away if asked to, otherwise fails with EAGAIN.
+  pub fn lock(&self, node: NodeId, lock: FileLock, wait: bool) -> Result<(), c_int> {
+    let mut locks = self.locks.lock().unwrap();
+    loop {
+      let held = locks.entry(node).or_default();
+      if !held.iter().any(|l| l.conflicts(&lock)) {
+        Self::remove_range(held, lock.owner, lock.start, lock.end);
+        held.push(lock);
+        return Ok(())
+      }
+      if !wait {
+        return Err(libc::EAGAIN)
+      }
+      locks = self.unlocked.wait(locks).unwrap();
+    }
+  }
+
+  pub fn unlock(&self, node: NodeId, owner: u64, start: u64, end: u64) {
+    let mut locks = self.locks.lock().unwrap();
+    if let Some(held) = locks.get_mut(&node) {
+      Self::remove_range(held, owner, start, end);
+      if held.is_empty() {
+        locks.remove(&node);
+      }
+    }
+    self.unlocked.notify_all();
+  }
+
+  // Everything an owner holds goes away when it closes the file
+  pub fn unlock_owner(&self, node: NodeId, owner: u64) {
+    self.unlock(node, owner, 0, u64::MAX)
+  }
+
+  // Cut a range out of an owner's locks, splitting the ones that stick out of it
+  fn remove_range(held: &mut Vec<FileLock>, owner: u64, start: u64, end: u64) {
+    let mut kept = Vec::new();
+    for lock in held.drain(..) {
+      if lock.owner != owner || !lock.overlaps(start, end) {
+        kept.push(lock);
+        continue
+      }
+      if lock.start < start {
+        kept.push(FileLock { end: start - 1, ..lock });
+      }
+      if lock.end > end {
+        kept.push(FileLock { start: end + 1, ..lock });
+      }
+    }
+    *held = kept;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use std::thread;
+
+  fn lock(owner: u64, start: u64, end: u64, write: bool) -> FileLock {
+    FileLock { owner, start, end, write, pid: owner as u32 }
+  }
+
+  #[test]
+  fn conflicts() {
+    let table = LockTable::new();
+    let node = (0, 1);
+    table.lock(node, lock(1, 0, 99, false), false).unwrap();
+    table.lock(node, lock(2, 50, 149, false), false).unwrap();
+    assert_eq!(Err(libc::EAGAIN), table.lock(node, lock(3, 0, 9, true), false));
+    assert_eq!(Some(lock(1, 0, 99, false)), table.conflict(node, &lock(3, 0, 9, true)));
+    assert_eq!(None, table.conflict(node, &lock(3, 150, u64::MAX, true)));
+    assert_eq!(None, table.conflict((0, 2), &lock(3, 0, 9, true)));
+
+    // An owner's own locks never conflict and get replaced over the new range
+    table.lock(node, lock(1, 10, 19, true), false).unwrap();
+    assert_eq!(Some(lock(1, 10, 19, true)), table.conflict(node, &lock(3, 15, 15, false)));
+    assert_eq!(None, table.conflict(node, &lock(3, 5, 9, false)));
+
+    // Unlocking the middle of a range leaves both ends locked
+    table.unlock(node, 1, 40, 59);
+    assert_eq!(None, table.conflict(node, &lock(3, 40, 49, true)));
+    assert!(table.conflict(node, &lock(3, 60, 60, true)).is_some());
+    assert!(table.conflict(node, &lock(3, 39, 39, true)).is_some());
+
+    table.unlock_owner(node, 1);
+    table.unlock_owner(node, 2);
+    assert_eq!(None, table.conflict(node, &lock(3, 0, u64::MAX, true)));
+  }
+
+  #[test]
+  fn conflicting_ranges() {
+    let table = LockTable::new();
+    let node = (0, 1);
+    table.lock(node, lock(1, 100, 199, true), false).unwrap();
+
+    // Ends are inclusive so touching ranges don't overlap but a shared byte does
+    assert_eq!(None, table.conflict(node, &lock(2, 0, 99, true)));
+    assert_eq!(None, table.conflict(node, &lock(2, 200, 299, true)));
+    assert!(table.conflict(node, &lock(2, 0, 100, false)).is_some());
+    assert!(table.conflict(node, &lock(2, 199, 199, false)).is_some());
+    assert!(table.conflict(node, &lock(2, 150, u64::MAX, false)).is_some());
+    assert!(table.conflict(node, &lock(2, 0, u64::MAX, false)).is_some());
+
+    // Readers share a range but a writer can't join them, not even one of the readers
+    table.unlock_owner(node, 1);
+    table.lock(node, lock(1, 0, 199, false), false).unwrap();
+    table.lock(node, lock(2, 100, u64::MAX, false), false).unwrap();
+    assert_eq!(Err(libc::EAGAIN), table.lock(node, lock(1, 150, 150, true), false));
+    assert_eq!(Err(libc::EAGAIN), table.lock(node, lock(3, 500, 500, true), false));
+    table.lock(node, lock(1, 0, 99, true), false).unwrap();
+
+    // The failed upgrade left the read lock in place
+    assert_eq!(Some(lock(1, 100, 199, false)), table.conflict(node, &lock(3, 150, 150, true)));
+    assert_eq!(Some(lock(1, 0, 99, true)), table.conflict(node, &lock(3, 50, 50, false)));
+  }
+
+  #[test]
+  fn waits_for_unlock() {
+    let table = Arc::new(LockTable::new());
+    let node = (0, 1);
+    table.lock(node, lock(1, 0, u64::MAX, true), false).unwrap();
+    let waiter = {
+      let table = table.clone();
+      thread::spawn(move || table.lock(node, lock(2, 0, 0, true), true))
+    };
+    table.unlock_owner(node, 1);
+    assert_eq!(Ok(()), waiter.join().unwrap());
+    assert!(table.conflict(node, &lock(1, 0, 0, false)).is_some());
+  }
+}
@@ -6,10 +6,12 @@ extern crate users;
 extern crate time;
 use self::time::Timespec;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::sync::Mutex;
+extern crate hex;
+use std::collections::{HashSet, HashMap};
 
 use crate::backingstore::*;
 use crate::settings::*;
@@ -19,6 +21,30 @@ mod entry;
 pub use self::entry::*;
 mod vclock;
 pub use self::vclock::*;
+mod versions;
+mod locks;
+use self::locks::*;
+
+// Where fsck puts the nodes it finds that aren't in any directory
+const LOST_FOUND: &str = "lost+found";
+
+// Virtual xattr that exposes the stable inode number of a node
+const INODE_XATTR: &str = "user.syncer.inode";
+
+// Tell the kernel it can keep the pages it has cached for the file when opening it
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+fn ttl() -> Timespec {
+  Timespec::new(ATTR_TTL, 0)
+}
+
+// Which side to keep when resolving a conflict
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKeep {
+  Mine,
+  Theirs,
+  Both,
+}
 
 struct Handle {
   node: NodeId,
@@ -30,6 +56,7 @@ pub struct FS<'a> {
   backing: &'a BackingStore,
   handles: RwHashes<u64,Handle>,
   handle_counter: Mutex<u64>,
+  locks: LockTable,
 }
 
 impl<'a> FS<'a> {
@@ -39,6 +66,7 @@ impl<'a> FS<'a> {
       backing: bs,
       handles: RwHashes::new(8),
       handle_counter: Mutex::new(0),
+      locks: LockTable::new(),
     };
 
     // Add a root node as 0 if it doesn't exist
@@ -110,6 +138,7 @@ impl<'a> FS<'a> {
 
   fn modify_node<F,T>(&self, node: NodeId, cache: bool, closure: &F) -> Result<T, c_int>
     where F : Fn(&mut FSEntry, NodeId) -> T {
+    let _lock = self.backing.lock_node(node);
     let mut entry = self.backing.get_node(node)?;
     let res = closure(&mut entry, node);
     entry.clock = self::time::get_time();
@@ -123,7 +152,154 @@ impl<'a> FS<'a> {
     Ok(res)
   }
 
-  fn find_node(&self, path: &Path) -> Result<NodeId, c_int> {
+  // Walk back up the tree through the parents to find where a node lives
+  pub fn node_path(&self, node: NodeId) -> Result<PathBuf, c_int> {
+    let mut names = Vec::new();
+    let mut curr = node;
+    while curr != (0,0) {
+      let entry = self.backing.get_node(curr)?;
+      let parent = self.backing.get_node(entry.parent)?;
+      match parent.children.iter().find(|(_, c)| c.0 == curr) {
+        Some((name, _)) => names.push(name.clone()),
+        None => return Err(libc::ENOENT),
+      }
+      if names.len() > 4096 { return Err(libc::ELOOP) }
+      curr = entry.parent;
+    }
+    let mut path = PathBuf::from("/");
+    for name in names.iter().rev() {
+      path.push(name);
+    }
+    Ok(path)
+  }
+
+  pub fn resolve_conflict(&self, conflict: &ConflictInfo, keep: ConflictKeep) -> Result<(), c_int> {
+    let local = self.backing.fetch_entry(&conflict.local)?;
+    let remote = self.backing.fetch_entry(&conflict.remote)?;
+    let version = match keep {
+      ConflictKeep::Mine => local,
+      ConflictKeep::Theirs => remote,
+      ConflictKeep::Both => {
+        if conflict.copy.is_none() {
+          // We didn't keep a copy ourselves so make one of whichever side didn't win
+          let current = self.backing.get_node(conflict.node)?;
+          let merged = self.backing.fetch_entry(&conflict.merged)?;
+          let loser = if (local.size, &local.blocks) == (merged.size, &merged.blocks) { remote } else { local };
+          self.backing.create_conflict_copy(conflict.node, &current, &loser)?;
+        }
+        return Ok(self.backing.mark_resolved(conflict)?)
+      },
+    };
+
+    self.modify_node(conflict.node, false, &(|entry, _| entry.restore(&version)))?;
+    // The copy is now redundant
+    if let Some(copy) = conflict.copy {
+      if self.backing.node_exists(copy)? {
+        let parent = self.backing.get_node(copy)?.parent;
+        self.modify_node(parent, false, &(|dir, _| dir.children.retain(|_, c| c.0 != copy)))?;
+      }
+    }
+    Ok(self.backing.mark_resolved(conflict)?)
+  }
+
+  // Walk the tree from the root checking that every child and block can be found.
+  // Nodes that can't be reached because the directory they were in is gone or can't
+  // be read get linked into lost+found when repairing.
+  pub fn fsck(&self, repair: bool, report: &mut FsckReport) -> Result<(), c_int> {
+    let root = (0, 0);
+    let mut seen = HashSet::new();
+    let mut unreadable = HashSet::new();
+    let mut queue = vec![root];
+    seen.insert(root);
+    while let Some(node) = queue.pop() {
+      report.nodes += 1;
+      let entry = match self.backing.get_node(node) {
+        Ok(e) => e,
+        Err(_) => {
+          unreadable.insert(node);
+          report.issue(format!("node {:?} can't be read", node), false);
+          continue
+        },
+      };
+      self.fsck_blocks(node, &entry, report);
+
+      let mut dangling = Vec::new();
+      for (name, (child, _)) in entry.children.iter() {
+        if !self.backing.node_exists(*child)? {
+          dangling.push(name.clone());
+        } else if seen.insert(*child) {
+          queue.push(*child);
+        }
+      }
+      for name in dangling {
+        if repair {
+          self.modify_node(node, false, &(|dir, _| dir.children.remove(&name)))?;
+        }
+        report.issue(format!("{:?} in directory {:?} points to a missing node", name, node), repair);
+      }
+    }
+
+    // Deleted nodes are still around but their parent just doesn't list them anymore.
+    // The ones whose parent is missing or unreadable were lost, as were the nodes under
+    // them, but only the top of each of those subtrees needs to be relinked.
+    let mut unreachable = HashMap::new();
+    for node in self.backing.latest_node_ids()? {
+      if seen.contains(&node) { continue }
+      if let Ok(entry) = self.backing.get_node(node) {
+        unreachable.insert(node, entry);
+      }
+    }
+    let mut lost = Vec::new();
+    for (node, entry) in unreachable.iter() {
+      if unreadable.contains(&entry.parent) || !self.backing.node_exists(entry.parent)? {
+        lost.push((*node, entry.filetype));
+      }
+    }
+    lost.sort_by_key(|l| l.0);
+    if lost.is_empty() {
+      return Ok(())
+    }
+
+    let lostfound = if repair { Some(self.lost_found()?) } else { None };
+    for (node, filetype) in lost {
+      let name = format!("{:016x}-{}", node.0 as u64, node.1);
+      if let Some(dir) = lostfound {
+        self.modify_node(dir, false, &(|dir, _| dir.add_child(OsStr::new(&name), (node, filetype))))??;
+        self.modify_node(node, false, &(|entry, _| entry.parent = dir))?;
+      }
+      report.issue(format!("node {:?} isn't in any directory, relinked as {}/{}", node, LOST_FOUND, name), repair);
+    }
+    Ok(())
+  }
+
+  // Blocks that are neither here nor on the server were lost before they were uploaded
+  // and there's nowhere left to get them from. The ones that should be here but aren't
+  // get fetched again by BlobStorage::fsck_blobs.
+  fn fsck_blocks(&self, node: NodeId, entry: &FSEntry, report: &mut FsckReport) {
+    let mut checked = HashSet::new();
+    for hash in entry.get_blocks() {
+      if !checked.insert(*hash) { continue }
+      report.blocks += 1;
+      if self.backing.blob_available(hash) { continue }
+      report.issue(format!("block {} of node {:?} was lost before it was uploaded",
+                           hash, node), false);
+    }
+  }
+
+  fn lost_found(&self) -> Result<NodeId, c_int> {
+    let root = (0, 0);
+    if let Some(&(node, _)) = self.backing.get_node(root)?.children.get(LOST_FOUND) {
+      return Ok(node)
+    }
+    let mut entry = FSEntry::new(FileTypeDef::Directory, self.peernum);
+    entry.perm = 0o700;
+    entry.parent = root;
+    let node = self.backing.create_node(entry)?;
+    self.modify_node(root, false, &(|dir, _| dir.add_child(OsStr::new(LOST_FOUND), (node, FileTypeDef::Directory))))??;
+    Ok(node)
+  }
+
+  pub fn find_node(&self, path: &Path) -> Result<NodeId, c_int> {
     let mut nodenum = (0, 0); // Start with the root node
     let mut iterator = path.iter();
     iterator.next(); // Skip the root as that's already nodenum 0
@@ -137,6 +313,38 @@ impl<'a> FS<'a> {
     Ok(nodenum)
   }
 
+  // A name for node was just removed from dir. Only hardlinked nodes need fixing, the
+  // others are now orphaned anyway.
+  fn drop_link(&self, node: NodeId, dir: NodeId) -> Result<(), c_int> {
+    let (nlink, parent) = self.with_node(node, &(|entry, _| (entry.nlink, entry.parent)))?;
+    if nlink <= 1 { return Ok(()) }
+    // The node has to stay where its parent says even if that was the name that went
+    let mut newparent = parent;
+    if parent == dir && !self.backing.get_node(dir)?.children.values().any(|c| c.0 == node) {
+      if let Some(other) = self.find_parent(node)? {
+        newparent = other;
+      }
+    }
+    self.modify_node(node, false, &(|entry, _| {
+      entry.nlink -= 1;
+      entry.parent = newparent;
+    }))?;
+    Ok(())
+  }
+
+  // There's no index from nodes to the directories that link them so this goes through
+  // all of them. It's only needed when the original name of a hardlink is removed.
+  fn find_parent(&self, node: NodeId) -> Result<Option<NodeId>, c_int> {
+    for dir in self.backing.latest_node_ids()? {
+      if let Ok(entry) = self.backing.get_node(dir) {
+        if entry.filetype == FileTypeDef::Directory && entry.children.values().any(|c| c.0 == node) {
+          return Ok(Some(dir))
+        }
+      }
+    }
+    Ok(None)
+  }
+
   fn create_handle(&self, handle: Handle) -> u64 {
     let count = {
       let mut counter = self.handle_counter.lock().unwrap();
@@ -172,21 +380,35 @@ impl<'a> FilesystemMT for FS<'a> {
   fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
     let node = self.find_node(path)?;
     let handle = self.create_handle(Handle{node: node, _flags: flags,});
-    Ok((handle, flags))
+    // Without a way to invalidate the kernel's cache when another peer changes a file
+    // the best we can do is drop it the next time the file is opened
+    let openflags = if self.backing.take_remote_change(node) { 0 } else { FOPEN_KEEP_CACHE };
+    Ok((handle, openflags))
+  }
+
+  fn opendir(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+    let node = self.find_node(path)?;
+    let handle = self.create_handle(Handle{node, _flags: flags,});
+    Ok((handle, 0))
   }
 
-  fn opendir(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-    self.open(req, path, flags)
+  // Closing any descriptor drops the POSIX locks its process holds on the file
+  fn flush(&self, _req: RequestInfo, _path: &Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+    let node = self.with_handle(fh, &(|_, node| node))?;
+    self.locks.unlock_owner(node, lock_owner);
+    Ok(())
   }
 
-  fn release(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+  fn release(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32, lock_owner: u64, _flush: bool) -> ResultEmpty {
+    // flock() locks belong to the open file and last until it's gone
+    let node = self.with_handle(fh, &(|_, node| node))?;
+    self.locks.unlock_owner(node, lock_owner);
     self.delete_handle(fh)
   }
 
   fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
-    let attrs = self.with_path_optional_handle(path, fh, &(|entry, _| entry.attrs()))?;
-    let time = time::get_time();
-    Ok((time, attrs))
+    let attrs = self.with_path_optional_handle(path, fh, &(|entry, node| entry.attrs(node)))?;
+    Ok((ttl(), attrs))
   }
 
   fn readdir(&self, _req: RequestInfo, _path: &Path, fh: u64) -> ResultReaddir {
@@ -227,18 +449,18 @@ impl<'a> FilesystemMT for FS<'a> {
     let entry = self.with_node(node, &(|parent, _| {
       let mut e = FSEntry::new(FileTypeDef::RegularFile, self.peernum);
       e.perm = mode;
+      e.parent = node;
       e.gid = parent.gid;
       e.uid = parent.uid;
       e
     }))?;
-    let mut created_entry = CreatedEntry {
-      ttl: entry.ctime,
-      attr: entry.attrs(),
-      fh: 0,
+    let newnode = self.backing.create_node(entry.clone())?;
+    let created_entry = CreatedEntry {
+      ttl: ttl(),
+      attr: entry.attrs(newnode),
+      fh: self.create_handle(Handle{node: newnode, _flags: flags,}),
       flags: entry.flags,
     };
-    let newnode = self.backing.create_node(entry)?;
-    created_entry.fh = self.create_handle(Handle{node: newnode, _flags: flags,});
     self.modify_node(node, false, &(|parent, _| parent.add_child(name, (newnode, FileTypeDef::RegularFile))))??;
     Ok(created_entry)
   }
@@ -248,12 +470,13 @@ impl<'a> FilesystemMT for FS<'a> {
     let entry = self.with_node(node, &(|parent, _| {
       let mut e = FSEntry::new(FileTypeDef::Directory, self.peernum);
       e.perm = mode;
+      e.parent = node;
       e.gid = parent.gid;
       e.uid = parent.uid;
       e
     }))?;
-    let created_dir = (entry.ctime, entry.attrs());
-    let newnode = self.backing.create_node(entry)?;
+    let newnode = self.backing.create_node(entry.clone())?;
+    let created_dir = (ttl(), entry.attrs(newnode));
     self.modify_node(node, false, &(|parent, _| parent.add_child(name, (newnode, FileTypeDef::Directory))))??;
     Ok(created_dir)
   }
@@ -264,6 +487,7 @@ impl<'a> FilesystemMT for FS<'a> {
     let blob = self.backing.add_blob(&data)?;
     let entry = self.with_node(node, &(|parent, _| {
       let mut e = FSEntry::new(FileTypeDef::Symlink, self.peernum);
+      e.parent = node;
       e.blocks = vec![blob];
       e.perm = 0o777;
       e.size = data.len() as u64;
@@ -271,8 +495,8 @@ impl<'a> FilesystemMT for FS<'a> {
       e.uid = parent.uid;
       e
     }))?;
-    let created_symlink = (entry.ctime, entry.attrs());
-    let newnode = self.backing.create_node(entry)?;
+    let newnode = self.backing.create_node(entry.clone())?;
+    let created_symlink = (ttl(), entry.attrs(newnode));
     self.modify_node(node, false, &(|parent, _| parent.add_child(name, (newnode, FileTypeDef::Symlink))))??;
     Ok(created_symlink)
   }
@@ -280,20 +504,24 @@ impl<'a> FilesystemMT for FS<'a> {
   fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
     let childnode = self.find_node(path)?;
     let dirnode = self.find_node(newparent)?;
-    let childnodeinfo = self.with_node(childnode, &(|entry, _| {
-      ((entry.ctime, entry.attrs()), entry.filetype)
+    let childnodeinfo = self.modify_node(childnode, false, &(|entry, node| {
+      entry.nlink += 1;
+      ((ttl(), entry.attrs(node)), entry.filetype)
     }))?;
     self.modify_node(dirnode, false, &(|parent, _| parent.add_child(newname, (childnode, childnodeinfo.1))))??;
     Ok(childnodeinfo.0)
   }
 
   fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
-    self.modify_path_optional_handle(path, fh, &(|entry, _| {
-      entry.size = size;
-    }))
+    self.backing.throttle_writes()?;
+    let node = self.with_path_optional_handle(path, fh, &(|_, node| node))?;
+    // The cut block and the new size both go in the journal so they come back together
+    self.modify_node(node, true, &(|entry, node| entry.truncate(node, self.backing, size)))??;
+    Ok(())
   }
 
   fn write(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+    self.backing.throttle_writes()?;
     self.modify_handle(fh, true, &(|entry, node| entry.write(node, &self.backing, offset, &data)))?
   }
 
@@ -301,8 +529,52 @@ impl<'a> FilesystemMT for FS<'a> {
     self.with_handle(fh, &(|entry, node| entry.read(node, &self.backing, offset, size)))?
   }
 
+  fn fallocate(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, length: u64, mode: i32) -> ResultEmpty {
+    // Space is never reserved ahead of time so punching holes is all there is to do
+    if mode != libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE {
+      return Err(libc::EOPNOTSUPP)
+    }
+    self.backing.throttle_writes()?;
+    self.modify_handle(fh, true, &(|entry, node| entry.punch_hole(node, self.backing, offset, length)))?
+  }
+
+  fn lseek(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, whence: i32) -> ResultLseek {
+    let data = match whence {
+      libc::SEEK_DATA => true,
+      libc::SEEK_HOLE => false,
+      _ => return Err(libc::EINVAL),
+    };
+    self.with_handle(fh, &(|entry, node| entry.seek(node, self.backing, offset, data)))?
+  }
+
+  fn getlk(&self, _req: RequestInfo, _path: &Path, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> ResultLock {
+    let node = self.with_handle(fh, &(|_, node| node))?;
+    let lock = FileLock { owner: lock_owner, start, end, write: typ == libc::F_WRLCK as u32, pid };
+    Ok(match self.locks.conflict(node, &lock) {
+      Some(held) => {
+        let typ = if held.write { libc::F_WRLCK } else { libc::F_RDLCK };
+        LockInfo { start: held.start, end: held.end, typ: typ as u32, pid: held.pid }
+      },
+      None => LockInfo { start, end, typ: libc::F_UNLCK as u32, pid },
+    })
+  }
+
+  fn setlk(&self, _req: RequestInfo, _path: &Path, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> ResultEmpty {
+    let node = self.with_handle(fh, &(|_, node| node))?;
+    let write = match typ as c_int {
+      libc::F_UNLCK => {
+        self.locks.unlock(node, lock_owner, start, end);
+        return Ok(())
+      },
+      libc::F_RDLCK => false,
+      libc::F_WRLCK => true,
+      _ => return Err(libc::EINVAL),
+    };
+    self.locks.lock(node, FileLock { owner: lock_owner, start, end, write, pid }, sleep)
+  }
+
   fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
-    self.with_path(path, &(|entry, node| entry.read(node, &self.backing, 0, BLKSIZE as u32)))?
+    self.with_path(path, &(|entry, node| entry.read(node, &self.backing, 0, self.backing.blksize() as u32)))?
   }
 
   fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
@@ -320,13 +592,23 @@ impl<'a> FilesystemMT for FS<'a> {
   }
 
   fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
-    self.modify_path(parent, &(|parent, _| parent.remove_child(name)))??;
-    Ok(())
+    let dir = self.find_node(parent)?;
+    let (node, _) = self.modify_node(dir, false, &(|parent, _| parent.remove_child(name)))??;
+    self.drop_link(node, dir)
   }
 
   fn rename(&self, _req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
     let node = self.modify_path(parent, &(|parent, _| parent.remove_child(name)))??;
-    self.modify_path(newparent, &(|newparent, _| newparent.add_child(newname, node)))??;
+    let newparentnode = self.find_node(newparent)?;
+    let replaced = self.modify_node(newparentnode, false, &(|newparent, _| newparent.add_child(newname, node)))??;
+    if parent != newparent {
+      self.modify_node(node.0, false, &(|entry, _| entry.parent = newparentnode))?;
+    }
+    if let Some((oldnode, _)) = replaced {
+      if oldnode != node.0 {
+        self.drop_link(oldnode, newparentnode)?;
+      }
+    }
     Ok(())
   }
 
@@ -344,9 +626,12 @@ impl<'a> FilesystemMT for FS<'a> {
   }
 
   fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
-    self.with_path(path, &|entry, _| {
+    self.with_path(path, &|entry, node| {
       let attrname = from_os_str(name)?;
-      if let Some(value) = entry.xattrs.get(&attrname) {
+      // The same number st_ino has, for scripts that want it without a stat
+      let inodeval = inode(node).to_string().into_bytes();
+      let value = if attrname == INODE_XATTR { Some(&inodeval) } else { entry.xattrs.get(&attrname) };
+      if let Some(value) = value {
         if size == 0 {
           Ok(Xattr::Size(value.len() as u32))
         } else {
@@ -430,3 +715,169 @@ impl<'a> FilesystemMT for FS<'a> {
     })?
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backingstore::testutil::*;
+
+  #[test]
+  fn fsck_repairs() {
+    let (bs, _dir) = test_store("fsck");
+    let fs = FS::new(&bs, 0).unwrap();
+    let root = (0, 0);
+
+    // A file whose directory is gone, a name that points nowhere and a file with a
+    // block that never made it anywhere
+    let mut orphan = FSEntry::new(FileTypeDef::RegularFile, 0);
+    orphan.parent = (7, 7);
+    let orphan = bs.create_node(orphan).unwrap();
+    let mut data = FSEntry::new(FileTypeDef::RegularFile, 0);
+    data.parent = root;
+    data.blocks.push(BlobHash::compute(HashKind::default(), b"never stored"));
+    let data = bs.create_node(data).unwrap();
+    fs.modify_node(root, false, &(|dir, _| {
+      dir.add_child(OsStr::new("gone"), ((9, 9), FileTypeDef::RegularFile)).unwrap();
+      dir.add_child(OsStr::new("data"), (data, FileTypeDef::RegularFile)).unwrap();
+    })).unwrap();
+
+    let mut report = FsckReport::default();
+    fs.fsck(false, &mut report).unwrap();
+    assert_eq!(3, report.issues.len());
+    assert!(report.issues.iter().all(|(_, fixed)| !fixed));
+    assert!(fs.find_node(Path::new("/gone")).is_ok());
+
+    let mut report = FsckReport::default();
+    fs.fsck(true, &mut report).unwrap();
+    assert_eq!(2, report.issues.iter().filter(|(_, fixed)| *fixed).count());
+    assert!(fs.find_node(Path::new("/gone")).is_err());
+    let name = format!("/{}/{:016x}-{}", LOST_FOUND, orphan.0 as u64, orphan.1);
+    assert_eq!(Ok(orphan), fs.find_node(Path::new(&name)));
+    let lostfound = fs.find_node(Path::new("/lost+found")).unwrap();
+    assert_eq!(lostfound, bs.get_node(orphan).unwrap().parent);
+
+    // The lost block can't be fixed so it's all that's left
+    let mut report = FsckReport::default();
+    fs.fsck(true, &mut report).unwrap();
+    assert_eq!(1, report.issues.len());
+    assert!(report.issues[0].0.contains("lost before it was uploaded"));
+  }
+
+  #[test]
+  fn unlinked_original_name() {
+    let (bs, _dir) = test_store("unlink-original");
+    let fs = FS::new(&bs, 0).unwrap();
+    let req = || RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    fs.mkdir(req(), Path::new("/"), OsStr::new("a"), 0o755).unwrap();
+    fs.mkdir(req(), Path::new("/"), OsStr::new("b"), 0o755).unwrap();
+    fs.create(req(), Path::new("/a"), OsStr::new("f"), 0o644, 0).unwrap();
+    let a = fs.find_node(Path::new("/a")).unwrap();
+    let b = fs.find_node(Path::new("/b")).unwrap();
+    let node = fs.find_node(Path::new("/a/f")).unwrap();
+
+    // Removing the name the node was created with moves it to the one that's left
+    fs.link(req(), Path::new("/a/f"), Path::new("/b"), OsStr::new("g")).unwrap();
+    assert_eq!(a, bs.get_node(node).unwrap().parent);
+    fs.unlink(req(), Path::new("/a"), OsStr::new("f")).unwrap();
+    let entry = bs.get_node(node).unwrap();
+    assert_eq!((1, b), (entry.nlink, entry.parent));
+    assert_eq!(Ok(PathBuf::from("/b/g")), fs.node_path(node));
+
+    // So a conflict copy made when merging goes next to it
+    let mut loser = entry.clone();
+    loser.peernum = 3;
+    loser.size = 10;
+    let copy = bs.create_conflict_copy(node, &entry, &loser).unwrap();
+    assert!(bs.get_node(b).unwrap().children.values().any(|c| c.0 == copy));
+    assert!(bs.get_node(a).unwrap().children.is_empty());
+    assert!(fs.node_path(copy).unwrap().starts_with("/b"));
+  }
+
+  #[test]
+  fn seek_and_punch_holes() {
+    let (bs, _dir) = test_store("holes");
+    let fs = FS::new(&bs, 0).unwrap();
+    let req = || RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let blksize = bs.blksize() as u64;
+    let fh = fs.create(req(), Path::new("/"), OsStr::new("f"), 0o644, 0).unwrap().fh;
+    let path = Path::new("/f");
+    fs.write(req(), path, fh, 2*blksize, b"data".to_vec(), 0).unwrap();
+    assert_eq!(Ok(2*blksize), fs.lseek(req(), path, fh, 0, libc::SEEK_DATA));
+    assert_eq!(Ok(0), fs.lseek(req(), path, fh, 0, libc::SEEK_HOLE));
+    assert_eq!(Err(libc::EINVAL), fs.lseek(req(), path, fh, 0, libc::SEEK_END));
+
+    // Only punching holes is supported, preallocating is left to the caller
+    assert_eq!(Err(libc::EOPNOTSUPP), fs.fallocate(req(), path, fh, 0, blksize, 0));
+    let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    fs.fallocate(req(), path, fh, 2*blksize, 4, punch).unwrap();
+    assert_eq!(Ok(vec![0; 4]), fs.read(req(), path, fh, 2*blksize, 4));
+    fs.release(req(), path, fh, 0, 0, false).unwrap();
+
+    // The block was still cached so it only turns into a hole once it's saved
+    let fh = fs.open(req(), path, 0).unwrap().0;
+    assert_eq!(Err(libc::ENXIO), fs.lseek(req(), path, fh, 0, libc::SEEK_DATA));
+    assert_eq!(Ok(2*blksize+4), fs.getattr(req(), path, Some(fh)).map(|a| a.1.size));
+    fs.release(req(), path, fh, 0, 0, false).unwrap();
+  }
+
+  #[test]
+  fn locks_by_owner() {
+    let (bs, _dir) = test_store("locks");
+    let fs = FS::new(&bs, 0).unwrap();
+    let req = || RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+    let path = Path::new("/f");
+    let fh1 = fs.create(req(), Path::new("/"), OsStr::new("f"), 0o644, 0).unwrap().fh;
+    let fh2 = fs.open(req(), path, 0).unwrap().0;
+    let (wr, rd) = (libc::F_WRLCK as u32, libc::F_RDLCK as u32);
+
+    fs.setlk(req(), path, fh1, 1, 0, 99, wr, 10, false).unwrap();
+    fs.setlk(req(), path, fh2, 2, 100, 199, rd, 20, false).unwrap();
+    assert_eq!(Err(libc::EAGAIN), fs.setlk(req(), path, fh2, 2, 50, 149, rd, 20, false));
+    let held = fs.getlk(req(), path, fh2, 2, 50, 149, rd, 20).unwrap();
+    assert_eq!((0, 99, wr, 10), (held.start, held.end, held.typ, held.pid));
+    let held = fs.getlk(req(), path, fh1, 1, 150, 150, wr, 10).unwrap();
+    assert_eq!((100, 199, rd, 20), (held.start, held.end, held.typ, held.pid));
+    let free = fs.getlk(req(), path, fh1, 1, 200, 299, wr, 10).unwrap();
+    assert_eq!(libc::F_UNLCK as u32, free.typ);
+    assert_eq!(Err(libc::EINVAL), fs.setlk(req(), path, fh1, 1, 0, 0, 99, 10, false));
+
+    // Closing a descriptor drops its owner's locks and the other owner keeps theirs
+    fs.flush(req(), path, fh1, 1).unwrap();
+    fs.setlk(req(), path, fh2, 2, 50, 149, rd, 20, false).unwrap();
+    assert_eq!(Err(libc::EAGAIN), fs.setlk(req(), path, fh1, 1, 120, 120, wr, 10, false));
+    fs.release(req(), path, fh2, 0, 2, false).unwrap();
+    fs.setlk(req(), path, fh1, 1, 0, u64::MAX, wr, 10, false).unwrap();
+    fs.release(req(), path, fh1, 0, 1, false).unwrap();
+  }
+
+  #[test]
+  fn concurrent_writers() {
+    let dir = TempDir::new("concurrent");
+    let mut config = test_config();
+    config.maxdirty = 2 * config.blksize as u64;
+    let bs = BackingStore::new(dir.path(), &config).unwrap();
+    let fs = FS::new(&bs, 0).unwrap();
+    let blksize = bs.blksize() as u64;
+    let node = bs.create_node(FSEntry::new(FileTypeDef::RegularFile, 0)).unwrap();
+    let blocks = 64;
+
+    // Each writer keeps flushing the blocks the other one is about to save the node with
+    crossbeam_utils::thread::scope(|scope| {
+      for first in 0..2 {
+        let (fs, bs) = (&fs, &bs);
+        scope.spawn(move || {
+          for block in (first..blocks).step_by(2) {
+            bs.throttle_writes().unwrap();
+            let data = vec![block as u8 + 1; blksize as usize];
+            fs.modify_node(node, true, &(|entry, node| entry.write(node, bs, block * blksize, &data))).unwrap().unwrap();
+          }
+        });
+      }
+    });
+    bs.sync_node(node).unwrap();
+    let entry = bs.get_node(node).unwrap();
+    for block in 0..blocks {
+      assert_eq!(vec![block as u8 + 1; 4], entry.read(node, &bs, block * blksize, 4).unwrap());
+    }
+  }
+}
@@ -28,6 +28,15 @@ impl VectorClock {
     *counter += 1;
   }
 
+  pub fn get(&self, peer: i64) -> u64 {
+    *self.peers.get(&peer).unwrap_or(&0)
+  }
+
+  // Drop the entry of a peer that's been retired, returns if there was one
+  pub fn forget(&mut self, peer: i64) -> bool {
+    self.peers.remove(&peer).is_some()
+  }
+
   pub fn cmp(&self, other: &VectorClock) -> VectorOrdering {
     let mut keys: Vec<&i64> = self.peers.keys().collect();
     let mut otherkeys: Vec<&i64> = other.peers.keys().collect();
@@ -106,6 +115,23 @@ mod tests {
     assert_eq!(VectorOrdering::Conflict, vclock2.cmp(&vclock1));
   }
 
+  #[test]
+  fn forget_peer() {
+    let mut vclock1 = VectorClock::new();
+    vclock1.increment(0);
+    vclock1.increment(1);
+    let mut vclock2 = VectorClock::new();
+    vclock2.increment(0);
+    vclock2.increment(0);
+    assert_eq!(VectorOrdering::Conflict, vclock1.cmp(&vclock2));
+
+    assert!(vclock1.forget(1));
+    assert!(!vclock1.forget(1));
+    assert_eq!(0, vclock1.get(1));
+    assert_eq!(1, vclock1.get(0));
+    assert_eq!(VectorOrdering::Less, vclock1.cmp(&vclock2));
+  }
+
   #[test]
   fn serialization_roundtrips() {
     let mut vclock = VectorClock::new();
@@ -0,0 +1,247 @@
+extern crate bincode;
+extern crate time;
+use self::time::Timespec;
+
+use std::collections::BTreeMap;
+
+use super::entry::*;
+use super::vclock::*;
+use crate::backingstore::*;
+
+// Stored entries start with these and a version number so older layouts can still be
+// read after FSEntry changes. The magic can't be mistaken for the start of an entry from
+// before there was a version as those start with a timestamp that's far too large.
+const ENTRY_MAGIC: &[u8; 4] = b"SYNE";
+
+// Version of the FSEntry layout that gets written
+pub const ENTRY_VERSION: u8 = 3;
+
+// The layout before hardlink counts and parents were tracked. Converted entries get a
+// count of 1 and the root as parent until 'syncer upgrade' works out the real ones.
+#[derive(Serialize, Deserialize)]
+pub struct FSEntryV1 {
+  #[serde(with = "TimespecDef")]
+  clock: Timespec,
+  vclock: VectorClock,
+  peernum: i64,
+
+  filetype: FileTypeDef,
+  perm: u32,
+  uid: u32,
+  gid: u32,
+  flags: u32,
+  rdev: u32,
+  #[serde(with = "TimespecDef")]
+  atime: Timespec,
+  #[serde(with = "TimespecDef")]
+  mtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  ctime: Timespec,
+  #[serde(with = "TimespecDef")]
+  crtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  chgtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  bkuptime: Timespec,
+  size: u64,
+  blocks: Vec<LegacyHash>,
+  children: BTreeMap<String, (NodeId, FileTypeDef)>,
+  xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl From<FSEntryV1> for FSEntry {
+  fn from(old: FSEntryV1) -> Self {
+    FSEntry {
+      clock: old.clock,
+      vclock: old.vclock,
+      peernum: old.peernum,
+      filetype: old.filetype,
+      perm: old.perm,
+      nlink: 1,
+      uid: old.uid,
+      gid: old.gid,
+      flags: old.flags,
+      rdev: old.rdev,
+      atime: old.atime,
+      mtime: old.mtime,
+      ctime: old.ctime,
+      crtime: old.crtime,
+      chgtime: old.chgtime,
+      bkuptime: old.bkuptime,
+      size: old.size,
+      blocks: old.blocks.into_iter().map(BlobHash::from).collect(),
+      parent: (0,0),
+      children: old.children,
+      xattrs: old.xattrs,
+    }
+  }
+}
+
+// The layout before block hashes carried their algorithm
+#[derive(Serialize, Deserialize)]
+pub struct FSEntryV2 {
+  #[serde(with = "TimespecDef")]
+  clock: Timespec,
+  vclock: VectorClock,
+  peernum: i64,
+
+  filetype: FileTypeDef,
+  perm: u32,
+  nlink: u32,
+  uid: u32,
+  gid: u32,
+  flags: u32,
+  rdev: u32,
+  #[serde(with = "TimespecDef")]
+  atime: Timespec,
+  #[serde(with = "TimespecDef")]
+  mtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  ctime: Timespec,
+  #[serde(with = "TimespecDef")]
+  crtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  chgtime: Timespec,
+  #[serde(with = "TimespecDef")]
+  bkuptime: Timespec,
+  size: u64,
+  blocks: Vec<LegacyHash>,
+  parent: NodeId,
+  children: BTreeMap<String, (NodeId, FileTypeDef)>,
+  xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl From<FSEntryV2> for FSEntry {
+  fn from(old: FSEntryV2) -> Self {
+    FSEntry {
+      clock: old.clock,
+      vclock: old.vclock,
+      peernum: old.peernum,
+      filetype: old.filetype,
+      perm: old.perm,
+      nlink: old.nlink,
+      uid: old.uid,
+      gid: old.gid,
+      flags: old.flags,
+      rdev: old.rdev,
+      atime: old.atime,
+      mtime: old.mtime,
+      ctime: old.ctime,
+      crtime: old.crtime,
+      chgtime: old.chgtime,
+      bkuptime: old.bkuptime,
+      size: old.size,
+      blocks: old.blocks.into_iter().map(BlobHash::from).collect(),
+      parent: old.parent,
+      children: old.children,
+      xattrs: old.xattrs,
+    }
+  }
+}
+
+fn decode_layout(version: u8, buffer: &[u8]) -> Option<FSEntry> {
+  match version {
+    1 => decode_exact::<FSEntryV1>(buffer).map(FSEntry::from),
+    2 => decode_exact::<FSEntryV2>(buffer).map(FSEntry::from),
+    3 => decode_exact::<FSEntry>(buffer),
+    _ => None,
+  }
+}
+
+impl FSEntry {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut encoded = ENTRY_MAGIC.to_vec();
+    encoded.push(ENTRY_VERSION);
+    encoded.append(&mut bincode::serialize(self).unwrap());
+    encoded
+  }
+
+  pub fn decode(buffer: &[u8]) -> Result<FSEntry, String> {
+    Self::decode_versioned(buffer).map(|(entry, _)| entry)
+  }
+
+  // Decode an entry along with the layout version it was stored in
+  pub fn decode_versioned(buffer: &[u8]) -> Result<(FSEntry, u8), String> {
+    if buffer.len() > ENTRY_MAGIC.len() && buffer.starts_with(ENTRY_MAGIC) {
+      let version = buffer[ENTRY_MAGIC.len()];
+      return match decode_layout(version, &buffer[ENTRY_MAGIC.len()+1..]) {
+        Some(entry) => Ok((entry, version)),
+        None if version > ENTRY_VERSION => Err(format!("entry from newer version {}", version)),
+        None => Err(format!("invalid entry in version {}", version)),
+      }
+    }
+    // From before entries had a version. The hardlink counts and parents were added
+    // without a format change so either of the first two layouts can show up.
+    for version in (1..=2).rev() {
+      if let Some(entry) = decode_layout(version, buffer) {
+        return Ok((entry, version))
+      }
+    }
+    Err("invalid entry".to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::settings::*;
+
+  #[test]
+  fn encoding_roundtrips() {
+    let mut entry = FSEntry::new(FileTypeDef::Directory, 0);
+    entry.vclock.increment(0);
+    entry.parent = (1, 2);
+    let encoded = entry.encode();
+    assert_eq!((entry.clone(), ENTRY_VERSION), FSEntry::decode_versioned(&encoded).unwrap());
+    assert!(FSEntry::decode(&encoded[..encoded.len()-1]).is_err());
+  }
+
+  #[test]
+  fn reads_v1() {
+    let mut entry = FSEntry::new(FileTypeDef::RegularFile, 3);
+    entry.perm = 0o644;
+    entry.size = 10;
+    entry.blocks.push([7; HASHSIZE].into());
+    entry.xattrs.insert("user.test".to_string(), vec![1, 2]);
+    let old = FSEntryV1 {
+      clock: entry.clock, vclock: entry.vclock.clone(), peernum: entry.peernum,
+      filetype: entry.filetype, perm: entry.perm, uid: entry.uid, gid: entry.gid,
+      flags: entry.flags, rdev: entry.rdev, atime: entry.atime, mtime: entry.mtime,
+      ctime: entry.ctime, crtime: entry.crtime, chgtime: entry.chgtime, bkuptime: entry.bkuptime,
+      size: entry.size, blocks: vec![[7; HASHSIZE]], children: entry.children.clone(),
+      xattrs: entry.xattrs.clone(),
+    };
+    let plain = bincode::serialize(&old).unwrap();
+    assert_eq!((entry.clone(), 1), FSEntry::decode_versioned(&plain).unwrap());
+    let mut tagged = ENTRY_MAGIC.to_vec();
+    tagged.push(1);
+    tagged.extend(plain);
+    assert_eq!((entry, 1), FSEntry::decode_versioned(&tagged).unwrap());
+
+    tagged[ENTRY_MAGIC.len()] = ENTRY_VERSION + 1;
+    assert!(FSEntry::decode(&tagged).unwrap_err().contains("newer"));
+  }
+
+  #[test]
+  fn reads_v2() {
+    let mut entry = FSEntry::new(FileTypeDef::RegularFile, 3);
+    entry.nlink = 2;
+    entry.parent = (1, 2);
+    entry.size = 10;
+    entry.blocks.push([7; HASHSIZE].into());
+    let old = FSEntryV2 {
+      clock: entry.clock, vclock: entry.vclock.clone(), peernum: entry.peernum,
+      filetype: entry.filetype, perm: entry.perm, nlink: entry.nlink, uid: entry.uid,
+      gid: entry.gid, flags: entry.flags, rdev: entry.rdev, atime: entry.atime,
+      mtime: entry.mtime, ctime: entry.ctime, crtime: entry.crtime, chgtime: entry.chgtime,
+      bkuptime: entry.bkuptime, size: entry.size, blocks: vec![[7; HASHSIZE]],
+      parent: entry.parent, children: entry.children.clone(), xattrs: entry.xattrs.clone(),
+    };
+    let plain = bincode::serialize(&old).unwrap();
+    assert_eq!((entry.clone(), 2), FSEntry::decode_versioned(&plain).unwrap());
+    let mut tagged = ENTRY_MAGIC.to_vec();
+    tagged.push(2);
+    tagged.extend(plain);
+    assert_eq!((entry, 2), FSEntry::decode_versioned(&tagged).unwrap());
+  }
+}
@@ -14,8 +14,9 @@ use std::time;
 use std::mem;
 use std::sync::mpsc;
 use std::path::{Path, PathBuf};
-use std::io::{Read, BufRead, BufReader};
-use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::fs::{self, File};
+use std::collections::HashMap;
 
 mod filesystem;
 mod backingstore;
@@ -26,8 +27,9 @@ pub mod config;
 use crate::settings::*;
 use crate::config::*;
 
-use self::backingstore::BackingStore;
+use self::backingstore::{fetch_repository, publish_repository, fetch_format, publish_format, BackingStore, LocalStore, ConflictInfo, PeerInfo, FsckReport, SyncerError};
 use self::filesystem::FS;
+pub use self::filesystem::ConflictKeep;
 
 // This is a hack while FuseMT requires 'static for the FilesystemMT instance
 // See the github issue for discussion: https://github.com/wfraser/fuse-mt/issues/26
@@ -42,7 +44,7 @@ struct BackgroundThread<'a> {
 
 impl<'a> BackgroundThread<'a> {
   fn new<F: 'a>(scope: &Scope<'a>, secs: u64, closure: F) -> Self
-  where F: Fn() -> Result<(), Error> + Send {
+  where F: Fn() -> Result<(), SyncerError> + Send {
     let (tx, rx) = mpsc::channel();
 
     let handle = scope.spawn(move || {
@@ -74,16 +76,14 @@ impl<'a> BackgroundThread<'a> {
 }
 
 pub fn run(source: &Path, mount: &Path, conf: &Config) -> Result<(), Error> {
-  if conf.formatversion < FORMATVERSION {
-    let message = format!("Trying to mount old format (version {} vs {})",
-                           conf.formatversion, FORMATVERSION);
-    return Err(Error::new(ErrorKind::Other, message));
-  }
+  check_repository(source, conf)?;
+  check_server_format(source, conf)?;
 
   let bs = match BackingStore::new(source, &conf) {
     Ok(bs) => bs,
-    Err(_) => return Err(Error::new(ErrorKind::Other, "Couldn't create the backing store")),
+    Err(e) => return Err(store_error("Couldn't create the backing store", e)),
   };
+  bs.start_journal().map_err(|e| store_error("Couldn't replay the journal", e))?;
   let fs = match filesystem::FS::new(&bs, conf.peernum()) {
     Ok(fs) => fs,
     Err(_) => return Err(Error::new(ErrorKind::Other, "Couldn't create the filesystem")),
@@ -93,10 +93,12 @@ pub fn run(source: &Path, mount: &Path, conf: &Config) -> Result<(), Error> {
 
   crossbeam_utils::thread::scope(|scope| {
     let sync   = BackgroundThread::new(&scope, 60, move || bsref.sync_all());
-    let upload = BackgroundThread::new(&scope, 10, move || bsref.do_uploads());
-    let nodes1 = BackgroundThread::new(&scope, 10, move || bsref.do_uploads_nodes());
-    let nodes2 = BackgroundThread::new(&scope, 10, move || bsref.do_downloads_nodes());
+    let journal = BackgroundThread::new(&scope, JOURNAL_SYNC_INTERVAL, move || bsref.sync_journal());
+    // These wait for changes themselves so they get called straight away
+    let push   = BackgroundThread::new(&scope, 0, move || bsref.push_changes());
+    let pull   = BackgroundThread::new(&scope, 0, move || bsref.wait_downloads_nodes());
     let remove = BackgroundThread::new(&scope, 10, move || bsref.do_removals());
+    let checkpoint = BackgroundThread::new(&scope, CHECKPOINT_INTERVAL, move || bsref.do_checkpoint());
 
     let fshandle = scope.spawn(move || {
       let fs_mt = FuseMT::new(fs, 16);
@@ -105,72 +107,460 @@ pub fn run(source: &Path, mount: &Path, conf: &Config) -> Result<(), Error> {
     });
 
     let ret = fshandle.join();
+    bsref.stop_notifications();
     sync.join();
-    upload.join();
-    nodes1.join();
-    nodes2.join();
+    journal.join();
+    push.join();
+    pull.join();
     remove.join();
+    checkpoint.join();
     ret
   }).unwrap()
 }
 
-pub fn clone(source: &Path, conf: &Config) -> Result<(), Error> {
-  if conf.formatversion < FORMATVERSION {
-    let message = format!("Trying to clone into old format (version {} vs {})",
-                           conf.formatversion, FORMATVERSION);
-    return Err(Error::new(ErrorKind::Other, message));
+// Take the block and hash sizes of the repository being cloned from the server
+pub fn clone_settings(source: &Path, conf: &mut Config) -> Result<(), Error> {
+  if fetch_repository(&conf.server, source)? {
+    let settings = match RepoSettings::load(source).map_err(other_error)? {
+      Some(s) => s,
+      None => return Err(other_error("Couldn't find the repository settings fetched from the server".to_string())),
+    };
+    conf.blksize = settings.blksize;
+    conf.hashalgo = settings.hashalgo;
+    conf.hashsize = settings.hashsize;
   }
+  Ok(())
+}
+
+pub fn clone(source: &Path, conf: &Config, latest: bool, trust: &[(String, String)]) -> Result<(), Error> {
+  check_repository(source, conf)?;
 
   let bs = match BackingStore::new(source, &conf) {
     Ok(bs) => bs,
-    Err(_) => return Err(Error::new(ErrorKind::Other, "Couldn't create the backing store")),
+    Err(e) => return Err(store_error("Couldn't create the backing store", e)),
   };
+  write_format(source)?;
+  conf.repo_settings().save(source).map_err(other_error)?;
+  check_server_format(source, conf)?;
 
-  bs.do_downloads_nodes()?;
+  // Keys given on the command line are pinned before anything is read so the ones
+  // on the server can't stand in for them
+  for (name, pubkey) in trust {
+    if name.len() != 16 || hex::decode(name).is_err() {
+      return Err(other_error(format!("Peer {:?} isn't a peer id", name)))
+    }
+    bs.trust_peer(convert_peerid(name), pubkey).map_err(|e| store_error("Couldn't trust peer", e))?;
+  }
+
+  // Start from the latest checkpoint if there is one so only the log entries after it
+  // need to be replayed
+  bs.load_checkpoint().map_err(|e| store_error("Couldn't load the checkpoint", e))?;
+  let res = if latest {
+    bs.do_downloads_nodes_latest()
+  } else {
+    bs.do_downloads_nodes()
+  };
+  res.map_err(|e| store_error("Couldn't fetch the changes", e))?;
+
+  let untrusted = bs.untrusted_peers().map_err(|e| store_error("Couldn't read peers", e))?;
+  if !untrusted.is_empty() {
+    println!("Changes from these peers were skipped as they aren't trusted yet. Check each");
+    println!("key with whoever runs that peer and then run 'syncer peers trust' to take them:");
+    for (peer, key) in untrusted {
+      println!("  {:016x} {}", peer as u64, key);
+    }
+  }
 
   Ok(())
 }
 
 pub fn init(source: &Path, conf: &Config) -> Result<(), Error> {
-  if conf.formatversion < FORMATVERSION {
-    let message = format!("Trying to clone into old format (version {} vs {})",
-                           conf.formatversion, FORMATVERSION);
-    return Err(Error::new(ErrorKind::Other, message));
-  }
+  check_repository(source, conf)?;
 
   let bs = match BackingStore::new(source, &conf) {
     Ok(bs) => bs,
-    Err(_) => return Err(Error::new(ErrorKind::Other, "Couldn't create the backing store")),
+    Err(e) => return Err(store_error("Couldn't create the backing store", e)),
   };
+  write_format(source)?;
+  conf.repo_settings().save(source).map_err(other_error)?;
   match filesystem::FS::new(&bs, conf.peernum()) {
     Ok(fs) => fs,
     Err(_) => return Err(Error::new(ErrorKind::Other, "Couldn't create the filesystem")),
   };
 
-  bs.init_server()?;
+  bs.init_server().map_err(|e| store_error("Couldn't set up the server", e))?;
+  Ok(())
+}
+
+fn print_log_line(store: &LocalStore, line: &[u8]) -> Result<(), String> {
+  let encoded = line.split(|c| *c == b' ').next().unwrap_or(&[]);
+  let buffer = base64::decode(encoded).map_err(|e| format!("invalid encoding: {}", e))?;
+  let node = backingstore::decode_node_info(&buffer).ok_or_else(|| "invalid node".to_string())?;
+  let hash = node.hash.to_string();
+  println!("node {} -> {}, {:?}", hash, node.creation, node.id);
+  let buffer = store.read(&node.hash).map_err(|e| format!("can't read blob {}: {}", hash, e))?;
+  let entry = filesystem::FSEntry::decode(&buffer).map_err(|e| format!("invalid entry in blob {}: {}", hash, e))?;
+  println!("entry {:?}", entry);
   Ok(())
 }
 
 pub fn printlog(source: &Path, conf: &Config) -> Result<(), Error> {
-  let mut log = PathBuf::from(source);
-  log.push("nodes");
-  log.push(&conf.peerid);
-
-  let buffer = BufReader::new(File::open(&log).unwrap());
-  for line in buffer.lines() {
-    let line = line.unwrap();
-    let buffer = base64::decode(&line).unwrap();
-    let node: backingstore::NodeInfo = bincode::deserialize(&buffer).unwrap();
-    let hash = hex::encode(&node.hash);
-    println!("node {} -> {}, {:?}", hash, node.creation, node.id);
-    let mut blobpath = PathBuf::from(source);
-    blobpath.push("blobs");
-    blobpath.push(hash);
-    let mut buffer = Vec::new();
-    File::open(&blobpath).unwrap().read_to_end(&mut buffer).unwrap();
-    let entry: filesystem::FSEntry = bincode::deserialize(&buffer).unwrap();
-    println!("entry {:?}", entry);
+  let store = LocalStore::new(source, conf.localstore).map_err(|e| store_error("Couldn't open the local store", e))?;
+  let mut logdir = PathBuf::from(source);
+  logdir.push("nodes");
+
+  for (segment, log) in backingstore::log_segments(&logdir, &conf.peerid) {
+    println!("segment {}", segment);
+    let buffer = BufReader::new(File::open(&log)?);
+    for (num, line) in buffer.split(b'\n').enumerate() {
+      match print_log_line(&store, &line?) {
+        Ok(()) => {},
+        Err(e) => println!("bad line {}: {}", num+1, e),
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn open_store(source: &Path, conf: &Config) -> Result<BackingStore, Error> {
+  check_repository(source, conf)?;
+  BackingStore::new(source, conf).map_err(|e| store_error("Couldn't create the backing store", e))
+}
+
+fn format_path(source: &Path) -> PathBuf {
+  let mut path = PathBuf::from(source);
+  path.push("format");
+  path
+}
+
+// The format the data dir is in. Repositories from before it was written there only
+// have it in the config.
+fn format_version(source: &Path, conf: &Config) -> Result<u64, Error> {
+  match fs::read_to_string(format_path(source)) {
+    Ok(text) => text.trim().parse::<u64>()
+      .map_err(|e| other_error(format!("Couldn't understand format version {:?}: {}", text.trim(), e))),
+    Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(conf.formatversion),
+    Err(e) => Err(e),
+  }
+}
+
+fn write_format(source: &Path) -> Result<(), Error> {
+  fs::write(format_path(source), format!("{}\n", FORMATVERSION))
+}
+
+// Make sure the data dir is in the current format and was created with the block and
+// hash sizes in the config before touching anything
+fn check_repository(source: &Path, conf: &Config) -> Result<(), Error> {
+  let version = format_version(source, conf)?;
+  if version < FORMATVERSION {
+    return Err(other_error(format!("Repository is in old format {} (current is {}), run 'syncer upgrade' first",
+                                   version, FORMATVERSION)));
+  }
+  if version > FORMATVERSION {
+    return Err(other_error(format!("Repository is in format {} which is newer than this syncer supports ({})",
+                                   version, FORMATVERSION)));
+  }
+  let settings = conf.repo_settings();
+  settings.check().map_err(|e| other_error(format!("Invalid repository settings: {}", e)))?;
+  match RepoSettings::load(source).map_err(other_error)? {
+    Some(ref stored) if *stored != settings => {
+      Err(other_error(format!("Config has {:?} but the repository was created with {:?}", settings, stored)))
+    },
+    _ => Ok(()),
+  }
+}
+
+// Name new blobs with a different hash from now on. The ones already stored keep the
+// hash they have and can still be read, so the peers can move over one at a time.
+// Returns whether anything changed so the caller knows to save the config.
+pub fn set_hash(source: &Path, conf: &mut Config, spec: &str) -> Result<bool, Error> {
+  check_repository(source, conf)?;
+  let old = conf.hashkind();
+  conf.set_hash(spec).map_err(|e| other_error(format!("Couldn't understand hash {:?}: {}", spec, e)))?;
+  if conf.hashkind() == old {
+    println!("Already using {}:{}", conf.hashalgo.name(), conf.hashsize);
+    return Ok(false)
+  }
+  let settings = conf.repo_settings();
+  settings.check().map_err(|e| other_error(format!("Invalid repository settings: {}", e)))?;
+  settings.save(source).map_err(other_error)?;
+  // New clones take the hash from the server
+  publish_repository(&conf.server, source)?;
+  println!("New blobs will use {}:{}", conf.hashalgo.name(), conf.hashsize);
+  Ok(true)
+}
+
+// Refuse to work with a server some peer has already moved to a newer format and move
+// it up to ours otherwise, so peers older than us stop writing to it
+fn check_server_format(source: &Path, conf: &Config) -> Result<(), Error> {
+  let remote = match fetch_format(&conf.server, source) {
+    Ok(v) => v,
+    Err(e) => {
+      // Working offline is fine, the check happens the next time
+      eprintln!("WARNING: couldn't get the format of the server: {}", e);
+      return Ok(())
+    },
+  };
+  match remote {
+    Some(version) if version > FORMATVERSION => {
+      Err(other_error(format!("Server is in format {} which is newer than this syncer supports ({})",
+                              version, FORMATVERSION)))
+    },
+    Some(version) if version == FORMATVERSION => Ok(()),
+    _ => {
+      if let Err(e) = publish_format(&conf.server, source) {
+        eprintln!("WARNING: couldn't put format {} on the server: {}", FORMATVERSION, e);
+      }
+      Ok(())
+    },
+  }
+}
+
+// Bring a repository from an older format up to the current one. Returns whether
+// anything had to be done so the caller knows to save the config.
+pub fn upgrade(source: &Path, conf: &mut Config) -> Result<bool, Error> {
+  let version = format_version(source, conf)?;
+  if version == FORMATVERSION {
+    println!("Already in format {}", FORMATVERSION);
+    return Ok(false)
+  }
+  if version > FORMATVERSION {
+    return Err(other_error(format!("Repository is in format {} which is newer than this syncer supports ({})",
+                                   version, FORMATVERSION)));
+  }
+  // Journals from before hashes carried their algorithm can't be read back anymore
+  let mut journal = source.to_path_buf();
+  journal.push("journal");
+  if version < 10 && fs::metadata(&journal).map(|m| m.len() > 0).unwrap_or(false) {
+    return Err(other_error("Repository wasn't shut down cleanly, mount and unmount it with the previous \
+                            syncer version before upgrading".to_string()));
+  }
+  // Signed logs came in without a format change so older repositories may not have a key
+  if !conf.valid_secretkey() {
+    conf.secretkey = Config::new_secretkey();
+  }
+  conf.formatversion = FORMATVERSION;
+  conf.repo_settings().check().map_err(|e| other_error(format!("Invalid repository settings: {}", e)))?;
+
+  // Opening the database brings its schema up to date
+  let bs = BackingStore::new(source, conf).map_err(|e| store_error("Couldn't create the backing store", e))?;
+  let count = bs.upgrade().map_err(|e| store_error("Couldn't upgrade the nodes", e))?;
+  write_format(source)?;
+  check_server_format(source, conf)?;
+  if RepoSettings::load(source).map_err(other_error)?.is_none() {
+    conf.repo_settings().save(source).map_err(other_error)?;
+  }
+  println!("Upgraded from format {} to {}, re-encoded {} nodes", version, FORMATVERSION, count);
+  Ok(true)
+}
+
+fn other_error(message: String) -> Error {
+  Error::new(ErrorKind::Other, message)
+}
+
+fn fs_error(what: &str, errno: i32) -> Error {
+  other_error(format!("{}: {}", what, Error::from_raw_os_error(errno)))
+}
+
+fn store_error(what: &str, e: SyncerError) -> Error {
+  other_error(format!("{}: {}", what, e))
+}
+
+fn format_timeval(timeval: i64) -> String {
+  let tm = ::time::at(::time::Timespec::new(timeval / 1000, 0));
+  tm.strftime("%Y-%m-%d %H:%M:%S").unwrap().to_string()
+}
+
+fn print_conflict(fs: &FS, conflict: &ConflictInfo) {
+  let path = match fs.node_path(conflict.node) {
+    Ok(p) => p.to_string_lossy().to_string(),
+    Err(_) => format!("{:?}", conflict.node),
+  };
+  let status = if conflict.resolved { "resolved" } else { "unresolved" };
+  println!("#{} {} {} {} ({})", conflict.id, format_timeval(conflict.creation),
+           conflict.kind.name(), path, status);
+  if let Some(copy) = conflict.copy {
+    if let Ok(p) = fs.node_path(copy) {
+      println!("  copy at {}", p.to_string_lossy());
+    }
+  }
+}
+
+pub fn conflicts_list(source: &Path, conf: &Config, all: bool) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let fs = FS::new(&bs, conf.peernum()).map_err(|e| fs_error("Couldn't create the filesystem", e))?;
+  let conflicts = bs.conflicts(None, all).map_err(|e| store_error("Couldn't read conflicts", e))?;
+  for conflict in conflicts.iter() {
+    print_conflict(&fs, conflict);
+  }
+  Ok(())
+}
+
+pub fn conflicts_show(source: &Path, conf: &Config, path: &Path) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let fs = FS::new(&bs, conf.peernum()).map_err(|e| fs_error("Couldn't create the filesystem", e))?;
+  let node = fs.find_node(path).map_err(|e| fs_error("Couldn't find path", e))?;
+  let conflicts = bs.conflicts(Some(node), true).map_err(|e| store_error("Couldn't read conflicts", e))?;
+  if conflicts.is_empty() {
+    println!("No conflicts recorded for {:?}", path);
+  }
+  for conflict in conflicts.iter() {
+    print_conflict(&fs, conflict);
+    let versions = [("base", &conflict.base), ("mine", &conflict.local),
+                    ("theirs", &conflict.remote), ("merged", &conflict.merged)];
+    for (name, hash) in versions.iter() {
+      match bs.fetch_entry(hash) {
+        Ok(entry) => println!("  {:6} from peer {:016x} at {} size {} mode {:o} blocks {}",
+                              name, entry.peernum as u64, format_timeval(entry.timeval()),
+                              entry.size, entry.perm, hash),
+        Err(_) => println!("  {:6} {} (not available)", name, hash),
+      }
+    }
+  }
+  Ok(())
+}
+
+pub fn conflicts_resolve(source: &Path, conf: &Config, path: &Path, keep: ConflictKeep) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let fs = FS::new(&bs, conf.peernum()).map_err(|e| fs_error("Couldn't create the filesystem", e))?;
+  let node = fs.find_node(path).map_err(|e| fs_error("Couldn't find path", e))?;
+  let conflicts = bs.conflicts(Some(node), false).map_err(|e| store_error("Couldn't read conflicts", e))?;
+  if conflicts.is_empty() {
+    return Err(other_error(format!("No unresolved conflicts for {:?}", path)));
+  }
+  // Oldest first so the last one decides what the node ends up with
+  for conflict in conflicts.iter() {
+    fs.resolve_conflict(conflict, keep).map_err(|e| fs_error("Couldn't resolve conflict", e))?;
+  }
+  bs.sync_all().map_err(|e| store_error("Couldn't save the changes", e))?;
+  Ok(())
+}
+
+pub fn compact(source: &Path, conf: &Config) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  bs.compact().map_err(|e| store_error("Couldn't compact", e))
+}
+
+// Peers can be named by their id or their label
+fn find_peer(peers: &[PeerInfo], name: &str) -> Result<i64, Error> {
+  for peer in peers {
+    if format!("{:016x}", peer.id as u64) == name || peer.label.as_ref().map(|l| l == name).unwrap_or(false) {
+      return Ok(peer.id)
+    }
+  }
+  Err(other_error(format!("Unknown peer {:?}", name)))
+}
+
+pub fn quarantine_list(source: &Path, conf: &Config) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let records = bs.quarantined().map_err(|e| store_error("Couldn't read quarantine", e))?;
+  for record in records {
+    println!("{} {:8} {} ({})", format_timeval(record.creation), record.kind, record.source, record.reason);
   }
+  Ok(())
+}
 
+pub fn peers_list(source: &Path, conf: &Config) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let peers = bs.peers().map_err(|e| store_error("Couldn't read peers", e))?;
+  let published: HashMap<i64, String> = bs.untrusted_peers().map_err(|e| store_error("Couldn't read peers", e))?
+    .into_iter().collect();
+  for peer in peers {
+    let mut flags = Vec::new();
+    if peer.id == conf.peernum() { flags.push("self") }
+    if peer.retired { flags.push("retired") }
+    let lastseen = if peer.lastseen > 0 { format_timeval(peer.lastseen) } else { "never".to_string() };
+    println!("{:016x} {:16} last active {} log at {}:{} vclock {} {}",
+             peer.id as u64, peer.label.unwrap_or_default(), lastseen, peer.segment,
+             peer.offset, peer.counter, flags.join(" "));
+    match peer.pubkey {
+      Some(key) => println!("  key {}", key),
+      None => match published.get(&peer.id) {
+        Some(key) => println!("  not trusted, publishes key {}", key),
+        None => println!("  not trusted"),
+      },
+    }
+    if peer.rejected > 0 {
+      println!("  {} log entries rejected", peer.rejected);
+    }
+  }
+  Ok(())
+}
+
+pub fn peers_label(source: &Path, conf: &Config, name: &str, label: &str) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let peers = bs.peers().map_err(|e| store_error("Couldn't read peers", e))?;
+  let peer = find_peer(&peers, name)?;
+  bs.label_peer(peer, label).map_err(|e| store_error("Couldn't label peer", e))
+}
+
+pub fn peers_trust(source: &Path, conf: &Config, name: &str, pubkey: &str) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let peer = if name.len() == 16 && hex::decode(name).is_ok() {
+    convert_peerid(name)
+  } else {
+    let peers = bs.peers().map_err(|e| store_error("Couldn't read peers", e))?;
+    find_peer(&peers, name)?
+  };
+  bs.trust_peer(peer, pubkey).map_err(|e| store_error("Couldn't trust peer", e))
+}
+
+pub fn peers_retire(source: &Path, conf: &Config, name: &str) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let peers = bs.peers().map_err(|e| store_error("Couldn't read peers", e))?;
+  let peer = find_peer(&peers, name)?;
+  if peer == conf.peernum() {
+    return Err(other_error("Can't retire this peer from itself".to_string()));
+  }
+  bs.retire_peer(peer).map_err(|e| store_error("Couldn't retire peer", e))
+}
+
+pub fn fsck(source: &Path, conf: &Config, repair: bool) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let mut report = FsckReport::default();
+  bs.fsck(repair, &mut report).map_err(|e| store_error("Couldn't check the store", e))?;
+  let fs = FS::new(&bs, conf.peernum()).map_err(|e| fs_error("Couldn't create the filesystem", e))?;
+  fs.fsck(repair, &mut report).map_err(|e| fs_error("Couldn't check the filesystem", e))?;
+  bs.sync_all().map_err(|e| store_error("Couldn't save the changes", e))?;
+  println!("Checked {} nodes, {} blocks, {} blobs and {} log lines",
+           report.nodes, report.blocks, report.blobs, report.loglines);
+  let mut unfixed = 0;
+  for (issue, fixed) in report.issues.iter() {
+    println!("  {}{}", issue, if *fixed { " (fixed)" } else { "" });
+    if !fixed { unfixed += 1 }
+  }
+  if unfixed > 0 {
+    return Err(other_error(format!("{} problems left unfixed", unfixed)));
+  }
+  Ok(())
+}
+
+pub fn verify(source: &Path, conf: &Config, remote: bool) -> Result<(), Error> {
+  let bs = open_store(source, conf)?;
+  let report = bs.verify(remote).map_err(|e| store_error("Couldn't verify the store", e))?;
+  println!("Checked {} local blobs", report.checked);
+  for hash in report.corrupted.iter() {
+    println!("  corrupted {} (will be fetched again)", hash);
+  }
+  for hash in report.lost.iter() {
+    println!("  corrupted {} (not on the server, kept in quarantine)", hash);
+  }
+  if remote {
+    println!("Checked {} remote blobs", report.remote_checked);
+    for hash in report.remote_missing.iter() {
+      println!("  missing {}", hash);
+    }
+    for hash in report.remote_corrupted.iter() {
+      println!("  corrupted {}", hash);
+    }
+    for hash in report.repaired.iter() {
+      println!("  repaired {} from the local copy", hash);
+    }
+  }
+  let bad = report.lost.len() + report.remote_missing.len() + report.remote_corrupted.len();
+  if bad > report.repaired.len() {
+    return Err(other_error("Some blobs couldn't be recovered".to_string()));
+  }
   Ok(())
 }
@@ -4,13 +4,28 @@ use syncer::config;
 use std::env;
 use std::process;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn usage() {
   eprintln!("USAGE:");
-  eprintln!("  syncer init <local dir> <remote source> <max local size in MB>");
-  eprintln!("  syncer clone <local dir> <remote source> <max local size in MB>");
+  eprintln!("  syncer init [--blksize=<bytes>] [--hash=<blake2b|blake3>[:<bytes>]] [--local-store=flat|fanout|sqlite]");
+  eprintln!("              <local dir> <remote source> <max local size in MB>");
+  eprintln!("  syncer clone [--latest] [--local-store=flat|fanout|sqlite] [--trust=<peer>:<public key>]...");
+  eprintln!("               <local dir> <remote source> <max local size in MB>");
   eprintln!("  syncer mount <local dir> <mount dir>");
+  eprintln!("  syncer compact <local dir>");
+  eprintln!("  syncer verify <local dir> [--remote]");
+  eprintln!("  syncer fsck <local dir> [--repair]");
+  eprintln!("  syncer upgrade <local dir>");
+  eprintln!("  syncer set-hash <local dir> <blake2b|blake3>[:<bytes>]");
+  eprintln!("  syncer quarantine <local dir>");
+  eprintln!("  syncer peers list <local dir>");
+  eprintln!("  syncer peers label <local dir> <peer> <label>");
+  eprintln!("  syncer peers trust <local dir> <peer> <public key>");
+  eprintln!("  syncer peers retire <local dir> <peer>");
+  eprintln!("  syncer conflicts list <local dir> [--all]");
+  eprintln!("  syncer conflicts show <local dir> <path>");
+  eprintln!("  syncer conflicts resolve <local dir> --keep=mine|theirs|both <path>");
   process::exit(2);
 }
 
@@ -23,12 +38,49 @@ fn main() {
     "clone"  => init(&args[2..], true),
     "mount" => mount(&args[2..]),
     "printlog" => printlog(&args[2..]),
+    "compact" => compact(&args[2..]),
+    "verify" => verify(&args[2..]),
+    "fsck" => fsck(&args[2..]),
+    "upgrade" => upgrade(&args[2..]),
+    "set-hash" => set_hash(&args[2..]),
+    "quarantine" => quarantine(&args[2..]),
+    "peers" => peers(&args[2..]),
+    "conflicts" => conflicts(&args[2..]),
     _ => usage(),
   }
 
 }
 
 fn init(args: &[String], fetch: bool) {
+  let mut args = args;
+  let mut latest = false;
+  let mut blksize = None;
+  let mut hash = None;
+  let mut localstore = None;
+  let mut trust = Vec::new();
+  while !args.is_empty() {
+    let arg = &args[0];
+    if fetch && arg == "--latest" {
+      latest = true;
+    } else if let Some(v) = arg.strip_prefix("--trust=").filter(|_| fetch) {
+      match v.split_once(':') {
+        Some((peer, key)) => trust.push((peer.to_string(), key.to_string())),
+        None => {
+          eprintln!("ERROR: Couldn't understand {:?}, expected <peer>:<public key>", v);
+          usage();
+        },
+      }
+    } else if let Some(v) = arg.strip_prefix("--local-store=") {
+      localstore = Some(v);
+    } else if let Some(v) = arg.strip_prefix("--blksize=").filter(|_| !fetch) {
+      blksize = Some(v);
+    } else if let Some(v) = arg.strip_prefix("--hash=").filter(|_| !fetch) {
+      hash = Some(v);
+    } else {
+      break
+    }
+    args = &args[1..];
+  }
   if args.len() != 3 { usage() }
 
   let mut path = env::current_dir().unwrap();
@@ -37,19 +89,52 @@ fn init(args: &[String], fetch: bool) {
   let maxbytes = match args[2].parse::<u64>() {
     Ok(v) => v * 1000000,
     Err(e) => {
-      eprintln!("ERROR: Couldn't understand max local size {:?}: {}", args[4], e);
+      eprintln!("ERROR: Couldn't understand max local size {:?}: {}", args[2], e);
       usage();
       return
     },
   };
 
-  let conf = config::Config::new(server, maxbytes);
+  let mut conf = config::Config::new(server, maxbytes);
+  if let Some(blksize) = blksize {
+    conf.blksize = match blksize.parse::<usize>() {
+      Ok(v) => v,
+      Err(e) => {
+        eprintln!("ERROR: Couldn't understand block size {:?}: {}", blksize, e);
+        usage();
+        return
+      },
+    };
+  }
+  if let Some(localstore) = localstore {
+    if let Err(e) = conf.set_localstore(localstore) {
+      eprintln!("ERROR: Couldn't understand local store {:?}: {}", localstore, e);
+      usage();
+      return
+    }
+  }
+  if let Some(hash) = hash {
+    if let Err(e) = conf.set_hash(hash) {
+      eprintln!("ERROR: Couldn't understand hash {:?}: {}", hash, e);
+      usage();
+      return
+    }
+  }
 
   match fs::create_dir(&path) {
     Ok(_) => {},
     Err(e) => {eprintln!("ERROR: Couldn't create dir: {}", e); process::exit(3);},
   }
 
+  let mut source = path.clone();
+  source.push("data");
+  if fetch {
+    if let Err(e) = syncer::clone_settings(&source, &mut conf) {
+      eprintln!("CLONE ERROR: {}", e);
+      process::exit(3);
+    }
+  }
+
   let mut conffile = PathBuf::from(&path);
   conffile.push("config");
 
@@ -58,10 +143,8 @@ fn init(args: &[String], fetch: bool) {
     Err(e) => {eprintln!("ERROR: Couldn't save config file: {}", e); process::exit(3);},
   }
 
-  let mut source = path.clone();
-  source.push("data");
   if fetch {
-    match syncer::clone(&source, &conf) {
+    match syncer::clone(&source, &conf, latest, &trust) {
       Ok(_) => {},
       Err(e) => eprintln!("CLONE ERROR: {}", e),
     }
@@ -99,8 +182,16 @@ fn mount(args: &[String]) {
 fn printlog(args: &[String]) {
   if args.len() != 1 { usage() }
 
+  let (source, conf) = open(&args[0]);
+  match syncer::printlog(&source, &conf) {
+    Ok(_) => {},
+    Err(e) => eprintln!("LOG ERROR: {}", e),
+  }
+}
+
+fn open(dir: &str) -> (PathBuf, config::Config) {
   let mut path = env::current_dir().unwrap();
-  path.push(&args[0]);
+  path.push(dir);
   let mut source = path.clone();
   source.push("data");
   let mut config = path.clone();
@@ -110,9 +201,133 @@ fn printlog(args: &[String]) {
     Ok(c) => c,
     Err(e) => {eprintln!("ERROR: Couldn't load config file: {}", e); process::exit(3);},
   };
+  (source, conf)
+}
 
-  match syncer::printlog(&source, &conf) {
+fn compact(args: &[String]) {
+  if args.len() != 1 { usage() }
+
+  let (source, conf) = open(&args[0]);
+  match syncer::compact(&source, &conf) {
     Ok(_) => {},
-    Err(e) => eprintln!("LOG ERROR: {}", e),
+    Err(e) => eprintln!("COMPACT ERROR: {}", e),
+  }
+}
+
+fn verify(args: &[String]) {
+  let remote = match args.len() {
+    1 => false,
+    2 if args[1] == "--remote" => true,
+    _ => {usage(); return},
+  };
+
+  let (source, conf) = open(&args[0]);
+  match syncer::verify(&source, &conf, remote) {
+    Ok(_) => {},
+    Err(e) => {eprintln!("VERIFY ERROR: {}", e); process::exit(1);},
+  }
+}
+
+fn fsck(args: &[String]) {
+  let repair = match args.len() {
+    1 => false,
+    2 if args[1] == "--repair" => true,
+    _ => {usage(); return},
+  };
+
+  let (source, conf) = open(&args[0]);
+  match syncer::fsck(&source, &conf, repair) {
+    Ok(_) => {},
+    Err(e) => {eprintln!("FSCK ERROR: {}", e); process::exit(1);},
+  }
+}
+
+fn upgrade(args: &[String]) {
+  if args.len() != 1 { usage() }
+
+  let (source, mut conf) = open(&args[0]);
+  match syncer::upgrade(&source, &mut conf) {
+    Ok(false) => {},
+    Ok(true) => {
+      let mut config = source.clone();
+      config.set_file_name("config");
+      if let Err(e) = conf.save_config(&config) {
+        eprintln!("ERROR: Couldn't save config file: {}", e);
+        process::exit(3);
+      }
+    },
+    Err(e) => {eprintln!("UPGRADE ERROR: {}", e); process::exit(1);},
+  }
+}
+
+fn set_hash(args: &[String]) {
+  if args.len() != 2 { usage() }
+
+  let (source, mut conf) = open(&args[0]);
+  match syncer::set_hash(&source, &mut conf, &args[1]) {
+    Ok(false) => {},
+    Ok(true) => {
+      let mut config = source.clone();
+      config.set_file_name("config");
+      if let Err(e) = conf.save_config(&config) {
+        eprintln!("ERROR: Couldn't save config file: {}", e);
+        process::exit(3);
+      }
+    },
+    Err(e) => {eprintln!("SET-HASH ERROR: {}", e); process::exit(1);},
+  }
+}
+
+fn quarantine(args: &[String]) {
+  if args.len() != 1 { usage() }
+
+  let (source, conf) = open(&args[0]);
+  match syncer::quarantine_list(&source, &conf) {
+    Ok(_) => {},
+    Err(e) => eprintln!("QUARANTINE ERROR: {}", e),
+  }
+}
+
+fn peers(args: &[String]) {
+  if args.len() < 2 { usage() }
+
+  let (source, conf) = open(&args[1]);
+  let res = match (args[0].as_ref(), &args[2..]) {
+    ("list", []) => syncer::peers_list(&source, &conf),
+    ("label", [peer, label]) => syncer::peers_label(&source, &conf, peer, label),
+    ("trust", [peer, key]) => syncer::peers_trust(&source, &conf, peer, key),
+    ("retire", [peer]) => syncer::peers_retire(&source, &conf, peer),
+    _ => {usage(); return},
+  };
+
+  match res {
+    Ok(_) => {},
+    Err(e) => eprintln!("PEERS ERROR: {}", e),
+  }
+}
+
+fn conflicts(args: &[String]) {
+  if args.len() < 2 { usage() }
+
+  let (source, conf) = open(&args[1]);
+  let res = match (args[0].as_ref(), &args[2..]) {
+    ("list", []) => syncer::conflicts_list(&source, &conf, false),
+    ("list", [all]) if all == "--all" => syncer::conflicts_list(&source, &conf, true),
+    ("show", [path]) => syncer::conflicts_show(&source, &conf, Path::new(path)),
+    ("resolve", [keep, path]) => {
+      let keep = match keep.as_ref() {
+        "--keep=mine" => syncer::ConflictKeep::Mine,
+        "--keep=theirs" => syncer::ConflictKeep::Theirs,
+        "--keep=both" => syncer::ConflictKeep::Both,
+        _ => {usage(); return},
+      };
+      syncer::conflicts_resolve(&source, &conf, Path::new(path), keep)
+    },
+    _ => {usage(); return},
+  };
+
+  match res {
+    Ok(_) => {},
+    Err(e) => eprintln!("CONFLICTS ERROR: {}", e),
   }
 }
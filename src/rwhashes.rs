@@ -1,4 +1,4 @@
-use std::sync::{RwLock, RwLockWriteGuard, RwLockReadGuard};
+use std::sync::{RwLock, RwLockWriteGuard, RwLockReadGuard, TryLockError};
 use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
@@ -38,8 +38,21 @@ impl<K: Hash + Eq,V> RwHashes<K,V> {
     self.buckets[self.get_bucket(key)].write().unwrap()
   }
 
-  pub fn write_pos(&self, index: usize) -> RwLockWriteGuard<HashMap<K,V>> {
-    self.buckets[index].write().unwrap()
+  // Like write() but gives up instead of waiting for someone else to let go
+  pub fn try_write(&self, key: &K) -> Option<RwLockWriteGuard<'_, HashMap<K,V>>> {
+    match self.buckets[self.get_bucket(key)].try_write() {
+      Ok(guard) => Some(guard),
+      Err(TryLockError::WouldBlock) => None,
+      Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+    }
+  }
+
+  pub fn keys_pos(&self, index: usize) -> Vec<K> where K: Clone {
+    self.buckets[index].read().unwrap().keys().cloned().collect()
+  }
+
+  pub fn all_empty(&self) -> bool {
+    self.buckets.iter().all(|b| b.read().unwrap().is_empty())
   }
 
   pub fn len(&self) -> usize {
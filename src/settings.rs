@@ -3,30 +3,84 @@
 // How many blobs to fetch at once for upload
 pub const TO_UPLOAD: usize = 4;
 
+// How many blobs to look at at once when packing them for upload
+pub const TO_PACK: usize = 1000;
+
+// Blobs smaller than this get uploaded together in packs instead of as their own files
+pub const PACK_MAXBLOB: usize = 65536;
+
+// How large a pack can get before a new one is started. A whole pack has to be fetched
+// to get a single blob out of it so this shouldn't be much more than a block.
+pub const PACK_SIZE: usize = 4000000;
+
 // How many nodes to fetch at once for upload
 pub const TO_UPLOAD_NODES: usize = 10;
 
 // How many blobs to fetch at once for delete
 pub const TO_DELETE: usize = 100;
 
+// How many blobs to fetch at once when verifying the server
+pub const TO_VERIFY: usize = 100;
+
+// How many times to fetch a blob that doesn't match its hash before giving up
+pub const FETCH_RETRIES: usize = 3;
+
 // How large of a file to never evict from local cache
 pub const KEEP_UP_TO_SIZE: usize = 65536;
 
 // How many blocks to read ahead when we've already read one
 pub const READAHEAD: usize = 3;
 
+// How large a node log segment can get before we start a new one
+pub const NODELOG_SEGMENT_SIZE: u64 = 16000000;
+
+// How often to write a checkpoint of all the nodes (in seconds)
+pub const CHECKPOINT_INTERVAL: u64 = 86400;
+
+// How long to wait on the server for changes from other peers before checking anyway (in seconds)
+pub const LIVE_WATCH_TIMEOUT: u64 = 60;
+
+// How often to poll the server for changes when it can't be watched (in seconds)
+pub const LIVE_POLL_INTERVAL: u64 = 10;
+
+// How long to let local changes settle before pushing them (in milliseconds)
+pub const LIVE_PUSH_DELAY: u64 = 200;
+
+// How many opened files to remember as unchanged by other peers so the kernel can
+// keep their cache (past that they all get their cache dropped once more)
+pub const MAX_FRESH_NODES: usize = 100000;
+
+// How much memory blocks that were written but not saved yet can take before
+// writers have to start saving the oldest ones (default for new repositories)
+pub const MAXDIRTY: u64 = 256000000;
+
+// How often to flush the journal of cached writes to disk (in seconds)
+pub const JOURNAL_SYNC_INTERVAL: u64 = 1;
+
+// How long the kernel can cache attributes and directory entries (in seconds). There's
+// no way to invalidate them when changes from other peers come in so keep it short.
+pub const ATTR_TTL: i64 = 1;
+
 // From now on these can be changed but will make the on-disk format incompatible
-// Making them per-repository in the future may make sense for some
 
 // On-disk format version. Needs to be bumped when incompatible changes happen
-pub const FORMATVERSION: u64 = 5;
+pub const FORMATVERSION: u64 = 11;
 
-// 20 bytes are probably more than enough for safety
+// 20 bytes are probably more than enough for safety. This is the default for new
+// repositories and the size every hash had before they carried their algorithm.
 pub const HASHSIZE: usize = 20;
 
-// A zeroed out hash
-pub const HASHZERO: [u8; HASHSIZE] = [0; HASHSIZE];
+// Limits for the hash size of a repository. Hashes are kept inline so the largest one
+// sets how much space each of them takes in memory.
+pub const MINHASHSIZE: usize = 16;
+pub const MAXHASHSIZE: usize = 32;
 
 // Smaller blocks mean better deduplication but make for much slower performance
-// Disks use base 10 so use 1MB instead of 1MiB
+// Disks use base 10 so use 1MB instead of 1MiB. This is only the default for new
+// repositories, each one keeps the size it was created with.
 pub const BLKSIZE: usize = 1000000;
+
+// Limits for the block size of a repository. Symlink targets are a single block so
+// blocks need to fit PATH_MAX and whole blocks get kept in memory while being written.
+pub const MINBLKSIZE: usize = 4096;
+pub const MAXBLKSIZE: usize = 64000000;